@@ -1,27 +1,120 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::atomic::AtomicUsize,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{
+    bloom::BloomFilter,
+    crdt::{gossip_round, Gossip, GrowOnlySet},
+    event_loop_with_config,
+    fanout::Fanout,
+    join_all, join_quorum,
+    liveness::{Liveness, PING_INTERVAL},
+    log,
+    persist::{StateFile, StateWriter},
+    retry,
+    rpc::{self, PendingRpc},
+    serialize_sorted, spawn_timers, Body, Config, ErrorPayload, Event, Init, Message, Node, Output,
+    Periodic, Rng,
+};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, time::Instant};
+
+/// Forward new broadcasts to neighbors right away instead of waiting for the
+/// next gossip round. Forwards are acked, so this gives low latency without
+/// giving up delivery during partitions.
+const EAGER_FORWARD: bool = true;
+/// How long a forward waits for `broadcast_ok` before it is resent.
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(200);
+/// Most values a `reconcile_ok` carries, and how many exchanges a reconcile
+/// takes at most, so a node far behind catches up in a few bounded messages.
+const RECONCILE_LIMIT: usize = 1024;
+const RECONCILE_ROUNDS: usize = 8;
+/// How long a forward is retried before leaving the value to periodic gossip.
+const FORWARD_DEADLINE: Duration = Duration::from_secs(10);
+/// Backoff between the attempts of a forward, on top of `FORWARD_TIMEOUT`.
+const FORWARD_BACKOFF: Duration = Duration::from_millis(100);
+const FORWARD_BACKOFF_CAP: Duration = Duration::from_secs(2);
+/// Send gossip with a message id, asking the receiver to answer with what it
+/// has that we lack, so one exchange syncs both sides (push-pull).
+const GOSSIP_PUSH_PULL: bool = true;
+/// Setting this environment variable to `1` makes gossip ticks send a Bloom
+/// filter digest of all our values instead of the values a neighbor isn't
+/// known to have; the neighbor answers with what the digest lacks. Neighbors
+/// that haven't advertised `DIGEST_CAPABILITY` get plain gossip.
+const DIGEST_GOSSIP_VAR: &str = "GLOMERS_DIGEST_GOSSIP";
+/// Protocol extensions this node understands, advertised in pings and their
+/// answers. A node only sends an extension to a peer that advertised it.
+const CAPABILITIES: &[&str] = &[DIGEST_CAPABILITY];
+/// Understands `digest` messages.
+const DIGEST_CAPABILITY: &str = "digest";
+/// Setting this environment variable to a number makes each gossip tick go to
+/// that many neighbors, taking turns; unset, ticks go to about the square root
+/// of the neighbor count.
+const GOSSIP_FANOUT_VAR: &str = "GLOMERS_GOSSIP_FANOUT";
+/// Setting this environment variable to a number `k` limits gossip ticks to
+/// `k` of the neighbors from the topology, see `gossip_overlay`; forwards
+/// still go to every neighbor.
+const GOSSIP_NEIGHBORS_VAR: &str = "GLOMERS_GOSSIP_NEIGHBORS";
+/// Setting this environment variable to a number `k` holds back a client's
+/// `broadcast_ok` until `k` neighbors have the value, or to `quorum` until a
+/// majority of them do, so an acked value survives this node crashing. A
+/// broadcast that doesn't get there within `FORWARD_DEADLINE` fails with
+/// `crash`, as the value may still spread.
+const DURABLE_ACKS_VAR: &str = "GLOMERS_DURABLE_ACKS";
+/// How often a held back `broadcast_ok` checks which neighbors have the value.
+const DURABLE_ACK_POLL: Duration = Duration::from_millis(10);
+/// Setting this environment variable to `1` logs the node's values and gossip
+/// backoff at exit, see `Config::snapshot_at_exit`.
+const SNAPSHOT_AT_EXIT_VAR: &str = "GLOMERS_SNAPSHOT_AT_EXIT";
+/// False positive rate of gossip digests. A value the neighbor lacks but that
+/// hits a false positive waits for a later digest, which uses another seed.
+const DIGEST_FP_RATE: f64 = 0.01;
+/// After this many consecutive gossip ticks with nothing new for a neighbor,
+/// the neighbor is taken to be converged and gossip to it backs off.
+const CONVERGED_AFTER: u32 = 3;
+/// Gossip to a converged neighbor goes out every `2^level` ticks, with the
+/// level capped at this.
+const MAX_BACKOFF_LEVEL: u32 = 4;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Broadcast {
-        #[serde(rename = "message")]
+        #[serde(rename = "message", alias = "msg")]
         msg: usize,
     },
     BroadcastOk,
-    Read,
+    Read {
+        /// Pull from every neighbor before answering, so the read includes
+        /// values that haven't been gossiped here yet, at the cost of a round
+        /// trip.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        fresh: bool,
+        /// With `fresh`, skip the pull if every neighbor sent us its values
+        /// within this many milliseconds, and answer right away. A read during
+        /// a partition then isn't held up by the neighbors it can't reach.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_staleness_ms: Option<u64>,
+        /// Pull from a majority of the cluster before answering, so the read
+        /// includes every value a majority has. Falls back to the local view
+        /// if no majority answers within `FORWARD_TIMEOUT`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        quorum: bool,
+    },
     ReadOk {
-        #[serde(rename = "messages")]
+        #[serde(
+            rename = "messages",
+            alias = "msgs",
+            serialize_with = "serialize_sorted"
+        )]
         msgs: HashSet<usize>,
     },
     Topology {
@@ -30,8 +123,52 @@ enum Payload {
     },
     TopologyOk,
     Gossip {
+        #[serde(serialize_with = "serialize_sorted")]
+        seen: HashSet<usize>,
+        /// Per-sender gossip round, so reordered or duplicated rounds can be
+        /// told apart from fresh ones.
+        #[serde(default)]
+        round: u64,
+    },
+    /// Answer to a gossip that carried a message id: the values the receiver
+    /// has that the sender isn't known to have.
+    GossipOk {
+        #[serde(serialize_with = "serialize_sorted")]
+        seen: HashSet<usize>,
+    },
+    /// The sender's values as a Bloom filter; the receiver answers with a
+    /// `gossip` of the values the filter lacks.
+    Digest {
+        filter: BloomFilter,
+    },
+    /// Asks for the values the receiver has beyond `have`; sent to every
+    /// neighbor once the topology is known, and to a neighbor that comes
+    /// back up, so a node catches up without waiting for gossip ticks.
+    Reconcile {
+        #[serde(serialize_with = "serialize_sorted")]
+        have: HashSet<usize>,
+    },
+    /// Up to `RECONCILE_LIMIT` of the missing values, the lowest first;
+    /// `more` says there are others.
+    ReconcileOk {
+        #[serde(serialize_with = "serialize_sorted")]
+        missing: HashSet<usize>,
+        more: bool,
+    },
+    Pull,
+    PullOk {
+        #[serde(serialize_with = "serialize_sorted")]
         seen: HashSet<usize>,
     },
+    /// Also advertises the sender's `CAPABILITIES`; older nodes send none.
+    Ping {
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    PingOk {
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -39,52 +176,599 @@ enum Payload {
 #[serde(rename_all = "snake_case")]
 enum InjectedPayload {
     Gossip,
+    Ping,
+}
+
+impl Periodic for InjectedPayload {
+    fn schedule() -> Vec<(Duration, Self)> {
+        vec![
+            (Duration::from_millis(500), Self::Gossip),
+            (PING_INTERVAL, Self::Ping),
+        ]
+    }
+}
+
+/// Per-neighbor gossip backoff. Gossip to a neighbor we believe is converged
+/// with us carries nothing, so it is sent ever more rarely until there is new
+/// data for it again.
+#[derive(Debug, Default)]
+struct Backoff {
+    quiet_ticks: u32,
+    level: u32,
+    skipped: u32,
+}
+
+impl Backoff {
+    /// Called once per gossip tick; returns whether to gossip this tick.
+    fn tick(&mut self, converged: bool) -> bool {
+        if !converged {
+            *self = Self::default();
+            return true;
+        }
+        self.quiet_ticks = self.quiet_ticks.saturating_add(1);
+        if self.quiet_ticks < CONVERGED_AFTER {
+            return true;
+        }
+        if self.skipped + 1 < 1 << self.level {
+            self.skipped += 1;
+            return false;
+        }
+        self.skipped = 0;
+        self.level = (self.level + 1).min(MAX_BACKOFF_LEVEL);
+        true
+    }
+}
+
+/// How many neighbors must have a value before a client's broadcast of it is
+/// acked, see `DURABLE_ACKS_VAR`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DurableAcks {
+    Neighbors(usize),
+    Quorum,
+}
+
+impl DurableAcks {
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let Result::Ok(acks) = std::env::var(DURABLE_ACKS_VAR) else {
+            return Ok(None);
+        };
+        if acks == "quorum" {
+            return Ok(Some(Self::Quorum));
+        }
+        let k = acks
+            .parse::<usize>()
+            .ok()
+            .filter(|k| *k > 0)
+            .with_context(|| {
+                format!(
+                    "{} must be a positive number or \"quorum\", not {:?}",
+                    DURABLE_ACKS_VAR, acks
+                )
+            })?;
+        Ok(Some(Self::Neighbors(k)))
+    }
+
+    /// How many of `neighbors` must have the value; never more than there
+    /// are, so a node with few neighbors still acks.
+    fn needed(self, neighbors: usize) -> usize {
+        match self {
+            Self::Neighbors(k) => k,
+            Self::Quorum => neighbors / 2 + 1,
+        }
+        .min(neighbors)
+    }
 }
 
 struct BroadcastNode {
     node: String,
-    msgs: Mutex<HashSet<usize>>,
+    /// Shared with the state writer, which saves the values.
+    msgs: Arc<Mutex<GrowOnlySet<usize>>>,
+    peers: HashSet<String>,
     neighbors: Mutex<Vec<String>>,
-    known: Mutex<HashMap<String, HashSet<usize>>>,
-    stdout: Mutex<tokio::io::Stdout>,
-    id: AtomicUsize,
+    /// The neighbors of every other node, for routing around neighbors that
+    /// are down.
+    topology: Mutex<HashMap<String, Vec<String>>>,
+    /// How many neighbors gossip ticks go to, see `GOSSIP_NEIGHBORS_VAR`;
+    /// `None` gossips with all of them.
+    gossip_neighbors: Option<usize>,
+    /// How many neighbors must have a client's value before it is acked, see
+    /// `DURABLE_ACKS_VAR`; `None` acks right away.
+    durable_acks: Option<DurableAcks>,
+    /// The neighbors gossip ticks go to.
+    overlay: Mutex<Vec<String>>,
+    /// Only ever incremented, and receivers merely compare rounds of the same
+    /// sender, so it is updated with `Relaxed`.
+    round: AtomicU64,
+    last_round: Mutex<HashMap<String, u64>>,
+    /// When each neighbor last sent us its values, by gossip, gossip ack or
+    /// pull reply, which bounds how far our view lags behind theirs.
+    heard_from: Mutex<HashMap<String, Instant>>,
+    backoff: Mutex<HashMap<String, Backoff>>,
+    /// What each peer advertised in its last ping or ping answer.
+    capabilities: Mutex<HashMap<String, HashSet<String>>>,
+    /// Picks the neighbors each gossip tick goes to.
+    fanout: Mutex<Fanout>,
+    /// Neighbors that stopped answering pings are neither gossiped nor
+    /// forwarded to, and gossip is routed around them.
+    liveness: Mutex<Liveness>,
+    /// Whether ticks gossip digests, see `DIGEST_GOSSIP_VAR`.
+    digest_gossip: bool,
+    /// Seeds the digests, so successive ones have different false positives.
+    digest_seed: AtomicU64,
+    /// Jitter for retry backoff.
+    rng: Mutex<Rng>,
+    stdout: Output,
+    /// RPCs waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+    state: Option<StateWriter>,
+}
+
+/// Picks the `k` neighbors of `node` that gossip ticks go to. Every node
+/// computes the same spanning tree of the topology, breadth first from the
+/// lowest node id, and keeps its edges in it, so the nodes stay connected
+/// even if that takes more than `k`; the nearest other neighbors, by position
+/// in `neighbors`, make up the rest.
+fn gossip_overlay(
+    node: &str,
+    neighbors: &[String],
+    topology: &HashMap<String, Vec<String>>,
+    k: usize,
+) -> Vec<String> {
+    let adjacency = |of: &str| -> &[String] {
+        if of == node {
+            neighbors
+        } else {
+            topology.get(of).map_or(&[], Vec::as_slice)
+        }
+    };
+    let mut roots: Vec<&str> = topology.keys().map(String::as_str).collect();
+    roots.push(node);
+    roots.sort_unstable();
+    let mut visited = HashSet::new();
+    let mut tree = HashSet::new();
+    for root in roots {
+        if !visited.insert(root) {
+            continue;
+        }
+        let mut queue = VecDeque::from([root]);
+        while let Some(parent) = queue.pop_front() {
+            for child in adjacency(parent) {
+                if !visited.insert(child.as_str()) {
+                    continue;
+                }
+                if parent == node {
+                    tree.insert(child.as_str());
+                } else if child == node {
+                    tree.insert(parent);
+                }
+                queue.push_back(child.as_str());
+            }
+        }
+    }
+    let mut overlay: Vec<String> = neighbors
+        .iter()
+        .filter(|neighbor| tree.contains(neighbor.as_str()))
+        .cloned()
+        .collect();
+    for neighbor in neighbors {
+        if overlay.len() >= k {
+            break;
+        }
+        if !overlay.contains(neighbor) {
+            overlay.push(neighbor.clone());
+        }
+    }
+    overlay
+}
+
+impl BroadcastNode {
+    /// The neighbors to gossip to this tick: the overlay, with each of its
+    /// members that is down replaced by another neighbor that is up.
+    async fn gossip_targets(&self) -> Vec<String> {
+        let neighbors = self.neighbors.lock().await.clone();
+        let overlay = self.overlay.lock().await.clone();
+        let liveness = self.liveness.lock().await;
+        let down = overlay.iter().filter(|node| !liveness.is_up(node)).count();
+        let spares = neighbors
+            .iter()
+            .filter(|node| !overlay.contains(node) && liveness.is_up(node))
+            .take(down);
+        overlay
+            .iter()
+            .filter(|node| liveness.is_up(node))
+            .chain(spares)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `id` is another node from the initial membership.
+    fn is_known_peer(&self, id: &str) -> bool {
+        self.peers.contains(id)
+    }
+
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.call(msg).await
+    }
+
+    /// Sends `msg` under a fresh message id and waits for the reply.
+    async fn call(&self, mut msg: Message<Payload>) -> anyhow::Result<Message<Payload>> {
+        let (id, rx) = self.rpc.register();
+        msg.body.id = Some(id);
+        msg.send(&self.stdout).await.context("send rpc message")?;
+        let res = tokio::time::timeout(FORWARD_TIMEOUT, rx).await;
+        self.rpc.cancel(id);
+        res.context("rpc timed out")?
+            .context("receive rpc response")
+    }
+
+    /// Merges values `from` sent us and notes that we heard from it.
+    async fn merge(&self, from: &str, seen: HashSet<usize>) {
+        self.msgs.lock().await.merge_delta(from, seen);
+        self.heard_from
+            .lock()
+            .await
+            .insert(from.to_string(), Instant::now());
+    }
+
+    /// How long ago the neighbor we heard from least recently sent us its
+    /// values, or `None` if some neighbor never has.
+    async fn staleness(&self) -> Option<Duration> {
+        let neighbors = self.neighbors.lock().await.clone();
+        let heard_from = self.heard_from.lock().await;
+        neighbors
+            .iter()
+            .map(|neighbor| heard_from.get(neighbor).map(Instant::elapsed))
+            .try_fold(Duration::ZERO, |max, elapsed| Some(max.max(elapsed?)))
+    }
+
+    /// Has the seen messages saved if persistence is enabled.
+    fn persist(&self) {
+        if let Some(state) = &self.state {
+            state.changed();
+        }
+    }
+
+    /// Pulls the values this node is missing from every neighbor. Neighbors
+    /// that don't answer within `FORWARD_TIMEOUT` are skipped.
+    async fn pull_neighbors(&self) -> anyhow::Result<()> {
+        let neighbors = self.neighbors.lock().await.clone();
+        let mut pulls = Vec::with_capacity(neighbors.len());
+        for neighbor in &neighbors {
+            pulls.push(self.rpc(neighbor, Payload::Pull));
+        }
+        for reply in join_all(pulls).await.into_iter().filter_map(Result::ok) {
+            if let Payload::PullOk { seen } = reply.body.payload {
+                self.merge(&reply.src, seen).await;
+            }
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Asks `neighbor` for the values we lack, in up to `RECONCILE_ROUNDS`
+    /// exchanges. A neighbor that doesn't answer is left to gossip.
+    async fn reconcile(&self, neighbor: &str) {
+        for _ in 0..RECONCILE_ROUNDS {
+            let have = self.msgs.lock().await.values().clone();
+            let Result::Ok(reply) = self.rpc(neighbor, Payload::Reconcile { have }).await else {
+                return;
+            };
+            let Payload::ReconcileOk { missing, more } = reply.body.payload else {
+                return;
+            };
+            self.merge(neighbor, missing).await;
+            self.persist();
+            if !more {
+                return;
+            }
+        }
+        log!(
+            "{} still has values for us after {} reconciles",
+            neighbor,
+            RECONCILE_ROUNDS
+        );
+    }
+
+    /// Sends `payload` to every peer and returns the replies once enough have
+    /// come in for this node and the repliers to be a majority, or `None` if
+    /// too many of them timed out.
+    async fn rpc_quorum(&self, payload: Payload) -> Option<Vec<Message<Payload>>> {
+        let cluster = self.peers.len() + 1;
+        let majority = cluster / 2 + 1;
+        // This node counts towards the majority too
+        let needed = majority - 1;
+        let calls = self
+            .peers
+            .iter()
+            .map(|peer| self.rpc(peer, payload.clone()));
+        join_quorum(calls, needed).await
+    }
+
+    /// Pulls from a majority, see `Payload::Read::quorum`.
+    async fn pull_quorum(&self) -> anyhow::Result<()> {
+        let Some(replies) = self.rpc_quorum(Payload::Pull).await else {
+            log!("no majority answered, reading the local view");
+            return Ok(());
+        };
+        for reply in replies {
+            if let Payload::PullOk { seen } = reply.body.payload {
+                self.merge(&reply.src, seen).await;
+            }
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Gossips `seen` to `to`, split into as many messages as it takes.
+    async fn gossip(&self, to: &str, seen: HashSet<usize>) -> anyhow::Result<()> {
+        let seen: Vec<usize> = seen.into_iter().collect();
+        // Every part gets its own round so none is taken for a duplicate
+        let parts = Message::split_to_fit(&self.stdout, &seen, &|part: &[usize]| Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: GOSSIP_PUSH_PULL.then(|| self.rpc.next_id()),
+                in_reply_to: None,
+                payload: Payload::Gossip {
+                    seen: part.iter().copied().collect(),
+                    round: self.round.fetch_add(1, Ordering::Relaxed),
+                },
+            },
+        })
+        .context("split gossip message")?;
+        if parts.len() > 1 {
+            log!("split gossip to {} into {} parts", to, parts.len());
+        }
+        for part in parts {
+            part.send(&self.stdout)
+                .await
+                .context("send gossip message")?;
+        }
+        Ok(())
+    }
+
+    /// Routes around neighbors that are down: gossips what the nodes behind
+    /// each of them lack straight to those nodes, so values keep spreading
+    /// past the gap during a partition. Nodes that are our neighbors too get
+    /// regular gossip already. Once the neighbor answers pings again, gossip
+    /// goes through it and the detours stop.
+    async fn gossip_detours(&self) -> anyhow::Result<()> {
+        let neighbors = self.neighbors.lock().await.clone();
+        let detours: HashSet<String> = {
+            let liveness = self.liveness.lock().await;
+            let topology = self.topology.lock().await;
+            neighbors
+                .iter()
+                .filter(|neighbor| !liveness.is_up(neighbor))
+                .filter_map(|neighbor| topology.get(neighbor))
+                .flatten()
+                .filter(|node| {
+                    **node != self.node
+                        && self.is_known_peer(node)
+                        && !neighbors.contains(node)
+                        && liveness.is_up(node)
+                })
+                .cloned()
+                .collect()
+        };
+        for node in detours {
+            let Some(seen) = self.msgs.lock().await.missing(&node) else {
+                continue;
+            };
+            if !seen.is_empty() {
+                self.gossip(&node, seen).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pings every neighbor and records which ones answered within
+    /// `FORWARD_TIMEOUT`.
+    async fn ping_neighbors(&self) {
+        let neighbors = self.neighbors.lock().await.clone();
+        let answers = join_all(neighbors.iter().map(|neighbor| {
+            let ping = Payload::Ping {
+                capabilities: Self::capabilities(),
+            };
+            self.rpc(neighbor, ping)
+        }))
+        .await;
+        let mut liveness = self.liveness.lock().await;
+        let mut back = Vec::new();
+        for (neighbor, answer) in neighbors.iter().zip(answers) {
+            if liveness.record(neighbor, answer.is_ok()) && liveness.is_up(neighbor) {
+                back.push(neighbor.clone());
+            }
+            if let Result::Ok(Message {
+                body:
+                    Body {
+                        payload: Payload::PingOk { capabilities },
+                        ..
+                    },
+                ..
+            }) = answer
+            {
+                self.advertised(neighbor, capabilities).await;
+            }
+        }
+        drop(liveness);
+        // Whatever it missed while it was down comes back in one go
+        for neighbor in back {
+            self.reconcile(&neighbor).await;
+        }
+    }
+
+    fn capabilities() -> Vec<String> {
+        CAPABILITIES.iter().map(|c| c.to_string()).collect()
+    }
+
+    /// Records the capabilities `peer` advertised, replacing earlier ones.
+    async fn advertised(&self, peer: &str, capabilities: Vec<String>) {
+        self.capabilities
+            .lock()
+            .await
+            .insert(peer.to_string(), capabilities.into_iter().collect());
+    }
+
+    /// Whether `peer` advertised `capability`. Until it has, it is assumed
+    /// to understand nothing but the plain protocol.
+    async fn supports(&self, peer: &str, capability: &str) -> bool {
+        self.capabilities
+            .lock()
+            .await
+            .get(peer)
+            .is_some_and(|capabilities| capabilities.contains(capability))
+    }
+
+    /// Waits until `needed` neighbors are known to have `msg`, by forward
+    /// ack, gossip or gossip ack, and says whether they were before
+    /// `FORWARD_DEADLINE`.
+    async fn durable(&self, msg: usize, acks: DurableAcks) -> bool {
+        let deadline = Instant::now() + FORWARD_DEADLINE;
+        loop {
+            let neighbors = self.neighbors.lock().await.clone();
+            let needed = acks.needed(neighbors.len());
+            let msgs = self.msgs.lock().await;
+            let known = neighbors
+                .iter()
+                .filter(|neighbor| msgs.is_known(neighbor, &msg))
+                .count();
+            drop(msgs);
+            if known >= needed {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(DURABLE_ACK_POLL).await;
+        }
+    }
+
+    /// Sends `forward`, a broadcast of `msg`, until it is acked. What the set
+    /// knows the neighbor has doubles as the record of pending forwards: once
+    /// an ack or a gossip from the neighbor shows it has `msg`, retrying
+    /// stops, and a forward still pending when a partition heals gets through
+    /// on its next retry.
+    async fn forward(&self, forward: Message<Payload>, msg: usize) {
+        let neighbor = forward.dest.as_str();
+        let deadline = Instant::now() + FORWARD_DEADLINE;
+        let rng = self.rng.lock().await.fork();
+        let mut backoff = retry::Backoff::new(FORWARD_BACKOFF, FORWARD_BACKOFF_CAP, 0.5, rng);
+        let mut first = true;
+        while Instant::now() < deadline {
+            if !std::mem::take(&mut first) {
+                backoff.wait().await;
+                retry::pace(&self.stdout).await;
+            }
+            if self.msgs.lock().await.is_known(neighbor, &msg) {
+                return;
+            }
+            // Periodic gossip reaches a neighbor that is down once it's back,
+            // and routes around it until then
+            if !self.liveness.lock().await.is_up(neighbor) {
+                return;
+            }
+            if let Result::Ok(reply) = self.call(forward.clone()).await {
+                if let Payload::BroadcastOk = reply.body.payload {
+                    self.msgs.lock().await.mark_known(neighbor, [msg]);
+                    return;
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl Node<Payload, InjectedPayload> for BroadcastNode {
+    const NAME: &'static str = "broadcast";
+
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // Generate a Gossip injection event every 500ms
-        // TODO: handle EOF (AtomicBool?)
-        tokio::spawn(async move {
-            loop {
-                std::thread::sleep(Duration::from_millis(500));
-                if let Err(_) = tx
-                    .send(gossip_glomers::Event::Injected(InjectedPayload::Gossip))
-                    .await
-                {
-                    break;
-                }
-            }
+        let peers: HashSet<String> = init.peers().into_iter().collect();
+        // Start the gossip timer, unless there is no one to gossip with
+        if !peers.is_empty() {
+            spawn_timers(&tx);
+        }
+        let fanout = match std::env::var(GOSSIP_FANOUT_VAR) {
+            Err(_) => None,
+            Result::Ok(size) => Some(
+                size.parse::<usize>()
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .with_context(|| {
+                        format!(
+                            "{} must be a positive number, not {:?}",
+                            GOSSIP_FANOUT_VAR, size
+                        )
+                    })?,
+            ),
+        };
+        let mut rng = init.rng();
+        let fanout = Fanout::new(fanout, rng.fork());
+        let gossip_neighbors = match std::env::var(GOSSIP_NEIGHBORS_VAR) {
+            Err(_) => None,
+            Result::Ok(k) => Some(k.parse::<usize>().ok().filter(|k| *k > 0).with_context(
+                || {
+                    format!(
+                        "{} must be a positive number, not {:?}",
+                        GOSSIP_NEIGHBORS_VAR, k
+                    )
+                },
+            )?),
+        };
+        let mut msgs = GrowOnlySet::new(peers.iter().cloned());
+        let state_file = StateFile::from_env(Self::NAME, &init.node_id);
+        if let Some(state_file) = &state_file {
+            let saved: HashSet<usize> = state_file.load()?.unwrap_or_default();
+            saved.into_iter().for_each(|msg| {
+                msgs.insert(msg);
+            });
+        }
+        let msgs = Arc::new(Mutex::new(msgs));
+        let state = state_file.map(|state_file| {
+            let msgs = msgs.clone();
+            state_file.spawn_writer(move || {
+                let msgs = msgs.clone();
+                async move { msgs.lock().await.values().clone() }
+            })
         });
         Ok(Self {
             node: init.node_id,
-            msgs: Mutex::new(HashSet::new()),
+            msgs,
+            peers,
             neighbors: Mutex::new(Vec::new()),
-            known: Mutex::new(
-                init.node_ids
-                    .into_iter()
-                    .map(|id| (id, HashSet::new()))
-                    .collect(),
-            ),
-            id: 1.into(),
+            topology: Mutex::new(HashMap::new()),
+            gossip_neighbors,
+            durable_acks: DurableAcks::from_env()?,
+            overlay: Mutex::new(Vec::new()),
+            round: AtomicU64::new(1),
+            last_round: Mutex::new(HashMap::new()),
+            heard_from: Mutex::new(HashMap::new()),
+            backoff: Mutex::new(HashMap::new()),
+            capabilities: Mutex::new(HashMap::new()),
+            fanout: Mutex::new(fanout),
+            liveness: Mutex::new(Liveness::default()),
+            digest_gossip: std::env::var(DIGEST_GOSSIP_VAR).is_ok_and(|v| v == "1"),
+            digest_seed: AtomicU64::new(0),
+            rng: Mutex::new(rng),
+            rpc: PendingRpc::new(&stdout),
             stdout,
+            state,
         })
     }
 
@@ -95,80 +779,361 @@ impl Node<Payload, InjectedPayload> for BroadcastNode {
         match event {
             gossip_glomers::Event::EOF => {}
             gossip_glomers::Event::Message(message) => {
-                let mut reply = message.into_reply(Some(&self.id));
+                // Forwards of a broadcast are built while its source is at hand
+                let forwards: Vec<Message<Payload>> = match message.body.payload {
+                    Payload::Broadcast { msg } if EAGER_FORWARD => self
+                        .neighbors
+                        .lock()
+                        .await
+                        .iter()
+                        .filter_map(|neighbor| {
+                            message.forward(&self.node, neighbor, Payload::Broadcast { msg })
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+
+                let mut reply = message.into_reply(Some(self.rpc.ids()));
                 match reply.body.payload {
-                    Payload::Gossip { seen } => {
-                        self.known
+                    Payload::Gossip { seen, round } => {
+                        if !self.is_known_peer(&reply.dest) {
+                            log!("ignoring gossip from unknown node {}", reply.dest);
+                            return Ok(());
+                        }
+                        // Round 0 means the sender doesn't number its rounds
+                        if round > 0 {
+                            let mut last_round = self.last_round.lock().await;
+                            let last = last_round.entry(reply.dest.clone()).or_default();
+                            if round <= *last {
+                                return Ok(());
+                            }
+                            *last = round;
+                        }
+                        let wants_ack = reply.body.in_reply_to.is_some();
+                        self.merge(&reply.dest, seen).await;
+                        let theirs = self
+                            .msgs
+                            .lock()
+                            .await
+                            .missing(&reply.dest)
+                            .unwrap_or_default();
+                        self.persist();
+                        if wants_ack && !theirs.is_empty() {
+                            let theirs: Vec<usize> = theirs.into_iter().collect();
+                            let parts = Message::split_to_fit(
+                                &self.stdout,
+                                &theirs,
+                                &|part: &[usize]| Message {
+                                    src: reply.src.clone(),
+                                    dest: reply.dest.clone(),
+                                    body: Body {
+                                        id: None,
+                                        in_reply_to: reply.body.in_reply_to,
+                                        payload: Payload::GossipOk {
+                                            seen: part.iter().copied().collect(),
+                                        },
+                                    },
+                                },
+                            )
+                            .context("split gossip ack")?;
+                            for part in parts {
+                                part.send(&self.stdout).await.context("send gossip ack")?;
+                            }
+                        }
+                    }
+                    Payload::Digest { filter } => {
+                        if !self.is_known_peer(&reply.dest) {
+                            log!("ignoring digest from unknown node {}", reply.dest);
+                            return Ok(());
+                        }
+                        let lacking: Vec<usize> = self
+                            .msgs
                             .lock()
                             .await
-                            .get_mut(&reply.dest)
-                            .expect("got gossip from unknown node")
-                            .extend(seen.iter().copied());
-                        self.msgs.lock().await.extend(seen);
+                            .values()
+                            .iter()
+                            .filter(|msg| !filter.contains(*msg))
+                            .copied()
+                            .collect();
+                        if lacking.is_empty() {
+                            return Ok(());
+                        }
+                        let parts =
+                            Message::split_to_fit(&self.stdout, &lacking, &|part: &[usize]| {
+                                Message {
+                                    src: self.node.clone(),
+                                    dest: reply.dest.clone(),
+                                    body: Body {
+                                        id: None,
+                                        in_reply_to: None,
+                                        payload: Payload::Gossip {
+                                            seen: part.iter().copied().collect(),
+                                            round: self.round.fetch_add(1, Ordering::Relaxed),
+                                        },
+                                    },
+                                }
+                            })
+                            .context("split gossip message")?;
+                        for part in parts {
+                            part.send(&self.stdout)
+                                .await
+                                .context("send gossip message")?;
+                        }
                     }
                     Payload::Broadcast { msg } => {
-                        self.msgs.lock().await.insert(msg);
-                        reply.body.payload = Payload::BroadcastOk;
+                        let new = {
+                            let mut msgs = self.msgs.lock().await;
+                            // A forward from a peer means the peer already has it
+                            msgs.mark_known(&reply.dest, [msg]);
+                            msgs.insert(msg)
+                        };
+                        if new {
+                            self.persist();
+                        }
+                        // Peers forwarding to us are acked right away, or
+                        // forwards around a cycle would wait on each other
+                        let durable = self
+                            .durable_acks
+                            .filter(|_| !self.is_known_peer(&reply.dest));
+                        let forwarding = async {
+                            if new {
+                                join_all(forwards.into_iter().map(|fwd| self.forward(fwd, msg)))
+                                    .await;
+                            }
+                        };
+                        let ack = async {
+                            let durable = match durable {
+                                Some(acks) => self.durable(msg, acks).await,
+                                None => true,
+                            };
+                            if durable {
+                                reply.body.payload = Payload::BroadcastOk;
+                                return reply
+                                    .send(&self.stdout)
+                                    .await
+                                    .context("send response message");
+                            }
+                            let error = Message {
+                                src: reply.src,
+                                dest: reply.dest,
+                                body: Body {
+                                    id: reply.body.id,
+                                    in_reply_to: reply.body.in_reply_to,
+                                    payload: ErrorPayload {
+                                        code: ErrorPayload::CRASH,
+                                        text: format!("{} isn't on enough neighbors yet", msg),
+                                    },
+                                },
+                            };
+                            error.send(&self.stdout).await.context("send error reply")
+                        };
+                        tokio::join!(forwarding, ack).1?;
+                    }
+                    Payload::Read {
+                        fresh,
+                        max_staleness_ms,
+                        quorum,
+                    } => {
+                        let fresh_enough = match (self.staleness().await, max_staleness_ms) {
+                            (Some(staleness), Some(max)) => staleness <= Duration::from_millis(max),
+                            _ => false,
+                        };
+                        if quorum {
+                            self.pull_quorum().await?;
+                        } else if fresh && !fresh_enough {
+                            self.pull_neighbors().await?;
+                        }
+                        match self.staleness().await {
+                            Some(staleness) => {
+                                log!("answering read at most {:?} behind neighbors", staleness)
+                            }
+                            None => log!("answering read with a neighbor never heard from"),
+                        }
+                        reply.body.payload = Payload::ReadOk {
+                            msgs: self.msgs.lock().await.values().clone(),
+                        };
                         reply
                             .send(&self.stdout)
                             .await
                             .context("send response message")?;
                     }
-                    Payload::BroadcastOk => {}
-                    Payload::Read => {
-                        reply.body.payload = Payload::ReadOk {
-                            msgs: self.msgs.lock().await.clone(),
+                    Payload::Pull => {
+                        let msgs = self.msgs.lock().await;
+                        let seen = match msgs.missing(&reply.dest) {
+                            Some(missing) => missing,
+                            None => msgs.values().clone(),
                         };
+                        drop(msgs);
+                        reply.body.payload = Payload::PullOk { seen };
                         reply
                             .send(&self.stdout)
                             .await
                             .context("send response message")?;
                     }
-                    Payload::ReadOk { .. } => {}
                     Payload::Topology { mut topo } => {
-                        *self.neighbors.lock().await = topo
+                        let (neighbors, unknown): (Vec<_>, Vec<_>) = topo
                             .remove(&self.node)
-                            .unwrap_or_else(|| panic!("node {} not found in topology", self.node));
+                            .unwrap_or_else(|| panic!("node {} not found in topology", self.node))
+                            .into_iter()
+                            .partition(|neighbor| self.is_known_peer(neighbor));
+                        if !unknown.is_empty() {
+                            log!("ignoring unknown neighbors {:?}", unknown);
+                        }
+                        // What only non-neighbors lack needn't be tracked per neighbor
+                        self.msgs
+                            .lock()
+                            .await
+                            .compact_over(neighbors.iter().cloned());
+                        *self.overlay.lock().await = match self.gossip_neighbors {
+                            Some(k) => gossip_overlay(&self.node, &neighbors, &topo, k),
+                            None => neighbors.clone(),
+                        };
+                        *self.neighbors.lock().await = neighbors.clone();
+                        *self.topology.lock().await = topo;
                         reply.body.payload = Payload::TopologyOk;
                         reply
                             .send(&self.stdout)
                             .await
                             .context("send response message")?;
+                        join_all(neighbors.iter().map(|neighbor| self.reconcile(neighbor))).await;
+                    }
+                    Payload::Reconcile { have } => {
+                        if !self.is_known_peer(&reply.dest) {
+                            log!("ignoring reconcile from unknown node {}", reply.dest);
+                            return Ok(());
+                        }
+                        let mut missing: Vec<usize> = {
+                            let msgs = self.msgs.lock().await;
+                            msgs.values().difference(&have).copied().collect()
+                        };
+                        // What the sender has is news to us just as well
+                        self.merge(&reply.dest, have).await;
+                        self.persist();
+                        missing.sort_unstable();
+                        let more = missing.len() > RECONCILE_LIMIT;
+                        missing.truncate(RECONCILE_LIMIT);
+                        reply.body.payload = Payload::ReconcileOk {
+                            missing: missing.into_iter().collect(),
+                            more,
+                        };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send response message")?;
+                    }
+                    Payload::Ping { capabilities } => {
+                        self.advertised(&reply.dest, capabilities).await;
+                        reply.body.payload = Payload::PingOk {
+                            capabilities: Self::capabilities(),
+                        };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send response message")?;
                     }
-                    Payload::TopologyOk => {}
+                    // Replies go to `handle_reply`
+                    Payload::GossipOk { .. }
+                    | Payload::BroadcastOk
+                    | Payload::ReadOk { .. }
+                    | Payload::PullOk { .. }
+                    | Payload::ReconcileOk { .. }
+                    | Payload::TopologyOk
+                    | Payload::PingOk { .. } => {}
                 }
             }
-            gossip_glomers::Event::Injected(_) => {
-                for neighbor in self.neighbors.lock().await.iter() {
-                    let known_to_n = &self.known.lock().await[neighbor];
-                    let seen = self
-                        .msgs
-                        .lock()
-                        .await
-                        .difference(&known_to_n)
-                        .copied()
-                        .collect();
-                    let to_send = gossip_glomers::Message {
-                        src: self.node.clone(),
-                        dest: neighbor.clone(),
-                        body: gossip_glomers::Body {
-                            id: None,
-                            in_reply_to: None,
-                            payload: Payload::Gossip { seen },
-                        },
-                    };
-                    to_send
-                        .send(&self.stdout)
-                        .await
-                        .context("send gossip message")?;
+            gossip_glomers::Event::Injected(InjectedPayload::Ping) => self.ping_neighbors().await,
+            gossip_glomers::Event::Injected(InjectedPayload::Gossip) => {
+                let filter = if self.digest_gossip {
+                    let msgs = self.msgs.lock().await;
+                    let mut filter = BloomFilter::new(
+                        msgs.values().len(),
+                        DIGEST_FP_RATE,
+                        self.digest_seed.fetch_add(1, Ordering::Relaxed),
+                    );
+                    msgs.values().iter().for_each(|msg| filter.insert(msg));
+                    Some(filter)
+                } else {
+                    None
+                };
+                let targets = self.gossip_targets().await;
+                // Neighbors that take digests get one instead of what they lack
+                let neighbors = self.fanout.lock().await.pick(&targets);
+                let mut plain = Vec::with_capacity(neighbors.len());
+                for neighbor in neighbors {
+                    match &filter {
+                        Some(filter) if self.supports(&neighbor, DIGEST_CAPABILITY).await => {
+                            if !self.liveness.lock().await.is_up(&neighbor) {
+                                continue;
+                            }
+                            let digest = Payload::Digest {
+                                filter: filter.clone(),
+                            };
+                            rpc::send_oneway(&self.stdout, &self.node, &neighbor, digest)
+                                .await
+                                .context("send digest")?;
+                        }
+                        _ => plain.push(neighbor),
+                    }
                 }
+                gossip_round(
+                    &self.node,
+                    &self.msgs,
+                    &plain,
+                    &self.liveness,
+                    |neighbor, seen| async move {
+                        let due = self
+                            .backoff
+                            .lock()
+                            .await
+                            .entry(neighbor.to_string())
+                            .or_default()
+                            .tick(seen.is_empty());
+                        if !due {
+                            return Ok(());
+                        }
+                        self.gossip(neighbor, seen).await
+                    },
+                )
+                .await?;
+                self.gossip_detours().await?;
             }
         }
         Ok(())
     }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        // Gossip acks are applied whenever they show up, nobody waits on them
+        if let Payload::GossipOk { seen } = reply.body.payload {
+            self.merge(&reply.src, seen).await;
+            self.persist();
+            return Ok(());
+        }
+        // Acks to our forwards and pulls
+        self.rpc.resolve_reply(reply)
+    }
+
+    async fn snapshot(&self) -> serde_json::Value {
+        let mut seen: Vec<usize> = self.msgs.lock().await.values().iter().copied().collect();
+        seen.sort_unstable();
+        let backoff: HashMap<String, u32> = self
+            .backoff
+            .lock()
+            .await
+            .iter()
+            .map(|(neighbor, backoff)| (neighbor.clone(), backoff.level))
+            .collect();
+        serde_json::json!({ "seen": seen, "backoff": backoff })
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<BroadcastNode, _, _>().await
+    let config = Config {
+        snapshot_at_exit: std::env::var(SNAPSHOT_AT_EXIT_VAR).is_ok_and(|v| v == "1"),
+        ..Config::default()
+    };
+    event_loop_with_config::<BroadcastNode, _, _>(config)
+        .await?
+        .exit_if_signalled();
+    Ok(())
 }
@@ -1,20 +1,74 @@
-use std::{cmp, collections::HashMap, sync::atomic::AtomicUsize, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{
+    crdt::{gossip_round, Gossip, GrowOnlyCounter},
+    event_loop, join_all,
+    liveness::{Liveness, PING_INTERVAL},
+    log,
+    persist::{StateFile, StateWriter},
+    retry::{self, Backoff},
+    rpc::{self, PendingRpc},
+    spawn_timers, Body, Event, Init, KvError, Message, Node, Output, Periodic, Rng,
+};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, time::Instant};
+
+/// Where every node publishes its own sub-counter, for read-repair.
+const STORAGE: &str = "seq-kv";
+/// A peer we haven't had a `Sync` from for this long may be partitioned away,
+/// so a `Read` reads its sub-counter from `STORAGE` before answering.
+const STALE_AFTER: Duration = Duration::from_millis(1000);
+/// How long an RPC, to a peer or to `STORAGE`, waits for its reply.
+const REPAIR_TIMEOUT: Duration = Duration::from_millis(100);
+/// How many times a read-repair reads a stale peer's sub-counter before giving
+/// up on it.
+const REPAIR_ATTEMPTS: usize = 2;
+/// Backoff between read-repair attempts.
+const REPAIR_BACKOFF: Duration = Duration::from_millis(50);
+const REPAIR_BACKOFF_CAP: Duration = Duration::from_millis(200);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Add { delta: u64 },
+    Add {
+        delta: u64,
+    },
     AddOk,
-    Read,
-    ReadOk { value: u64 },
-    Sync { value: u64 },
+    /// A client's read of the counter has no key, our read of the KV store
+    /// does. Both are answered with `read_ok`.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Write {
+        key: String,
+        value: u64,
+    },
+    WriteOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+    Sync {
+        value: u64,
+    },
+    Ping,
+    PingOk,
+}
+
+/// Returns the KV key `node` publishes its sub-counter under.
+fn counter_key(node: &str) -> String {
+    format!("counter:{}", node)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,46 +76,214 @@ enum Payload {
 #[serde(rename_all = "snake_case")]
 enum InjectedPayload {
     Sync,
+    Ping,
+}
+
+impl Periodic for InjectedPayload {
+    fn schedule() -> Vec<(Duration, Self)> {
+        vec![
+            (Duration::from_millis(500), Self::Sync),
+            (PING_INTERVAL, Self::Ping),
+        ]
+    }
 }
 
 struct CounterNode {
-    id: AtomicUsize,
     node: String,
-    nodes: Vec<String>,
-    counter: Mutex<HashMap<String, u64>>,
-    stdout: Mutex<tokio::io::Stdout>,
+    /// Every other node, all of which the counter syncs with.
+    peers: Vec<String>,
+    /// Shared with the state writer, which saves our own sub-counter.
+    counter: Arc<Mutex<GrowOnlyCounter>>,
+    last_sync: Mutex<HashMap<String, Instant>>,
+    /// Peers that stopped answering pings aren't sent syncs.
+    liveness: Mutex<Liveness>,
+    stdout: Output,
+    /// RPCs waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+    state: Option<StateWriter>,
+    /// Held while publishing our sub-counter, so that publishes land in order.
+    publishing: Mutex<()>,
+    /// Set once the first `Read` has repaired every peer.
+    warmed_up: OnceLock<()>,
+    /// Jitter for retry backoff.
+    rng: Mutex<Rng>,
+}
+
+impl CounterNode {
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        let (id, rx) = self.rpc.register();
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        msg.send(&self.stdout).await.context("send rpc message")?;
+        let res = tokio::time::timeout(REPAIR_TIMEOUT, rx).await;
+        self.rpc.cancel(id);
+        res.context("rpc timed out")?
+            .context("receive rpc response")
+    }
+
+    /// Records the sub-counter `value` that `node` reported.
+    async fn merge(&self, node: &str, value: u64) {
+        if !self.counter.lock().await.merge_delta(node, value) {
+            return;
+        }
+        self.last_sync
+            .lock()
+            .await
+            .insert(node.to_string(), Instant::now());
+    }
+
+    /// Reads `key` from `STORAGE`, `None` if it doesn't exist yet.
+    async fn read_kv(&self, key: String) -> anyhow::Result<Option<u64>> {
+        let reply = self.rpc(STORAGE, Payload::Read { key: Some(key) }).await?;
+        match reply.body.payload {
+            Payload::ReadOk { value } => Ok(Some(value)),
+            Payload::Error { code, text } => match KvError::from_code(code, text) {
+                KvError::KeyDoesNotExist => Ok(None),
+                e => Err(e.into()),
+            },
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write_kv(&self, key: String, value: u64) -> anyhow::Result<()> {
+        let reply = self.rpc(STORAGE, Payload::Write { key, value }).await?;
+        match reply.body.payload {
+            Payload::WriteOk => Ok(()),
+            Payload::Error { code, text } => Err(KvError::from_code(code, text).into()),
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    /// Publishes our sub-counter to `STORAGE`, for peers' read-repairs.
+    async fn publish(&self) -> anyhow::Result<()> {
+        let _publishing = self.publishing.lock().await;
+        let value = self.counter.lock().await.get(&self.node);
+        self.write_kv(counter_key(&self.node), value).await
+    }
+
+    /// Pings every peer and records which ones answered within
+    /// `REPAIR_TIMEOUT`.
+    async fn ping_peers(&self) {
+        let answers = join_all(self.peers.iter().map(|peer| self.rpc(peer, Payload::Ping))).await;
+        let mut liveness = self.liveness.lock().await;
+        for (peer, answer) in self.peers.iter().zip(answers) {
+            liveness.record(peer, answer.is_ok());
+        }
+    }
+
+    /// Read-repair: reads the sub-counter every peer whose last `Sync` is
+    /// older than `STALE_AFTER` published to `STORAGE`, so a read doesn't sum
+    /// a view that went stale during a partition. A sub-counter read that is
+    /// behind what we already know of the peer means the store served a stale
+    /// view: we then write a key of our own, a barrier the store must take in
+    /// before answering our next reads, and read again. Peers still behind
+    /// after `REPAIR_ATTEMPTS` reads are left at their last known value.
+    ///
+    /// The first read repairs every peer regardless: a node that just started
+    /// may have taken a `Sync` sent before peers' latest adds.
+    async fn repair(&self) {
+        let first = self.warmed_up.set(()).is_ok();
+        let mut stale: Vec<String> = {
+            let last_sync = self.last_sync.lock().await;
+            self.peers
+                .iter()
+                .filter(|node| {
+                    first
+                        || last_sync
+                            .get(*node)
+                            .is_none_or(|at| at.elapsed() > STALE_AFTER)
+                })
+                .cloned()
+                .collect()
+        };
+        let rng = self.rng.lock().await.fork();
+        let mut backoff = Backoff::new(REPAIR_BACKOFF, REPAIR_BACKOFF_CAP, 0.5, rng);
+        for attempt in 0..REPAIR_ATTEMPTS {
+            if stale.is_empty() {
+                break;
+            }
+            if attempt > 0 {
+                backoff.wait().await;
+                retry::pace(&self.stdout).await;
+                // Unique, so the store can't take it for a write it already has
+                let barrier = self.rpc.next_id() as u64;
+                if let Err(e) = self
+                    .write_kv(format!("barrier:{}", self.node), barrier)
+                    .await
+                {
+                    log!("read-repair barrier write failed: {:#}", e);
+                }
+            }
+            let reads = join_all(stale.iter().map(|peer| self.read_kv(counter_key(peer)))).await;
+            let mut behind = Vec::new();
+            for (peer, read) in stale.into_iter().zip(reads) {
+                let known = self.counter.lock().await.get(&peer);
+                // A peer that never published counts as at 0
+                match read.map(Option::unwrap_or_default) {
+                    Result::Ok(value) if value >= known => self.merge(&peer, value).await,
+                    // Stale, or not read at all
+                    _ => behind.push(peer),
+                }
+            }
+            stale = behind;
+        }
+    }
 }
 
 #[async_trait]
 impl Node<Payload, InjectedPayload> for CounterNode {
+    const NAME: &'static str = "counter";
+
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // Generate a Gossip injection event every 500ms
-        // TODO: handle EOF (AtomicBool?)
-        tokio::spawn(async move {
-            loop {
-                std::thread::sleep(Duration::from_millis(500));
-                if let Err(_) = tx
-                    .send(gossip_glomers::Event::Injected(InjectedPayload::Sync))
-                    .await
-                {
-                    break;
-                }
-            }
+        let peers = init.peers();
+        // Start the sync timer, unless there is no one to sync with
+        if !peers.is_empty() {
+            spawn_timers(&tx);
+        }
+
+        // Only our own sub-counter is saved, the others come back by gossip
+        let mut counter = GrowOnlyCounter::new(init.node_ids.clone());
+        let state_file = StateFile::from_env(Self::NAME, &init.node_id);
+        if let Some(state_file) = &state_file {
+            counter.increment(&init.node_id, state_file.load()?.unwrap_or_default());
+        }
+
+        let counter = Arc::new(Mutex::new(counter));
+        let state = state_file.map(|state_file| {
+            let (counter, node) = (counter.clone(), init.node_id.clone());
+            state_file.spawn_writer(move || {
+                let (counter, node) = (counter.clone(), node.clone());
+                async move { counter.lock().await.get(&node) }
+            })
         });
 
+        let rng = init.rng();
         Ok(Self {
-            id: 1.into(),
             node: init.node_id,
-            nodes: init.node_ids.clone(),
-            counter: Mutex::new(init.node_ids.into_iter().map(|id| (id, 0)).collect()),
+            peers,
+            counter,
+            last_sync: Mutex::new(HashMap::new()),
+            liveness: Mutex::new(Liveness::default()),
+            rpc: PendingRpc::new(&stdout),
             stdout,
+            state,
+            publishing: Mutex::new(()),
+            warmed_up: OnceLock::new(),
+            rng: Mutex::new(rng),
         })
     }
 
@@ -72,11 +294,16 @@ impl Node<Payload, InjectedPayload> for CounterNode {
         match event {
             gossip_glomers::Event::EOF => {}
             gossip_glomers::Event::Message(message) => {
-                let mut reply = message.into_reply(Some(&self.id));
+                let mut reply = message.into_reply(Some(self.rpc.ids()));
                 match reply.body.payload {
                     Payload::Add { delta } => {
-                        if let Some(current) = self.counter.lock().await.get_mut(&reply.src) {
-                            *current += delta;
+                        self.counter.lock().await.increment(&reply.src, delta);
+                        if let Some(state) = &self.state {
+                            state.changed();
+                        }
+                        // Gossip carries the add to peers all the same
+                        if let Err(e) = self.publish().await {
+                            log!("failed to publish the counter: {:#}", e);
                         }
                         reply.body.payload = Payload::AddOk;
                         reply
@@ -84,50 +311,57 @@ impl Node<Payload, InjectedPayload> for CounterNode {
                             .await
                             .context("send add response")?;
                     }
-                    Payload::AddOk => {}
-                    Payload::Read => {
-                        let value = self.counter.lock().await.iter().map(|(_, v)| v).sum();
+                    Payload::Read { .. } => {
+                        self.repair().await;
+                        let value = self.counter.lock().await.value();
                         reply.body.payload = Payload::ReadOk { value };
                         reply
                             .send(&self.stdout)
                             .await
                             .context("send read response")?;
                     }
-                    Payload::ReadOk { .. } => {}
-                    Payload::Sync { value } => {
-                        if let Some(current) = self.counter.lock().await.get_mut(&reply.dest) {
-                            *current = cmp::max(*current, value);
-                        }
-                    }
-                }
-            }
-            gossip_glomers::Event::Injected(_) => {
-                for node in &self.nodes {
-                    if node != &self.node {
-                        let sync_msg = gossip_glomers::Message {
-                            src: self.node.clone(),
-                            dest: node.clone(),
-                            body: gossip_glomers::Body {
-                                id: None,
-                                in_reply_to: None,
-                                payload: Payload::Sync {
-                                    value: self.counter.lock().await[&self.node],
-                                },
-                            },
-                        };
-                        sync_msg
+                    Payload::Sync { value } => self.merge(&reply.dest, value).await,
+                    Payload::Ping => {
+                        reply.body.payload = Payload::PingOk;
+                        reply
                             .send(&self.stdout)
                             .await
-                            .context("send sync message")?;
+                            .context("send ping response")?;
                     }
+                    // Replies go to `handle_reply`
+                    Payload::AddOk
+                    | Payload::ReadOk { .. }
+                    | Payload::Write { .. }
+                    | Payload::WriteOk
+                    | Payload::Error { .. }
+                    | Payload::PingOk => {}
                 }
             }
+            gossip_glomers::Event::Injected(InjectedPayload::Sync) => {
+                gossip_round(
+                    &self.node,
+                    &self.counter,
+                    &self.peers,
+                    &self.liveness,
+                    |peer, value| {
+                        rpc::send_oneway(&self.stdout, &self.node, peer, Payload::Sync { value })
+                    },
+                )
+                .await
+                .context("send sync message")?;
+            }
+            gossip_glomers::Event::Injected(InjectedPayload::Ping) => self.ping_peers().await,
         }
         Ok(())
     }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        self.rpc.resolve_reply(reply)
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<CounterNode, _, _>().await
+    event_loop::<CounterNode, _, _>().await?.exit_if_signalled();
+    Ok(())
 }
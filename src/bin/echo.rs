@@ -2,9 +2,8 @@ use std::sync::atomic::AtomicUsize;
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{event_loop, Event, Init, Node, Output};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -16,15 +15,17 @@ enum Payload {
 
 struct EchoNode {
     id: AtomicUsize,
-    stdout: Mutex<tokio::io::Stdout>,
+    stdout: Output,
 }
 
 #[async_trait]
 impl Node<Payload> for EchoNode {
+    const NAME: &'static str = "echo";
+
     fn from_init(
         _init: Init,
         _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
@@ -37,13 +38,13 @@ impl Node<Payload> for EchoNode {
 
     async fn handle(&self, event: gossip_glomers::Event<Payload>) -> anyhow::Result<()> {
         let gossip_glomers::Event::Message(message) = event else {
-            panic!("unexpected event: {:?}", event);
+            // EOF carries nothing to reply to
+            return Ok(());
         };
-        let mut reply = message.into_reply(Some(&self.id));
-        match reply.body.payload {
+        match message.body.payload.clone() {
             Payload::Echo { echo } => {
-                reply.body.payload = Payload::EchoOk { echo };
-                reply
+                message
+                    .into_reply_with(Some(&self.id), Payload::EchoOk { echo })
                     .send(&self.stdout)
                     .await
                     .context("send response message")?;
@@ -56,5 +57,6 @@ impl Node<Payload> for EchoNode {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<EchoNode, _, _>().await
+    event_loop::<EchoNode, _, _>().await?.exit_if_signalled();
+    Ok(())
 }
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashSet,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{
+    crdt::{gossip_round, Gossip, GrowOnlySet},
+    event_loop, join_all,
+    liveness::{Liveness, PING_INTERVAL},
+    log,
+    rpc::{self, PendingRpc},
+    spawn_timers, Body, Event, Init, Message, Node, Output, Periodic,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// How long a ping waits for its answer before the peer is marked down.
+const PING_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A g-set element. Elements can be any JSON value, which isn't `Hash`, so
+/// they are hashed through their serialized form (objects serialize with
+/// sorted keys, so equal values hash equally).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+struct Element(serde_json::Value);
+
+impl Hash for Element {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Add { element: Element },
+    AddOk,
+    Read,
+    ReadOk { value: HashSet<Element> },
+    Gossip { seen: HashSet<Element> },
+    Ping,
+    PingOk,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InjectedPayload {
+    Gossip,
+    Ping,
+}
+
+impl Periodic for InjectedPayload {
+    fn schedule() -> Vec<(Duration, Self)> {
+        vec![
+            (Duration::from_millis(500), Self::Gossip),
+            (PING_INTERVAL, Self::Ping),
+        ]
+    }
+}
+
+struct GSetNode {
+    node: String,
+    peers: Vec<String>,
+    elements: Mutex<GrowOnlySet<Element>>,
+    /// Peers that stopped answering pings aren't gossiped to.
+    liveness: Mutex<Liveness>,
+    stdout: Output,
+    /// RPCs waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+}
+
+impl GSetNode {
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        let (id, rx) = self.rpc.register();
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        msg.send(&self.stdout).await.context("send rpc message")?;
+        let res = tokio::time::timeout(PING_TIMEOUT, rx).await;
+        self.rpc.cancel(id);
+        res.context("rpc timed out")?
+            .context("receive rpc response")
+    }
+
+    /// Pings every peer and records which ones answered.
+    async fn ping_peers(&self) {
+        let answers = join_all(self.peers.iter().map(|peer| self.rpc(peer, Payload::Ping))).await;
+        let mut liveness = self.liveness.lock().await;
+        for (peer, answer) in self.peers.iter().zip(answers) {
+            liveness.record(peer, answer.is_ok());
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload, InjectedPayload> for GSetNode {
+    const NAME: &'static str = "g-set";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        // The g-set workload sends no topology, so gossip to every other node
+        let peers = init.peers();
+        // Start the gossip timer, unless there is no one to gossip with
+        if !peers.is_empty() {
+            spawn_timers(&tx);
+        }
+        Ok(Self {
+            node: init.node_id,
+            elements: Mutex::new(GrowOnlySet::new(peers.iter().cloned())),
+            peers,
+            liveness: Mutex::new(Liveness::default()),
+            rpc: PendingRpc::new(&stdout),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        match event {
+            Event::EOF => {}
+            Event::Message(message) => {
+                let mut reply = message.into_reply(Some(self.rpc.ids()));
+                match reply.body.payload {
+                    Payload::Add { element } => {
+                        self.elements.lock().await.insert(element);
+                        reply.body.payload = Payload::AddOk;
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send add response")?;
+                    }
+                    Payload::Read => {
+                        reply.body.payload = Payload::ReadOk {
+                            value: self.elements.lock().await.values().clone(),
+                        };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send read response")?;
+                    }
+                    Payload::Gossip { seen } => {
+                        if !self.elements.lock().await.merge_delta(&reply.dest, seen) {
+                            log!("ignoring gossip from unknown node {}", reply.dest);
+                        }
+                    }
+                    Payload::Ping => {
+                        reply.body.payload = Payload::PingOk;
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send ping response")?;
+                    }
+                    // Replies go to `handle_reply`
+                    Payload::AddOk | Payload::ReadOk { .. } | Payload::PingOk => {}
+                }
+            }
+            Event::Injected(InjectedPayload::Gossip) => {
+                gossip_round(
+                    &self.node,
+                    &self.elements,
+                    &self.peers,
+                    &self.liveness,
+                    |peer, seen| {
+                        rpc::send_oneway(&self.stdout, &self.node, peer, Payload::Gossip { seen })
+                    },
+                )
+                .await
+                .context("send gossip message")?;
+            }
+            Event::Injected(InjectedPayload::Ping) => self.ping_peers().await,
+        }
+        Ok(())
+    }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        self.rpc.resolve_reply(reply)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<GSetNode, _, _>().await?.exit_if_signalled();
+    Ok(())
+}
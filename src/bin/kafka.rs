@@ -1,16 +1,70 @@
 use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicUsize, Ordering},
+    cmp,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Context;
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Body, Event, Init, Message, Node, KV};
+use gossip_glomers::breaker::CircuitBreaker;
+use gossip_glomers::{
+    event_loop, join_all, log,
+    persist::{StateFile, StateWriter},
+    retry::Backoff,
+    rpc::PendingRpc,
+    serialize_sorted_map, Body, ErrorPayload, Event, Init, KvError, Message, Node, Output, Rng,
+    StoredValue, KV,
+};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::{
+    sync::{Mutex, Semaphore},
+    time::Instant,
+};
 
 const MSG_SIZE: i64 = 5;
 
+/// Maximum number of keys a single node reads concurrently while serving polls.
+const POLL_CONCURRENCY: usize = 8;
+
+/// How long a poll's result for a key and offset is reused by later polls from
+/// the same offset. Sends through this node invalidate it right away; sends
+/// through other nodes show up once it expires.
+const POLL_CACHE_TTL: Duration = Duration::from_millis(200);
+/// Most poll results cached at once.
+const POLL_CACHE_CAPACITY: usize = 1024;
+
+/// How long an offset below the latest one may stay empty before a poll
+/// declares it a hole and tombstones it. Much longer than a send takes
+/// between reserving an offset and writing its message.
+const HOLE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often, and how long apart, a poll reads the message at its offset
+/// again when the offset is reserved but the send hasn't written the message
+/// yet, before answering without it.
+const POLL_WAIT_RETRIES: u32 = 3;
+const POLL_WAIT: Duration = Duration::from_millis(20);
+/// How long a KV request waits for its answer.
+const KV_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many times a read is retried while its store has never answered yet,
+/// e.g. because it is still starting, and the backoff between those retries.
+/// After the last one the key is taken to be absent.
+const STARTUP_RETRIES: u32 = 3;
+const STARTUP_BACKOFF: Duration = Duration::from_millis(50);
+const STARTUP_BACKOFF_CAP: Duration = Duration::from_millis(400);
+/// Consecutive timeouts that open a store's circuit breaker, and how long it
+/// then stays open before letting a probe through.
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(1);
+/// Environment variables naming the linearizable and the sequential store,
+/// for running against services other than Maelstrom's `lin-kv` and `seq-kv`.
+const LIN_KV_VAR: &str = "GLOMERS_LIN_KV";
+const SEQ_KV_VAR: &str = "GLOMERS_SEQ_KV";
+/// Environment variable choosing the store of message bodies: `seq` (the
+/// default) or `lin`. Bodies in the sequential store may not be visible yet to
+/// a poll on another node right after their send; the linearizable store makes
+/// every acknowledged send visible to the next poll, at the cost of latency.
+const MSG_STORE_VAR: &str = "GLOMERS_MSG_STORE";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -24,80 +78,369 @@ enum Payload {
     },
     Poll {
         offsets: HashMap<String, i64>,
+        /// Answer with `poll_batch_ok`, which also reports each key's latest
+        /// and committed offset so a consumer learns its lag in one round trip.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        markers: bool,
+        /// Group whose committed offsets `markers` reports.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        /// Roughly how many bytes of messages the reply may carry. A reply cut
+        /// short by it ends early for some keys, and the consumer carries on
+        /// by polling again from past the last offsets it got.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
     },
+    /// `msgs` holds `[offset, msg]` pairs for each polled key, in offset
+    /// order. They run contiguously from the requested offset and stop before
+    /// the first offset without a message, so a consumer that commits the last
+    /// offset it got can't skip a message. The only offsets left out are
+    /// tombstoned ones, which will never hold a message.
     PollOk {
+        #[serde(serialize_with = "serialize_sorted_map")]
+        msgs: HashMap<String, Vec<Vec<i64>>>,
+    },
+    PollBatchOk {
+        #[serde(serialize_with = "serialize_sorted_map")]
         msgs: HashMap<String, Vec<Vec<i64>>>,
+        #[serde(serialize_with = "serialize_sorted_map")]
+        latest: HashMap<String, i64>,
+        #[serde(serialize_with = "serialize_sorted_map")]
+        committed: HashMap<String, i64>,
     },
+    /// A poll of the last `count` messages of each key, for consumers that
+    /// only want the tail. Answered with `poll_ok`, in offset order like any
+    /// poll.
+    PollLatest {
+        keys: Vec<String>,
+        count: usize,
+    },
+    /// A poll that also commits, for each key, the last offset it returns,
+    /// saving auto-committing consumers a `commit_offsets` round trip.
+    PollCommit {
+        offsets: HashMap<String, i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    PollCommitOk {
+        #[serde(serialize_with = "serialize_sorted_map")]
+        msgs: HashMap<String, Vec<Vec<i64>>>,
+    },
+    /// Checks that `offset` is one of `key`'s, i.e. in `[0, latest]`, for a
+    /// consumer about to replay the key from there. The consumer keeps its
+    /// position itself; committed offsets stay as they are.
+    Seek {
+        key: String,
+        offset: i64,
+    },
+    SeekOk {
+        offset: i64,
+    },
+    Subscribe {
+        group: String,
+        keys: Vec<String>,
+    },
+    SubscribeOk,
     CommitOffsets {
         offsets: HashMap<String, i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
+        #[serde(serialize_with = "serialize_sorted_map")]
         offsets: HashMap<String, i64>,
     },
     Read {
         key: String,
     },
     ReadOk {
-        value: i64,
+        value: StoredValue,
     },
     Error {
         code: usize,
         text: String,
+        /// The key's current value, included by some stores when a `cas`
+        /// fails its precondition.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        current: Option<StoredValue>,
     },
     Write {
         key: String,
-        value: i64,
+        value: StoredValue,
     },
     WriteOk {},
     Cas {
         key: String,
-        from: i64,
-        to: i64,
+        from: StoredValue,
+        to: StoredValue,
         #[serde(default, rename = "create_if_not_exists")]
         put: bool,
     },
     CasOk {},
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum InjectedPayload {}
-
 struct KafkaNode {
-    id: AtomicUsize,
     node: String,
-    stdout: Mutex<tokio::io::Stdout>,
+    stdout: Output,
     storage_lin: String,
     storage_seq: String,
-    rpc: Mutex<HashMap<usize, tokio::sync::oneshot::Sender<Message<Payload>>>>,
+    /// Store holding the message bodies, one of the two above.
+    storage_msg: String,
+    /// RPCs waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+    poll_permits: Semaphore,
+    /// When polls first found each still empty message key empty.
+    holes: Mutex<HashMap<String, Instant>>,
+    /// Offset the next send of each key tries first: the one after the last
+    /// offset this node reserved.
+    next_offsets: Arc<Mutex<HashMap<String, i64>>>,
+    /// Committed offset last seen under each committed key, which the next
+    /// commit's cas starts from.
+    committed: Arc<Mutex<HashMap<String, i64>>>,
+    /// Saves the two above, if persistence is enabled.
+    state: Option<StateWriter>,
+    /// How many offset-allocation iterations the sends of each key took, as
+    /// iterations -> sends. Logged at shutdown; keys whose sends often take
+    /// more than one are contended.
+    cas_iterations: Mutex<HashMap<String, BTreeMap<usize, usize>>>,
+    breaker: CircuitBreaker,
+    /// Recent poll results by key and offset.
+    poll_cache: Mutex<HashMap<(String, i64), CachedPoll>>,
+    /// Stores that have answered a read, after which reads aren't retried.
+    ready: Mutex<HashSet<String>>,
+    /// Jitter for the startup backoff.
+    rng: Mutex<Rng>,
+}
+
+/// What a node saves of its offsets, so that a restarted node's first sends
+/// and commits don't start from scratch. Only hints: the stores have the say.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SavedOffsets {
+    #[serde(default)]
+    next_offsets: HashMap<String, i64>,
+    #[serde(default)]
+    committed: HashMap<String, i64>,
+}
+
+#[derive(Debug)]
+struct CachedPoll {
+    read_at: Instant,
+    msgs: Vec<Vec<i64>>,
+}
+
+/// What a message key holds, stored as `{"msg": msg}` or `"tombstone"`: tagged,
+/// so that no message, whatever its value, can pass for a tombstone.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Slot {
+    /// The message of the send that reserved the offset.
+    Msg(i64),
+    /// Marks an offset whose send never wrote its message.
+    Tombstone,
+}
+
+impl From<Slot> for StoredValue {
+    fn from(slot: Slot) -> Self {
+        StoredValue::Json(serde_json::to_value(slot).expect("a slot serializes"))
+    }
+}
+
+impl TryFrom<StoredValue> for Slot {
+    type Error = anyhow::Error;
+
+    fn try_from(value: StoredValue) -> anyhow::Result<Self> {
+        serde_json::from_value(value.into_json())
+            .context("message key holds neither a message nor a tombstone")
+    }
+}
+
+/// Returns the KV key holding the committed offset of `key`. Commits without a
+/// group belong to the implicit default group, which keeps the original
+/// `committed:{key}` layout.
+fn committed_key(group: Option<&str>, key: &str) -> String {
+    match group {
+        Some(group) => format!("committed:{}:{}", group, key),
+        None => format!("committed:{}", key),
+    }
+}
+
+/// Returns the KV key holding the keys `group` subscribed to, next to its
+/// committed offsets, so that every node sees the same subscriptions.
+fn subscriptions_key(group: &str) -> String {
+    format!("subscriptions:{}", group)
+}
+
+/// Cuts `msgs` down to about `max_bytes` of serialized `[offset, msg]` pairs,
+/// taking keys in order and each key's messages from its lowest offset, so what
+/// is left still runs contiguously from every requested offset. The first
+/// message is kept even if it alone is over budget, so a consumer always makes
+/// progress.
+fn fit_budget(msgs: &mut HashMap<String, Vec<Vec<i64>>>, max_bytes: usize) {
+    let mut keys: Vec<_> = msgs.keys().cloned().collect();
+    keys.sort_unstable();
+    let mut used = 0;
+    let mut kept = 0;
+    for key in keys {
+        let key_msgs = msgs.get_mut(&key).expect("key of msgs");
+        // The key itself, quoted, with its colon and brackets
+        used += key.len() + 5;
+        let mut fits = 0;
+        for pair in key_msgs.iter() {
+            // `[offset,msg]` and the comma after it
+            used += pair.iter().map(|n| n.to_string().len()).sum::<usize>() + 4;
+            if used > max_bytes && kept > 0 {
+                break;
+            }
+            fits += 1;
+            kept += 1;
+        }
+        key_msgs.truncate(fits);
+    }
 }
 
 impl KafkaNode {
-    async fn rpc(&self, to: &String, payload: Payload) -> anyhow::Result<Message<Payload>> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
+    /// Sends `payload` to the store `to` and waits for its answer. Fails fast
+    /// while the store's circuit breaker is open, and times out after
+    /// `KV_TIMEOUT`.
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        self.breaker.check(to)?;
+        let (id, rx) = self.rpc.register();
         let msg = Message {
             src: self.node.clone(),
-            dest: to.clone(),
+            dest: to.to_string(),
             body: Body {
-                id: self.id.fetch_add(1, Ordering::SeqCst).into(),
+                id: Some(id),
                 in_reply_to: None,
                 payload,
             },
         };
-        self.rpc.lock().await.insert(msg.body.id.unwrap(), tx);
         msg.send(&self.stdout).await.context("send rpc message")?;
-        rx.await.context("receive rpc response")
+        let Ok(reply) = tokio::time::timeout(KV_TIMEOUT, rx).await else {
+            self.rpc.cancel(id);
+            self.breaker.record_timeout(to);
+            return Err(KvError::Timeout.into());
+        };
+        let reply = reply.context("receive rpc response")?;
+        match reply.body.payload {
+            Payload::Error { code: 0, .. } => self.breaker.record_timeout(to),
+            _ => self.breaker.record_answer(to),
+        }
+        Ok(reply)
     }
-}
 
-#[async_trait]
-impl KV<i64> for KafkaNode {
-    async fn read(&self, storage: &String, key: String) -> anyhow::Result<i64> {
+    /// Sets the committed offset under `committed_key` to `offset`, unless it
+    /// is already at or past it: a late or retried commit must not move a
+    /// consumer group back. Runs a cas loop, from the current offset each failed
+    /// cas reports.
+    async fn advance_committed(&self, committed_key: String, offset: i64) -> anyhow::Result<()> {
+        let seen = self.committed.lock().await.get(&committed_key).copied();
+        // Committed offsets only grow, so it is at least what we saw last
+        if seen.is_some_and(|seen| seen >= offset) {
+            return Ok(());
+        }
+        // A key that doesn't exist yet is created whatever `current` says
+        let mut current = seen.unwrap_or_default();
+        let stored = loop {
+            let res = self
+                .cas_or_current(
+                    &self.storage_seq,
+                    committed_key.clone(),
+                    current,
+                    offset,
+                    true,
+                )
+                .await;
+            match res {
+                Ok(()) => break offset,
+                Err((KvError::PreconditionFailed, Some(stored))) if stored >= offset => {
+                    break stored;
+                }
+                Err((KvError::PreconditionFailed, Some(stored))) => current = stored,
+                Err((err, _)) => return Err(err).context("advance committed offset"),
+            }
+        };
+        let mut committed = self.committed.lock().await;
+        let seen = committed.entry(committed_key).or_default();
+        *seen = cmp::max(*seen, stored);
+        self.persist();
+        Ok(())
+    }
+
+    /// Reads the keys `group` subscribed to, or `None` if it never did.
+    async fn subscriptions(&self, group: &str) -> anyhow::Result<Option<BTreeSet<String>>> {
+        let res = self
+            .read_stored(&self.storage_seq, subscriptions_key(group))
+            .await;
+        match res {
+            Ok(value) => Ok(Some(
+                serde_json::from_value(value.into_json()).context("read subscriptions")?,
+            )),
+            Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => Ok(None),
+            Err(e) => Err(e.context("read subscriptions")),
+        }
+    }
+
+    /// Adds `keys` to the keys `group` subscribed to. Runs a cas loop, so
+    /// concurrent subscriptions through different nodes all land.
+    async fn subscribe(&self, group: &str, keys: Vec<String>) -> anyhow::Result<()> {
+        let mut current = self.subscriptions(group).await?;
+        loop {
+            let mut next = current.clone().unwrap_or_default();
+            next.extend(keys.iter().cloned());
+            if current.as_ref() == Some(&next) {
+                return Ok(());
+            }
+            // A key that doesn't exist yet is created whatever `from` says
+            let from = serde_json::to_value(current.unwrap_or_default())?;
+            let to = serde_json::to_value(next)?;
+            let res = self
+                .cas_stored(
+                    &self.storage_seq,
+                    subscriptions_key(group),
+                    from.into(),
+                    to.into(),
+                    true,
+                )
+                .await;
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if KvError::classify(&e) == KvError::PreconditionFailed => {
+                    current = self.subscriptions(group).await?;
+                }
+                Err(e) => return Err(e.context("subscribe")),
+            }
+        }
+    }
+
+    /// Has the offsets saved if persistence is enabled.
+    fn persist(&self) {
+        if let Some(state) = &self.state {
+            state.changed();
+        }
+    }
+
+    /// Answers `request` with a Maelstrom error.
+    async fn send_error(
+        &self,
+        request: Message<()>,
+        code: usize,
+        text: impl Into<String>,
+    ) -> anyhow::Result<()> {
+        request
+            .into_error_reply(Some(self.rpc.ids()), code, text)
+            .send(&self.stdout)
+            .await
+            .context("send error response")
+    }
+
+    /// A single read of `key`, see `read_stored`.
+    async fn read_once(&self, storage: &str, key: String) -> anyhow::Result<StoredValue> {
         let payload = Payload::Read { key };
         let result = self
             .rpc(storage, payload)
@@ -105,113 +448,559 @@ impl KV<i64> for KafkaNode {
             .context("read from storage")?;
         match result.body.payload {
             Payload::ReadOk { value } => Ok(value),
+            Payload::Error { code, text, .. } => Err(KvError::from_code(code, text).into()),
             _ => anyhow::bail!("unexpected payload"),
         }
     }
 
-    async fn write(&self, storage: &String, key: String, value: i64) -> anyhow::Result<()> {
-        let payload = Payload::Write { key, value };
-        let _result = self.rpc(storage, payload).await.context("write to storage");
-        Ok(())
+    /// Reads `key` as whatever kind of value it holds. Until `storage` first
+    /// answers, a read it times out or is unavailable for is retried
+    /// `STARTUP_RETRIES` times, so a store that starts after the node doesn't
+    /// fail its first requests.
+    async fn read_stored(&self, storage: &str, key: String) -> anyhow::Result<StoredValue> {
+        let mut backoff = None;
+        let mut attempt = 0;
+        loop {
+            let res = self.read_once(storage, key.clone()).await;
+            let unavailable = res.as_ref().is_err_and(|e| {
+                matches!(
+                    KvError::classify(e),
+                    KvError::Timeout | KvError::TemporarilyUnavailable
+                )
+            });
+            if !unavailable {
+                self.ready.lock().await.insert(storage.to_string());
+                return res;
+            }
+            if self.ready.lock().await.contains(storage) {
+                return res;
+            }
+            if attempt == STARTUP_RETRIES {
+                log!(
+                    "{} hasn't answered {} reads, taking {} to be absent",
+                    storage,
+                    attempt + 1,
+                    key
+                );
+                return Err(KvError::KeyDoesNotExist.into());
+            }
+            if backoff.is_none() {
+                let rng = self.rng.lock().await.fork();
+                backoff = Some(Backoff::new(STARTUP_BACKOFF, STARTUP_BACKOFF_CAP, 0.5, rng));
+            }
+            if let Some(backoff) = &mut backoff {
+                backoff.wait().await;
+            }
+            attempt += 1;
+        }
     }
 
-    async fn cas(
+    /// Reads the message key `msg_key`.
+    async fn read_slot(&self, msg_key: String) -> anyhow::Result<Slot> {
+        self.read_stored(&self.storage_msg, msg_key)
+            .await?
+            .try_into()
+    }
+
+    /// Like `KV::cas`, for any kind of value.
+    async fn cas_stored(
         &self,
-        storage: &String,
+        storage: &str,
         key: String,
-        from: i64,
-        to: i64,
+        from: StoredValue,
+        to: StoredValue,
         put: bool,
     ) -> anyhow::Result<()> {
         let payload = Payload::Cas { key, from, to, put };
         let result = self.rpc(storage, payload).await.context("cas to storage")?;
         match result.body.payload {
             Payload::CasOk {} => Ok(()),
+            Payload::Error { code, text, .. } => Err(KvError::from_code(code, text).into()),
             _ => anyhow::bail!("unexpected payload"),
         }
     }
+
+    /// Creates the message key `msg_key` holding `slot`, like `KV::create`.
+    async fn create_slot(&self, msg_key: String, slot: Slot) -> anyhow::Result<()> {
+        let res = self
+            .cas_stored(&self.storage_msg, msg_key, slot.into(), slot.into(), true)
+            .await;
+        match res {
+            Err(e) if KvError::classify(&e) == KvError::PreconditionFailed => {
+                Err(KvError::KeyAlreadyExists.into())
+            }
+            res => res,
+        }
+    }
+
+    /// Like `read_msgs`, but reuses what a poll from the same offset read less
+    /// than `POLL_CACHE_TTL` ago.
+    async fn poll_key(&self, key: &str, offset: i64) -> anyhow::Result<Vec<Vec<i64>>> {
+        let cache_key = (key.to_string(), offset);
+        if let Some(cached) = self.poll_cache.lock().await.get(&cache_key) {
+            if cached.read_at.elapsed() < POLL_CACHE_TTL {
+                return Ok(cached.msgs.clone());
+            }
+        }
+        let msgs = self.read_msgs(key, offset).await?;
+        let mut cache = self.poll_cache.lock().await;
+        if cache.len() >= POLL_CACHE_CAPACITY {
+            cache.retain(|_, cached| cached.read_at.elapsed() < POLL_CACHE_TTL);
+        }
+        if cache.len() < POLL_CACHE_CAPACITY {
+            let cached = CachedPoll {
+                read_at: Instant::now(),
+                msgs: msgs.clone(),
+            };
+            cache.insert(cache_key, cached);
+        }
+        Ok(msgs)
+    }
+
+    /// Reads up to `MSG_SIZE` messages of `key` starting at `offset`, skipping
+    /// tombstones. Stops at the first offset whose message isn't written yet,
+    /// so a poll never skips a message that is still being sent; if that is
+    /// the very first one, waits up to `POLL_WAIT_RETRIES` times for it to
+    /// land rather than answer with nothing.
+    async fn read_msgs(&self, key: &str, offset: i64) -> anyhow::Result<Vec<Vec<i64>>> {
+        let _permit = self
+            .poll_permits
+            .acquire()
+            .await
+            .context("acquire poll permit")?;
+        // Offsets past the latest one aren't reserved yet, so they aren't holes
+        let Ok(latest) = self
+            .read(&self.storage_lin, format!("latest:{}", key))
+            .await
+        else {
+            return Ok(Vec::new());
+        };
+        let mut msg = Vec::new();
+        let mut id = offset;
+        let mut waits = 0;
+        while id <= latest && msg.len() < MSG_SIZE as usize {
+            let msg_key = format!("{}:{}", key, id);
+            let res = self
+                .read_slot(msg_key.clone())
+                .await
+                .context("read message");
+            match res {
+                Ok(slot) => {
+                    self.holes.lock().await.remove(&msg_key);
+                    if let Slot::Msg(value) = slot {
+                        msg.push(vec![id, value]);
+                    }
+                }
+                Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => {
+                    // A hole that was tombstoned is skipped like any tombstone
+                    if !self.fill_hole(msg_key).await {
+                        if msg.is_empty() && waits < POLL_WAIT_RETRIES {
+                            waits += 1;
+                            tokio::time::sleep(POLL_WAIT).await;
+                            continue;
+                        }
+                        break;
+                    }
+                }
+                Err(_) => break,
+            };
+            id += 1;
+        }
+        Ok(msg)
+    }
+
+    /// Polls every key from its offset, concurrently.
+    async fn poll_keys(
+        &self,
+        offsets: HashMap<String, i64>,
+    ) -> anyhow::Result<HashMap<String, Vec<Vec<i64>>>> {
+        let offsets: Vec<_> = offsets.into_iter().collect();
+        let results = join_all(
+            offsets
+                .iter()
+                .map(|(key, offset)| self.poll_key(key, *offset)),
+        )
+        .await;
+        let mut msgs = HashMap::new();
+        for ((key, _), msg) in offsets.into_iter().zip(results) {
+            msgs.insert(key, msg?);
+        }
+        Ok(msgs)
+    }
+
+    /// Reads the last `count` messages of `key`, from its latest offset back
+    /// to 0, and returns them in ascending order. Offsets without a message,
+    /// tombstoned or still being sent, are left out rather than ending the
+    /// read, since a tail has no starting offset to keep contiguous with.
+    async fn read_tail(&self, key: &str, count: usize) -> anyhow::Result<Vec<Vec<i64>>> {
+        let _permit = self
+            .poll_permits
+            .acquire()
+            .await
+            .context("acquire poll permit")?;
+        let Ok(latest) = self
+            .read(&self.storage_lin, format!("latest:{}", key))
+            .await
+        else {
+            return Ok(Vec::new());
+        };
+        let mut msgs = Vec::new();
+        for id in (0..=latest).rev() {
+            if msgs.len() >= count {
+                break;
+            }
+            let res = self.read_slot(format!("{}:{}", key, id)).await;
+            match res {
+                Ok(Slot::Msg(value)) => msgs.push(vec![id, value]),
+                Ok(Slot::Tombstone) => {}
+                Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => {}
+                Err(e) => return Err(e.context("read message")),
+            }
+        }
+        msgs.reverse();
+        Ok(msgs)
+    }
+
+    /// Tombstones the empty message key `msg_key` if it has been empty for
+    /// longer than `HOLE_TIMEOUT`, i.e. its send most likely crashed after
+    /// reserving the offset. Returns whether the key now holds a tombstone.
+    async fn fill_hole(&self, msg_key: String) -> bool {
+        let since = *self
+            .holes
+            .lock()
+            .await
+            .entry(msg_key.clone())
+            .or_insert_with(Instant::now);
+        if since.elapsed() < HOLE_TIMEOUT {
+            return false;
+        }
+        // Races the late send, if there is one: whichever creates the key wins
+        let filled = self
+            .create_slot(msg_key.clone(), Slot::Tombstone)
+            .await
+            .is_ok();
+        self.holes.lock().await.remove(&msg_key);
+        if filled {
+            log!("tombstoned hole at {}", msg_key);
+        }
+        filled
+    }
+
+    /// Reads the latest and the committed offset of every key. Offsets that
+    /// can't be read are reported as 0; they are only a hint for the consumer.
+    async fn markers(
+        &self,
+        keys: impl Iterator<Item = String>,
+        group: Option<&str>,
+    ) -> (HashMap<String, i64>, HashMap<String, i64>) {
+        let keys: Vec<String> = keys.collect();
+        let mut reads = Vec::with_capacity(keys.len());
+        for key in &keys {
+            reads.push(async move {
+                let latest = self.read(&self.storage_lin, format!("latest:{}", key));
+                let committed = self.read(&self.storage_seq, committed_key(group, key));
+                (
+                    latest.await.unwrap_or_default(),
+                    committed.await.unwrap_or_default(),
+                )
+            });
+        }
+        let offsets = join_all(reads).await;
+        let mut latest = HashMap::new();
+        let mut committed = HashMap::new();
+        for (key, (l, c)) in keys.into_iter().zip(offsets) {
+            latest.insert(key.clone(), l);
+            committed.insert(key, c);
+        }
+        (latest, committed)
+    }
+}
+
+#[async_trait]
+impl KV<i64> for KafkaNode {
+    /// Retries while the store is starting, see `read_stored`.
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<i64> {
+        let value = self.read_stored(storage, key).await?;
+        Ok(value.into_int().context("read from storage")?)
+    }
+
+    async fn write(&self, storage: &str, key: String, value: i64) -> anyhow::Result<()> {
+        let payload = Payload::Write {
+            key,
+            value: value.into(),
+        };
+        let _result = self.rpc(storage, payload).await.context("write to storage");
+        Ok(())
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: i64,
+        to: i64,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        self.cas_stored(storage, key, from.into(), to.into(), put)
+            .await
+    }
+
+    async fn cas_or_current(
+        &self,
+        storage: &str,
+        key: String,
+        from: i64,
+        to: i64,
+        put: bool,
+    ) -> Result<(), (KvError, Option<i64>)> {
+        let payload = Payload::Cas {
+            key: key.clone(),
+            from: from.into(),
+            to: to.into(),
+            put,
+        };
+        let result = match self.rpc(storage, payload).await {
+            Ok(result) => result,
+            Err(e) => return Err((KvError::classify(&e), None)),
+        };
+        let (err, current) = match result.body.payload {
+            Payload::CasOk {} => return Ok(()),
+            Payload::Error {
+                code,
+                text,
+                current,
+            } => (
+                KvError::from_code(code, text),
+                current.and_then(|current| current.into_int().ok()),
+            ),
+            _ => {
+                return Err((
+                    KvError::classify(&anyhow::anyhow!("unexpected payload")),
+                    None,
+                ))
+            }
+        };
+        // Fall back to reading the value if the store didn't include it
+        let current = match (&err, current) {
+            (KvError::PreconditionFailed, None) => self.read(storage, key).await.ok(),
+            (_, current) => current,
+        };
+        Err((err, current))
+    }
 }
 
 #[async_trait]
-impl Node<Payload, InjectedPayload> for KafkaNode {
+impl Node<Payload> for KafkaNode {
+    const NAME: &'static str = "kafka";
+
     fn from_init(
         init: Init,
-        _tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        let id = AtomicUsize::new(1);
-        let storage_lin = "lin-kv".to_string();
-        let storage_seq = "seq-kv".to_string();
+        let storage_lin = std::env::var(LIN_KV_VAR).unwrap_or_else(|_| "lin-kv".to_string());
+        let storage_seq = std::env::var(SEQ_KV_VAR).unwrap_or_else(|_| "seq-kv".to_string());
+        let storage_msg = match std::env::var(MSG_STORE_VAR).as_deref() {
+            Err(_) | Ok("seq") => storage_seq.clone(),
+            Ok("lin") => storage_lin.clone(),
+            Ok(other) => anyhow::bail!("{} must be seq or lin, not {:?}", MSG_STORE_VAR, other),
+        };
+
+        let state_file = StateFile::from_env(Self::NAME, &init.node_id);
+        let saved: SavedOffsets = match &state_file {
+            Some(state_file) => state_file.load()?.unwrap_or_default(),
+            None => SavedOffsets::default(),
+        };
+        let next_offsets = Arc::new(Mutex::new(saved.next_offsets));
+        let committed = Arc::new(Mutex::new(saved.committed));
+        let state = state_file.map(|state_file| {
+            let (next_offsets, committed) = (next_offsets.clone(), committed.clone());
+            state_file.spawn_writer(move || {
+                let (next_offsets, committed) = (next_offsets.clone(), committed.clone());
+                async move {
+                    SavedOffsets {
+                        next_offsets: next_offsets.lock().await.clone(),
+                        committed: committed.lock().await.clone(),
+                    }
+                }
+            })
+        });
 
+        let rng = init.rng();
         Ok(Self {
-            id,
             node: init.node_id,
+            rpc: PendingRpc::new(&stdout),
             stdout,
             storage_lin,
             storage_seq,
-            rpc: Mutex::new(HashMap::new()),
+            storage_msg,
+            poll_permits: Semaphore::new(POLL_CONCURRENCY),
+            holes: Mutex::new(HashMap::new()),
+            next_offsets,
+            committed,
+            state,
+            cas_iterations: Mutex::new(HashMap::new()),
+            breaker: CircuitBreaker::new(BREAKER_THRESHOLD, BREAKER_COOLDOWN),
+            poll_cache: Mutex::new(HashMap::new()),
+            ready: Mutex::new(HashSet::new()),
+            rng: Mutex::new(rng),
         })
     }
 
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.storage_lin.is_empty(), "{} is empty", LIN_KV_VAR);
+        anyhow::ensure!(!self.storage_seq.is_empty(), "{} is empty", SEQ_KV_VAR);
+        anyhow::ensure!(
+            self.storage_lin != self.storage_seq,
+            "{} and {} name the same store",
+            LIN_KV_VAR,
+            SEQ_KV_VAR
+        );
+        Ok(())
+    }
+
     /// # Handle incoming messages
     ///
     /// We will store the messages and offsets in the following format in the KV store:
-    /// - {key}:{offset} -> {msg}
+    /// - {key}:{offset} -> {"msg": msg}, or "tombstone", in the store chosen by
+    ///   `GLOMERS_MSG_STORE`
     /// - latest:{key} -> {offset}
     /// - committed:{key} -> {offset}
-    async fn handle(
-        &self,
-        event: gossip_glomers::Event<Payload, InjectedPayload>,
-    ) -> anyhow::Result<()> {
+    /// - committed:{group}:{key} -> {offset}
+    /// - subscriptions:{group} -> [{key}, ...]
+    ///
+    /// A send first reserves an offset by bumping `latest:{key}`, then creates
+    /// `{key}:{offset}`. A send that dies in between leaves a hole: a reserved
+    /// offset without a message. Polls stop at empty offsets, so they never
+    /// skip a message that is still being written. An offset that stays empty
+    /// for `HOLE_TIMEOUT` is tombstoned, and later polls skip it, so a crashed
+    /// send can't stall consumers for good. The send and the tombstone both
+    /// create the key, so exactly one of them wins. A send that loses reserves
+    /// a new offset, so an acked offset always holds its message.
+    async fn handle(&self, event: gossip_glomers::Event<Payload>) -> anyhow::Result<()> {
         match event {
-            gossip_glomers::Event::EOF => {}
-            gossip_glomers::Event::Message(message) => {
-                // Handle RPC responses
-                if message.body.in_reply_to.is_some() {
-                    let id = message.body.in_reply_to.unwrap();
-                    let tx = self.rpc.lock().await.remove(&id).unwrap();
-                    if let Err(_) = tx.send(message) {
-                        anyhow::bail!("rpc response channel closed");
-                    }
-                    return Ok(());
+            gossip_glomers::Event::EOF => {
+                let cas_iterations = self.cas_iterations.lock().await;
+                let mut keys: Vec<_> = cas_iterations.keys().collect();
+                keys.sort();
+                for key in keys {
+                    let histogram = &cas_iterations[key];
+                    let max = histogram.keys().next_back().copied().unwrap_or_default();
+                    log!(
+                        "sends to {} took {:?} cas iterations (max {})",
+                        key,
+                        histogram,
+                        max
+                    );
                 }
-
-                let mut reply = message.into_reply(Some(&self.id));
+            }
+            gossip_glomers::Event::Message(message) => {
+                // Just the header, to answer with an error if the request fails
+                let request = Message {
+                    src: message.src.clone(),
+                    dest: message.dest.clone(),
+                    body: Body {
+                        id: message.body.id,
+                        in_reply_to: None,
+                        payload: (),
+                    },
+                };
+                let mut reply = message.into_reply(Some(self.rpc.ids()));
                 match reply.body.payload {
                     Payload::Send { key, msg } => {
-                        // Find the offset
+                        // Guess the offset after the last one this node saw; a wrong
+                        // guess costs a failed cas, which reports the current offset
                         let latest_key = format!("latest:{}", key);
-                        let mut start = match self
-                            .read(&self.storage_lin, latest_key.clone())
+                        let mut start = self
+                            .next_offsets
+                            .lock()
                             .await
-                            .context("read latest offset")
-                        {
-                            Ok(offset) => offset,
-                            Err(_) => 0,
-                        };
+                            .get(&key)
+                            .copied()
+                            .unwrap_or_default();
 
+                        let mut iterations = 0;
                         loop {
-                            let curr = start.clone();
-                            let (prev, now) = (curr.clone() - 1, curr);
+                            iterations += 1;
+                            let curr = start;
+                            let (prev, now) = (curr - 1, curr);
                             let res = self
-                                .cas(&self.storage_lin, latest_key.clone(), prev, now, true)
-                                .await
-                                .context("cas to find offset");
+                                .cas_or_current(
+                                    &self.storage_lin,
+                                    latest_key.clone(),
+                                    prev,
+                                    now,
+                                    true,
+                                )
+                                .await;
                             match res {
-                                Ok(_) => break,
-                                Err(_) => start += 1,
+                                Ok(_) => {}
+                                // Skip straight past the offset someone else took
+                                Err((_, Some(current))) => {
+                                    start = cmp::max(start, current) + 1;
+                                    continue;
+                                }
+                                Err((KvError::TemporarilyUnavailable, _)) => {
+                                    let text = "reserve offset: lin-kv is unavailable";
+                                    self.send_error(
+                                        request,
+                                        ErrorPayload::TEMPORARILY_UNAVAILABLE,
+                                        text,
+                                    )
+                                    .await?;
+                                    anyhow::bail!(text);
+                                }
+                                Err(_) => {
+                                    start += 1;
+                                    continue;
+                                }
+                            }
+
+                            let msg_key = format!("{}:{}", key, start);
+                            let res = self.create_slot(msg_key, Slot::Msg(msg)).await;
+                            match res {
+                                Ok(()) => break,
+                                // A poll took us for a crashed send and tombstoned the offset
+                                Err(e) if KvError::classify(&e) == KvError::KeyAlreadyExists => {
+                                    start += 1;
+                                }
+                                Err(e) => {
+                                    // Unless the store was never asked, the write
+                                    // may have landed before it failed
+                                    let code = match KvError::classify(&e) {
+                                        KvError::TemporarilyUnavailable => {
+                                            ErrorPayload::TEMPORARILY_UNAVAILABLE
+                                        }
+                                        _ => ErrorPayload::CRASH,
+                                    };
+                                    self.send_error(
+                                        request,
+                                        code,
+                                        format!("write message: {:#}", e),
+                                    )
+                                    .await?;
+                                    return Err(e.context("write message"));
+                                }
                             }
                         }
 
-                        let msg_key = format!("{}:{}", key, start);
-                        let _ = self
-                            .write(&self.storage_seq, msg_key.clone(), msg)
+                        *self
+                            .cas_iterations
+                            .lock()
                             .await
-                            .context("write message");
-
+                            .entry(key.clone())
+                            .or_default()
+                            .entry(iterations)
+                            .or_default() += 1;
+                        self.poll_cache
+                            .lock()
+                            .await
+                            .retain(|(cached, _), _| *cached != key);
+                        self.next_offsets.lock().await.insert(key, start + 1);
+                        self.persist();
                         let _ = self
                             .write(&self.storage_seq, latest_key, start)
                             .await
@@ -223,36 +1012,138 @@ impl Node<Payload, InjectedPayload> for KafkaNode {
                             .await
                             .context("send send ok response")?;
                     }
-                    Payload::Poll { offsets } => {
-                        let mut msgs = HashMap::new();
-                        for (key, offset) in offsets {
-                            let mut msg = Vec::new();
-                            for id in offset..(offset + MSG_SIZE) {
-                                let msg_key = format!("{}:{}", key, id);
-                                let res = self
-                                    .read(&self.storage_seq, msg_key)
-                                    .await
-                                    .context("read message");
-                                match res {
-                                    Ok(value) => msg.push(vec![id, value]),
-                                    Err(_) => continue,
-                                };
+                    Payload::Poll {
+                        offsets,
+                        markers,
+                        group,
+                        max_bytes,
+                    } => {
+                        let keys: Vec<_> = offsets.keys().cloned().collect();
+                        let mut msgs = self.poll_keys(offsets).await?;
+                        if let Some(max_bytes) = max_bytes {
+                            fit_budget(&mut msgs, max_bytes);
+                        }
+                        reply.body.payload = if markers {
+                            let (latest, committed) =
+                                self.markers(keys.into_iter(), group.as_deref()).await;
+                            Payload::PollBatchOk {
+                                msgs,
+                                latest,
+                                committed,
                             }
-                            msgs.insert(key, msg);
+                        } else {
+                            Payload::PollOk { msgs }
+                        };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send poll ok response")?;
+                    }
+                    Payload::PollLatest { keys, count } => {
+                        let tails =
+                            join_all(keys.iter().map(|key| self.read_tail(key, count))).await;
+                        let mut msgs = HashMap::new();
+                        for (key, tail) in keys.into_iter().zip(tails) {
+                            msgs.insert(key, tail?);
                         }
                         reply.body.payload = Payload::PollOk { msgs };
                         reply
                             .send(&self.stdout)
                             .await
-                            .context("send poll ok response")?;
+                            .context("send poll latest ok response")?;
                     }
-                    Payload::CommitOffsets { offsets } => {
-                        for (key, offset) in offsets {
-                            let committed_key = format!("committed:{}", key);
-                            let _ = self
-                                .write(&self.storage_seq, committed_key, offset)
-                                .await
-                                .context("write committed offset");
+                    Payload::PollCommit { offsets, group } => {
+                        let msgs = self.poll_keys(offsets).await?;
+                        // Commit only what is about to be returned, and before
+                        // replying, so the commit never runs ahead of the reply
+                        // the consumer gets. A lost reply still leaves the
+                        // commit behind: that is the cost of auto-committing.
+                        for (key, key_msgs) in &msgs {
+                            let Some(last) = key_msgs.last() else {
+                                continue;
+                            };
+                            let committed_key = committed_key(group.as_deref(), key);
+                            if let Err(e) = self.advance_committed(committed_key, last[0]).await {
+                                log!("auto-commit of {} failed: {:#}", key, e);
+                            }
+                        }
+                        reply.body.payload = Payload::PollCommitOk { msgs };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send poll commit ok response")?;
+                    }
+                    Payload::Seek { key, offset } => {
+                        let res = self
+                            .read(&self.storage_lin, format!("latest:{}", key))
+                            .await;
+                        let latest = match res {
+                            Ok(latest) => Some(latest),
+                            Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => None,
+                            Err(e) => {
+                                self.send_error(
+                                    request,
+                                    ErrorPayload::TEMPORARILY_UNAVAILABLE,
+                                    format!("read latest offset: {:#}", e),
+                                )
+                                .await?;
+                                return Err(e.context("read latest offset"));
+                            }
+                        };
+                        if !latest.is_some_and(|latest| (0..=latest).contains(&offset)) {
+                            let range = match latest {
+                                Some(latest) => format!("offsets 0 to {}", latest),
+                                None => "no offsets yet".to_string(),
+                            };
+                            let text =
+                                format!("can't seek {} to {}, it has {}", key, offset, range);
+                            return self
+                                .send_error(request, ErrorPayload::MALFORMED_REQUEST, text)
+                                .await;
+                        }
+                        reply.body.payload = Payload::SeekOk { offset };
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send seek ok response")?;
+                    }
+                    Payload::Subscribe { group, keys } => {
+                        if let Err(e) = self.subscribe(&group, keys).await {
+                            // Unless the store was never asked, the subscription
+                            // may have landed before it failed
+                            let code = match KvError::classify(&e) {
+                                KvError::TemporarilyUnavailable => {
+                                    ErrorPayload::TEMPORARILY_UNAVAILABLE
+                                }
+                                _ => ErrorPayload::CRASH,
+                            };
+                            self.send_error(request, code, format!("{:#}", e)).await?;
+                            return Err(e);
+                        }
+                        reply.body.payload = Payload::SubscribeOk;
+                        reply
+                            .send(&self.stdout)
+                            .await
+                            .context("send subscribe ok response")?;
+                    }
+                    Payload::CommitOffsets { offsets, group } => {
+                        // One round trip for all keys rather than one per key
+                        let results = join_all(offsets.into_iter().map(|(key, offset)| {
+                            let committed_key = committed_key(group.as_deref(), &key);
+                            self.advance_committed(committed_key, offset)
+                        }))
+                        .await;
+                        if let Some(e) = results.into_iter().find_map(Result::err) {
+                            // Unless the store was never asked, the commit may
+                            // have landed before it failed
+                            let code = match KvError::classify(&e) {
+                                KvError::TemporarilyUnavailable => {
+                                    ErrorPayload::TEMPORARILY_UNAVAILABLE
+                                }
+                                _ => ErrorPayload::CRASH,
+                            };
+                            self.send_error(request, code, format!("{:#}", e)).await?;
+                            return Err(e);
                         }
                         reply.body.payload = Payload::CommitOffsetsOk;
                         reply
@@ -260,17 +1151,41 @@ impl Node<Payload, InjectedPayload> for KafkaNode {
                             .await
                             .context("send commit offsets ok response")?;
                     }
-                    Payload::ListCommittedOffsets { keys } => {
+                    Payload::ListCommittedOffsets { mut keys, group } => {
+                        // A group listing no keys asks for everything it subscribed to
+                        if let (true, Some(group)) = (keys.is_empty(), &group) {
+                            match self.subscriptions(group).await {
+                                Ok(subscribed) => keys = subscribed.into_iter().flatten().collect(),
+                                Err(e) => {
+                                    self.send_error(
+                                        request,
+                                        ErrorPayload::TEMPORARILY_UNAVAILABLE,
+                                        format!("{:#}", e),
+                                    )
+                                    .await?;
+                                    return Err(e);
+                                }
+                            }
+                        }
                         let mut offsets = HashMap::new();
                         for key in keys {
-                            let committed_key = format!("committed:{}", key);
-                            let res = self
-                                .read(&self.storage_seq, committed_key)
-                                .await
-                                .context("read committed offset");
+                            let committed_key = committed_key(group.as_deref(), &key);
+                            let res = self.read(&self.storage_seq, committed_key).await;
+                            // A key that was never committed starts at 0, but
+                            // any other failure must not pass for a commit at 0:
+                            // the consumer would reprocess the key from the start
                             let offset = match res {
                                 Ok(offset) => offset,
-                                Err(_) => 0,
+                                Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => 0,
+                                Err(e) => {
+                                    self.send_error(
+                                        request,
+                                        ErrorPayload::TEMPORARILY_UNAVAILABLE,
+                                        format!("read committed offset: {:#}", e),
+                                    )
+                                    .await?;
+                                    return Err(e.context("read committed offset"));
+                                }
                             };
                             offsets.insert(key, offset);
                         }
@@ -280,18 +1195,29 @@ impl Node<Payload, InjectedPayload> for KafkaNode {
                             .await
                             .context("send list commit offsets ok response")?;
                     }
-                    Payload::Error { code, text } => {
-                        eprintln!("Error {}: {}", code, text);
+                    Payload::Error { code, text, .. } => {
+                        log!("Error {}: {}", code, text);
                     }
+                    // KV requests are for the KV services, not for us
+                    Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {
+                        self.send_error(
+                            request,
+                            ErrorPayload::NOT_SUPPORTED,
+                            "kafka nodes don't serve KV requests",
+                        )
+                        .await?;
+                    }
+                    // Replies go to `handle_reply`
                     Payload::ListCommittedOffsetsOk { .. }
+                    | Payload::SubscribeOk
+                    | Payload::SeekOk { .. }
                     | Payload::CommitOffsetsOk
                     | Payload::PollOk { .. }
+                    | Payload::PollBatchOk { .. }
+                    | Payload::PollCommitOk { .. }
                     | Payload::SendOk { .. }
-                    | Payload::Read { .. }
                     | Payload::ReadOk { .. }
-                    | Payload::Write { .. }
                     | Payload::WriteOk {}
-                    | Payload::Cas { .. }
                     | Payload::CasOk {} => {}
                 }
             }
@@ -299,9 +1225,14 @@ impl Node<Payload, InjectedPayload> for KafkaNode {
         }
         Ok(())
     }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        self.rpc.resolve_reply(reply)
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<KafkaNode, _, _>().await
+    event_loop::<KafkaNode, _, _>().await?.exit_if_signalled();
+    Ok(())
 }
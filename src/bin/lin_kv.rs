@@ -0,0 +1,133 @@
+//! A reference `lin-kv` store, for running nodes against a KV service outside
+//! of Maelstrom. It keeps everything in one process behind one lock, which
+//! makes it trivially linearizable.
+//!
+//! It speaks Maelstrom's KV protocol, and additionally reports the key's
+//! current value in the error to a `cas` that fails its precondition, as
+//! `{"type": "error", "code": 22, "text": ..., "current": <value>}`. Clients
+//! can then retry without reading the key first; see `KV::cas_or_current`.
+
+use std::{collections::HashMap, sync::atomic::AtomicUsize};
+
+use anyhow::{Context, Ok};
+use async_trait::async_trait;
+use gossip_glomers::{event_loop, Event, Init, KvError, Node, Output};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        current: Option<Value>,
+    },
+}
+
+impl Payload {
+    fn error(err: KvError, current: Option<Value>) -> Self {
+        Self::Error {
+            code: err.code(),
+            text: err.to_string(),
+            current,
+        }
+    }
+}
+
+struct LinKvNode {
+    id: AtomicUsize,
+    stdout: Output,
+    /// Values by their key's JSON text, since JSON values can't be hashed.
+    values: Mutex<HashMap<String, Value>>,
+}
+
+#[async_trait]
+impl Node<Payload> for LinKvNode {
+    const NAME: &'static str = "lin-kv";
+
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            id: 1.into(),
+            stdout,
+            values: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(&self.id));
+        let mut values = self.values.lock().await;
+        reply.body.payload = match reply.body.payload {
+            Payload::Read { key } => match values.get(&key.to_string()) {
+                Some(value) => Payload::ReadOk {
+                    value: value.clone(),
+                },
+                None => Payload::error(KvError::KeyDoesNotExist, None),
+            },
+            Payload::Write { key, value } => {
+                values.insert(key.to_string(), value);
+                Payload::WriteOk
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match values.get_mut(&key.to_string()) {
+                Some(current) if *current == from => {
+                    *current = to;
+                    Payload::CasOk
+                }
+                Some(current) => Payload::error(KvError::PreconditionFailed, Some(current.clone())),
+                None if create_if_not_exists => {
+                    values.insert(key.to_string(), to);
+                    Payload::CasOk
+                }
+                None => Payload::error(KvError::KeyDoesNotExist, None),
+            },
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk | Payload::Error { .. } => {
+                return Ok(());
+            }
+        };
+        drop(values);
+        reply.send(&self.stdout).await.context("send reply")
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<LinKvNode, _, _>().await?.exit_if_signalled();
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! Totally ordered broadcast: every message is appended to one log in
+//! `lin-kv`, so every node reads the messages in the same order.
+//!
+//! An append is a cas loop on the whole log, which makes each append as
+//! expensive as the log is long; fine for a workload that reads it all anyway.
+//! Each entry carries the node that appended it and an id of that node's, so
+//! a retried append can tell its own earlier attempt from an equal message
+//! broadcast by someone else.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop, rpc::PendingRpc, Body, Event, Init, KvError, Message, Node, Output, KV,
+};
+use serde::{Deserialize, Serialize};
+
+/// Where the log lives.
+const STORAGE: &str = "lin-kv";
+const LOG_KEY: &str = "tob-log";
+/// How long a KV request waits for its answer.
+const KV_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Broadcast {
+        message: i64,
+    },
+    BroadcastOk,
+    /// A client's read of the log has no key, our read of the KV store does.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    /// Answers both: clients get the `messages`, the store sends the entries
+    /// as `value`.
+    ReadOk {
+        #[serde(default)]
+        messages: Vec<i64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        value: Vec<Entry>,
+    },
+    Write {
+        key: String,
+        value: Vec<Entry>,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: Vec<Entry>,
+        to: Vec<Entry>,
+        #[serde(rename = "create_if_not_exists")]
+        put: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+        #[serde(default)]
+        current: Option<Vec<Entry>>,
+    },
+}
+
+/// A message in the log, with the node that appended it and that node's id
+/// for the append.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    node: String,
+    id: usize,
+    message: i64,
+}
+
+struct TobNode {
+    node: String,
+    stdout: Output,
+    /// KV requests waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+}
+
+impl TobNode {
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        let (id, rx) = self.rpc.register();
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        msg.send(&self.stdout).await.context("send rpc message")?;
+        let res = tokio::time::timeout(KV_TIMEOUT, rx).await;
+        self.rpc.cancel(id);
+        match res {
+            Result::Ok(reply) => reply.context("receive rpc response"),
+            Err(_) => Err(KvError::Timeout.into()),
+        }
+    }
+
+    /// Appends `message` to the log. An entry already in it isn't appended
+    /// again, so retrying an append whose cas landed without an answer
+    /// doesn't duplicate it; the same message broadcast again is a new entry.
+    async fn append(&self, message: i64) -> anyhow::Result<()> {
+        let entry = Entry {
+            node: self.node.clone(),
+            id: self.rpc.next_id(),
+            message,
+        };
+        let mut current = Vec::new();
+        loop {
+            if current
+                .iter()
+                .any(|old: &Entry| old.node == entry.node && old.id == entry.id)
+            {
+                return Ok(());
+            }
+            let mut next = current.clone();
+            next.push(entry.clone());
+            match self
+                .cas_or_current(STORAGE, LOG_KEY.to_string(), current, next, true)
+                .await
+            {
+                Result::Ok(()) => return Ok(()),
+                Err((KvError::PreconditionFailed, Some(log))) => current = log,
+                Err((KvError::PreconditionFailed | KvError::Timeout, _)) => {
+                    current = self.log().await?;
+                }
+                Err((err, _)) => return Err(err).context("append to the log"),
+            }
+        }
+    }
+
+    /// Reads the log, which is empty until the first append creates it.
+    async fn log(&self) -> anyhow::Result<Vec<Entry>> {
+        match self.read(STORAGE, LOG_KEY.to_string()).await {
+            Err(e) if KvError::classify(&e) == KvError::KeyDoesNotExist => Ok(Vec::new()),
+            res => res.context("read the log"),
+        }
+    }
+}
+
+#[async_trait]
+impl KV<Vec<Entry>> for TobNode {
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<Vec<Entry>> {
+        let payload = Payload::Read { key: Some(key) };
+        let result = self.rpc(storage, payload).await?;
+        match result.body.payload {
+            Payload::ReadOk { value, .. } => Ok(value),
+            Payload::Error { code, text, .. } => Err(KvError::from_code(code, text).into()),
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn write(&self, storage: &str, key: String, value: Vec<Entry>) -> anyhow::Result<()> {
+        let payload = Payload::Write { key, value };
+        let result = self.rpc(storage, payload).await?;
+        match result.body.payload {
+            Payload::WriteOk => Ok(()),
+            Payload::Error { code, text, .. } => Err(KvError::from_code(code, text).into()),
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: Vec<Entry>,
+        to: Vec<Entry>,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        let payload = Payload::Cas { key, from, to, put };
+        let result = self.rpc(storage, payload).await?;
+        match result.body.payload {
+            Payload::CasOk => Ok(()),
+            Payload::Error { code, text, .. } => Err(KvError::from_code(code, text).into()),
+            _ => anyhow::bail!("unexpected payload"),
+        }
+    }
+
+    async fn cas_or_current(
+        &self,
+        storage: &str,
+        key: String,
+        from: Vec<Entry>,
+        to: Vec<Entry>,
+        put: bool,
+    ) -> Result<(), (KvError, Option<Vec<Entry>>)> {
+        let payload = Payload::Cas { key, from, to, put };
+        let result = match self.rpc(storage, payload).await {
+            Result::Ok(result) => result,
+            Err(e) => return Err((KvError::classify(&e), None)),
+        };
+        // Stores that don't report the current log leave it to the caller
+        // to read it
+        match result.body.payload {
+            Payload::CasOk => Result::Ok(()),
+            Payload::Error {
+                code,
+                text,
+                current,
+            } => Err((KvError::from_code(code, text), current)),
+            _ => Err((
+                KvError::classify(&anyhow::anyhow!("unexpected payload")),
+                None,
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for TobNode {
+    const NAME: &'static str = "tob";
+
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            node: init.node_id,
+            rpc: PendingRpc::new(&stdout),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        let mut reply = message.into_reply(Some(self.rpc.ids()));
+        reply.body.payload = match reply.body.payload {
+            Payload::Broadcast { message } => {
+                self.append(message).await?;
+                Payload::BroadcastOk
+            }
+            Payload::Read { key: None } => Payload::ReadOk {
+                messages: self.log().await?.into_iter().map(|e| e.message).collect(),
+                value: Vec::new(),
+            },
+            // Not a KV store, and replies go to `handle_reply`
+            _ => return Ok(()),
+        };
+        reply.send(&self.stdout).await.context("send reply")
+    }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        self.rpc.resolve_reply(reply)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    event_loop::<TobNode, _, _>().await?.exit_if_signalled();
+    Ok(())
+}
@@ -1,86 +1,194 @@
-use std::{collections::HashMap, sync::atomic::AtomicUsize};
+use std::time::Duration;
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::rpc::{self, PendingRpc};
+use gossip_glomers::txn::{Operation, TxnStore};
+use gossip_glomers::{event_loop, Body, ErrorPayload, Event, Init, Message, Node, Output};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
-type Operation = (String, u32, Option<u32>);
+/// How long a transaction waits for a peer's versions of its keys before
+/// committing without them.
+const PULL_TIMEOUT: Duration = Duration::from_millis(100);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Txn { txn: Vec<Operation> },
-    TxnOk { txn: Vec<Operation> },
+    Txn {
+        txn: Vec<Operation>,
+    },
+    TxnOk {
+        txn: Vec<Operation>,
+    },
+    /// Asks a peer for its `(key, value, version)` of each of `keys`.
+    Versions {
+        keys: Vec<u32>,
+    },
+    VersionsOk {
+        entries: Vec<(u32, u32, u64)>,
+    },
+    /// Writes a peer committed, sent to every other node once committed.
+    Replicate {
+        entries: Vec<(u32, u32, u64)>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum InjectedPayload {}
-
 struct TxnNode {
-    id: AtomicUsize,
-    stdout: Mutex<tokio::io::Stdout>,
-    storage: Mutex<HashMap<u32, u32>>,
+    node: String,
+    peers: Vec<String>,
+    stdout: Output,
+    /// RPCs waiting on replies; also numbers every message we send.
+    rpc: PendingRpc<Payload>,
+    storage: Mutex<TxnStore>,
+}
+
+impl TxnNode {
+    async fn rpc(&self, to: &str, payload: Payload) -> anyhow::Result<Message<Payload>> {
+        let (id, rx) = self.rpc.register();
+        let msg = Message {
+            src: self.node.clone(),
+            dest: to.to_string(),
+            body: Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+        msg.send(&self.stdout).await.context("send rpc message")?;
+        let res = tokio::time::timeout(PULL_TIMEOUT, rx).await;
+        self.rpc.cancel(id);
+        res.context("rpc timed out")?
+            .context("receive rpc response")
+    }
+
+    /// Merges in the newest version of each of `keys` any peer has. A write
+    /// a peer committed since a transaction read or wrote the key makes the
+    /// transaction conflict. Peers that don't answer in time are skipped.
+    async fn pull(&self, keys: Vec<u32>) {
+        if keys.is_empty() {
+            return;
+        }
+        let entries = rpc::query_peers(
+            &self.peers,
+            Payload::Versions { keys },
+            |peer, versions| self.rpc(peer, versions),
+            |replies| {
+                replies
+                    .into_iter()
+                    .filter_map(|reply| match reply.body.payload {
+                        Payload::VersionsOk { entries } => Some(entries),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>()
+            },
+        )
+        .await;
+        let mut storage = self.storage.lock().await;
+        for (key, value, version) in entries {
+            storage.merge(key, value, version);
+        }
+    }
 }
 
 #[async_trait]
-impl Node<Payload, InjectedPayload> for TxnNode {
+impl Node<Payload> for TxnNode {
+    const NAME: &'static str = "txn";
+
     fn from_init(
-        _init: Init,
-        _tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
         Ok(Self {
-            id: 1.into(),
+            peers: init.peers(),
+            node: init.node_id,
+            rpc: PendingRpc::new(&stdout),
             stdout,
-            storage: Mutex::new(HashMap::new()),
+            storage: Mutex::new(TxnStore::default()),
         })
     }
 
-    async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
         match event {
             Event::EOF => {}
-            Event::Message(payload) => {
-                let mut reply = payload.into_reply(Some(&self.id));
-                match reply.body.payload {
-                    Payload::Txn { txn } => {
+            Event::Message(payload) => match payload.body.payload.clone() {
+                Payload::Txn { txn } => {
+                    // Pulling peers' versions is done with the store unlocked,
+                    // so a transaction committed or merged in meanwhile can
+                    // make this one conflict
+                    let prepared = self.storage.lock().await.prepare(txn);
+                    self.pull(prepared.keys().collect()).await;
+                    let (committed, written) = {
                         let mut storage = self.storage.lock().await;
-                        let mut txn_ok = vec![];
-                        for op in txn {
-                            match op.0.as_str() {
-                                "r" => {
-                                    let val = storage.get(&op.1);
-                                    txn_ok.push((op.0, op.1, val.cloned()));
-                                }
-                                "w" => {
-                                    if let Some(val) = op.2 {
-                                        storage.insert(op.1, val);
-                                    }
-                                    txn_ok.push(op.clone());
+                        let written: Vec<u32> = prepared.written().collect();
+                        let committed = storage.commit(prepared);
+                        (committed, storage.entries(written))
+                    };
+                    match committed {
+                        Result::Ok(txn) => {
+                            if !written.is_empty() {
+                                for peer in &self.peers {
+                                    let entries = written.clone();
+                                    let replicate = Payload::Replicate { entries };
+                                    rpc::send_oneway(&self.stdout, &self.node, peer, replicate)
+                                        .await
+                                        .context("send replicate message")?;
                                 }
-                                _ => {}
                             }
+                            payload
+                                .into_reply_with(Some(self.rpc.ids()), Payload::TxnOk { txn })
+                                .send(&self.stdout)
+                                .await
+                                .context("send reply")?;
+                        }
+                        Err(conflict) => {
+                            payload
+                                .into_error_reply(
+                                    Some(self.rpc.ids()),
+                                    ErrorPayload::TXN_CONFLICT,
+                                    conflict.to_string(),
+                                )
+                                .send(&self.stdout)
+                                .await
+                                .context("send abort")?;
                         }
-                        reply.body.payload = Payload::TxnOk { txn: txn_ok };
-                        reply.send(&self.stdout).await.context("send reply")?;
                     }
-                    Payload::TxnOk { .. } => {}
                 }
-            }
+                Payload::Versions { keys } => {
+                    let entries = self.storage.lock().await.entries(keys);
+                    payload
+                        .into_reply_with(Some(self.rpc.ids()), Payload::VersionsOk { entries })
+                        .send(&self.stdout)
+                        .await
+                        .context("send versions")?;
+                }
+                Payload::Replicate { entries } => {
+                    let mut storage = self.storage.lock().await;
+                    for (key, value, version) in entries {
+                        storage.merge(key, value, version);
+                    }
+                }
+                // Replies go to `handle_reply`
+                Payload::TxnOk { .. } | Payload::VersionsOk { .. } => {}
+            },
             Event::Injected(..) => {}
         }
         Ok(())
     }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        self.rpc.resolve_reply(reply)
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<TxnNode, _, _>().await
+    event_loop::<TxnNode, _, _>().await?.exit_if_signalled();
+    Ok(())
 }
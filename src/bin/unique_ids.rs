@@ -1,10 +1,7 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
-use gossip_glomers::{event_loop, Event, Init, Node};
+use gossip_glomers::{event_loop, Event, Init, Output, Serial, SerialNode};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -19,35 +16,40 @@ enum Payload {
 
 struct UniqueIdsNode {
     node: String,
-    id: AtomicUsize,
-    stdout: Mutex<tokio::io::Stdout>,
+    id: usize,
+    stdout: Output,
 }
 
 #[async_trait]
-impl Node<Payload> for UniqueIdsNode {
+impl SerialNode<Payload> for UniqueIdsNode {
+    const NAME: &'static str = "unique-ids";
+
     fn from_init(
         init: Init,
         _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
         Ok(Self {
             node: init.node_id,
-            id: 1.into(),
+            id: 1,
             stdout,
         })
     }
 
-    async fn handle(&self, event: gossip_glomers::Event<Payload>) -> anyhow::Result<()> {
+    async fn handle(&mut self, event: gossip_glomers::Event<Payload>) -> anyhow::Result<()> {
         let gossip_glomers::Event::Message(message) = event else {
-            panic!("unexpected event: {:?}", event);
+            // EOF carries nothing to reply to
+            return Ok(());
         };
-        let mut reply = message.into_reply(Some(&self.id));
+        let mut reply = message.into_reply(None);
+        reply.body.id = Some(self.id);
+        self.id += 1;
         match reply.body.payload {
             Payload::Generate => {
-                let guid = format!("{}-{}", self.node, self.id.load(Ordering::SeqCst));
+                let guid = format!("{}-{}", self.node, self.id);
                 reply.body.payload = Payload::GenerateOk { guid };
                 reply
                     .send(&self.stdout)
@@ -62,5 +64,8 @@ impl Node<Payload> for UniqueIdsNode {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    event_loop::<UniqueIdsNode, _, _>().await
+    event_loop::<Serial<UniqueIdsNode>, _, _>()
+        .await?
+        .exit_if_signalled();
+    Ok(())
 }
@@ -0,0 +1,74 @@
+//! A Bloom filter, for telling a peer compactly which values a node has.
+
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+/// A Bloom filter over values of any `Hash` type.
+///
+/// Filters are meant to be sent to other processes, so hashing doesn't use
+/// the std hasher (whose algorithm may change between releases) but FNV-1a,
+/// mixed with the filter's `seed`. A value that is a false positive under one
+/// seed is unlikely to be one under the next, so a sender that varies the
+/// seed can't hide the same value forever.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    seed: u64,
+    hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `capacity` values at a false
+    /// positive rate of about `fp_rate`.
+    pub fn new(capacity: usize, fp_rate: f64, seed: u64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-capacity * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = ((bits / capacity) * ln2).round().clamp(1.0, 16.0);
+        Self {
+            seed,
+            hashes: hashes as u32,
+            bits: vec![0; (bits as usize).div_ceil(64)],
+        }
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, value: &T) {
+        for bit in self.positions(value).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `value` may have been inserted. `false` is always right;
+    /// `true` is wrong at about the rate the filter was sized for.
+    pub fn contains<T: Hash + ?Sized>(&self, value: &T) -> bool {
+        self.positions(value)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bit positions of `value`, by double hashing.
+    fn positions<T: Hash + ?Sized>(&self, value: &T) -> impl Iterator<Item = usize> {
+        let mut hasher = Fnv1a(0xcbf2_9ce4_8422_2325 ^ self.seed);
+        value.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // An odd step visits distinct positions for every hash
+        let h2 = h1.rotate_left(32).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        let len = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+struct Fnv1a(u64);
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,102 @@
+//! Circuit breaking for KV stores that stop answering.
+//!
+//! While a store is partitioned away, every request to it waits out its timeout
+//! and most of them are then retried. A [`CircuitBreaker`] counts consecutive
+//! timeouts per store and, past a threshold, opens: requests fail right away
+//! with [`KvError::TemporarilyUnavailable`] so clients back off instead. After a
+//! cooldown it half-opens and lets a single request through to probe the store;
+//! an answer closes it, another timeout opens it again, for twice as long each
+//! time, up to [`MAX_COOLDOWN_FACTOR`] times the first cooldown.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::retry::Backoff;
+use crate::{KvError, Rng};
+
+/// How many times longer than the first cooldown a circuit that keeps failing
+/// its probes stays open at most.
+pub const MAX_COOLDOWN_FACTOR: u32 = 16;
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Consecutive timeouts that open a store's circuit.
+    threshold: u32,
+    /// How long an open circuit stays open before half-opening.
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    consecutive_timeouts: u32,
+    /// When the circuit opened and for how long.
+    opened: Option<(Instant, Duration)>,
+    /// Whether the probe of a half-open circuit is in flight.
+    probing: bool,
+    cooldown: Backoff,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether a request to `store` may be sent. Call it before every
+    /// request and report the outcome of those it lets through.
+    pub fn check(&self, store: &str) -> Result<(), KvError> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(store) else {
+            return Ok(());
+        };
+        match circuit.opened {
+            None => Ok(()),
+            Some((opened_at, cooldown)) if opened_at.elapsed() < cooldown || circuit.probing => {
+                Err(KvError::TemporarilyUnavailable)
+            }
+            Some(_) => {
+                circuit.probing = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Records that a request to `store` timed out.
+    pub fn record_timeout(&self, store: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(store.to_string())
+            .or_insert_with(|| Circuit {
+                consecutive_timeouts: 0,
+                opened: None,
+                probing: false,
+                // A single probe per store can't stampede, so no jitter
+                cooldown: Backoff::new(
+                    self.cooldown,
+                    self.cooldown * MAX_COOLDOWN_FACTOR,
+                    0.0,
+                    Rng::with_seed(0),
+                ),
+            });
+        circuit.consecutive_timeouts += 1;
+        let probe_failed = std::mem::take(&mut circuit.probing);
+        // Requests sent before the circuit opened that time out now don't
+        // stretch the cooldown; only a failed probe does
+        let reopen = circuit.opened.is_none() || probe_failed;
+        if circuit.consecutive_timeouts >= self.threshold && reopen {
+            circuit.opened = Some((Instant::now(), circuit.cooldown.next_delay()));
+        }
+    }
+
+    /// Records that `store` answered a request, even with an error.
+    pub fn record_answer(&self, store: &str) {
+        self.circuits.lock().unwrap().remove(store);
+    }
+}
@@ -0,0 +1,241 @@
+//! Wire format of messages on stdin/stdout.
+//!
+//! Maelstrom speaks newline-delimited JSON, which is the default. Building with
+//! the `msgpack` feature switches to MessagePack for use outside Maelstrom:
+//! every message is a MessagePack map preceded by its length as a big-endian
+//! `u32`, since binary frames can't be delimited by newlines.
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// How frames look on the wire, for `--help`.
+#[cfg(not(feature = "msgpack"))]
+pub(crate) const FORMAT: &str = "one JSON object per line";
+#[cfg(feature = "msgpack")]
+pub(crate) const FORMAT: &str = "MessagePack maps each prefixed by a u32 length";
+
+/// Serializes `msg` into a complete frame, ready to be written out.
+#[cfg(not(feature = "msgpack"))]
+pub fn encode<T: Serialize>(msg: &T) -> anyhow::Result<Vec<u8>> {
+    let mut frame = serde_json::to_vec(msg).context("serialize message")?;
+    frame.push(b'\n');
+    Ok(frame)
+}
+
+/// Serializes `msg` into a complete frame, ready to be written out.
+#[cfg(feature = "msgpack")]
+pub fn encode<T: Serialize>(msg: &T) -> anyhow::Result<Vec<u8>> {
+    let value = serde_json::to_value(msg).context("serialize message")?;
+    let mut frame = vec![0; 4];
+    msgpack::write(&value, &mut frame);
+    let len = u32::try_from(frame.len() - 4).context("message too large")?;
+    frame[..4].copy_from_slice(&len.to_be_bytes());
+    Ok(frame)
+}
+
+/// Reads the next frame, without its delimiter. Returns `None` at the end of
+/// the input.
+#[cfg(not(feature = "msgpack"))]
+pub(crate) async fn read_frame<R>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut frame = Vec::new();
+    if reader.read_until(b'\n', &mut frame).await? == 0 {
+        return Ok(None);
+    }
+    if frame.last() == Some(&b'\n') {
+        frame.pop();
+    }
+    Ok(Some(frame))
+}
+
+/// Reads the next frame, without its length prefix. Returns `None` at the end
+/// of the input.
+#[cfg(feature = "msgpack")]
+pub(crate) async fn read_frame<R>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    if reader.fill_buf().await?.is_empty() {
+        return Ok(None);
+    }
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut len)
+        .await
+        .context("read frame length")?;
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut frame).await.context("read frame")?;
+    Ok(Some(frame))
+}
+
+/// Deserializes a frame returned by [`read_frame`].
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> anyhow::Result<T> {
+    #[cfg(not(feature = "msgpack"))]
+    let msg = serde_json::from_slice(frame)?;
+    #[cfg(feature = "msgpack")]
+    let msg = serde_json::from_value(msgpack::read(&mut &frame[..])?)?;
+    Ok(msg)
+}
+
+/// Just enough MessagePack to carry anything that fits in a JSON value.
+#[cfg(feature = "msgpack")]
+mod msgpack {
+    use anyhow::Context;
+    use serde_json::{Map, Number, Value};
+
+    pub(super) fn write(value: &Value, out: &mut Vec<u8>) {
+        match value {
+            Value::Null => out.push(0xc0),
+            Value::Bool(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+            Value::Number(n) => write_number(n, out),
+            Value::String(s) => {
+                write_len(s.len(), [0xa0, 0xd9, 0xda, 0xdb], 32, out);
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::Array(items) => {
+                write_len(items.len(), [0x90, 0, 0xdc, 0xdd], 16, out);
+                items.iter().for_each(|item| write(item, out));
+            }
+            Value::Object(map) => {
+                write_len(map.len(), [0x80, 0, 0xde, 0xdf], 16, out);
+                for (key, value) in map {
+                    write(&Value::String(key.clone()), out);
+                    write(value, out);
+                }
+            }
+        }
+    }
+
+    /// Writes a length header: the fix variant `markers[0] | len` below
+    /// `fix_limit`, else the 8 (if the type has one), 16 or 32 bit variant.
+    fn write_len(len: usize, markers: [u8; 4], fix_limit: usize, out: &mut Vec<u8>) {
+        if len < fix_limit {
+            out.push(markers[0] | len as u8);
+        } else if markers[1] != 0 && len <= u8::MAX as usize {
+            out.extend([markers[1], len as u8]);
+        } else if len <= u16::MAX as usize {
+            out.push(markers[2]);
+            out.extend((len as u16).to_be_bytes());
+        } else {
+            out.push(markers[3]);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+
+    fn write_number(n: &Number, out: &mut Vec<u8>) {
+        if let Some(n) = n.as_u64() {
+            if n < 0x80 {
+                out.push(n as u8);
+            } else {
+                out.push(0xcf);
+                out.extend(n.to_be_bytes());
+            }
+        } else if let Some(n) = n.as_i64() {
+            if n >= -32 {
+                out.push(n as i8 as u8);
+            } else {
+                out.push(0xd3);
+                out.extend(n.to_be_bytes());
+            }
+        } else {
+            out.push(0xcb);
+            out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+        }
+    }
+
+    pub(super) fn read(input: &mut &[u8]) -> anyhow::Result<Value> {
+        let marker = take::<1>(input)?[0];
+        let value = match marker {
+            0x00..=0x7f => Value::from(marker),
+            0xe0..=0xff => Value::from(marker as i8),
+            0x80..=0x8f => read_map((marker & 0x0f) as usize, input)?,
+            0x90..=0x9f => read_array((marker & 0x0f) as usize, input)?,
+            0xa0..=0xbf => read_str((marker & 0x1f) as usize, input)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => Value::from(f32::from_be_bytes(take(input)?) as f64),
+            0xcb => Value::from(f64::from_be_bytes(take(input)?)),
+            0xcc => Value::from(take::<1>(input)?[0]),
+            0xcd => Value::from(u16::from_be_bytes(take(input)?)),
+            0xce => Value::from(u32::from_be_bytes(take(input)?)),
+            0xcf => Value::from(u64::from_be_bytes(take(input)?)),
+            0xd0 => Value::from(i8::from_be_bytes(take(input)?)),
+            0xd1 => Value::from(i16::from_be_bytes(take(input)?)),
+            0xd2 => Value::from(i32::from_be_bytes(take(input)?)),
+            0xd3 => Value::from(i64::from_be_bytes(take(input)?)),
+            0xd9 => {
+                let len = take::<1>(input)?[0] as usize;
+                read_str(len, input)?
+            }
+            0xda => {
+                let len = u16::from_be_bytes(take(input)?) as usize;
+                read_str(len, input)?
+            }
+            0xdb => {
+                let len = u32::from_be_bytes(take(input)?) as usize;
+                read_str(len, input)?
+            }
+            0xdc => {
+                let len = u16::from_be_bytes(take(input)?) as usize;
+                read_array(len, input)?
+            }
+            0xdd => {
+                let len = u32::from_be_bytes(take(input)?) as usize;
+                read_array(len, input)?
+            }
+            0xde => {
+                let len = u16::from_be_bytes(take(input)?) as usize;
+                read_map(len, input)?
+            }
+            0xdf => {
+                let len = u32::from_be_bytes(take(input)?) as usize;
+                read_map(len, input)?
+            }
+            _ => anyhow::bail!("unsupported MessagePack type 0x{:02x}", marker),
+        };
+        Ok(value)
+    }
+
+    fn take<const N: usize>(input: &mut &[u8]) -> anyhow::Result<[u8; N]> {
+        let bytes = take_slice(N, input)?;
+        Ok(bytes.try_into().expect("slice has N bytes"))
+    }
+
+    fn take_slice<'a>(len: usize, input: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        anyhow::ensure!(input.len() >= len, "truncated MessagePack frame");
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        Ok(bytes)
+    }
+
+    fn read_str(len: usize, input: &mut &[u8]) -> anyhow::Result<Value> {
+        let bytes = take_slice(len, input)?;
+        let s = std::str::from_utf8(bytes).context("string is not UTF-8")?;
+        Ok(Value::String(s.to_string()))
+    }
+
+    fn read_array(len: usize, input: &mut &[u8]) -> anyhow::Result<Value> {
+        (0..len)
+            .map(|_| read(input))
+            .collect::<anyhow::Result<_>>()
+            .map(Value::Array)
+    }
+
+    fn read_map(len: usize, input: &mut &[u8]) -> anyhow::Result<Value> {
+        let mut map = Map::with_capacity(len);
+        for _ in 0..len {
+            let Value::String(key) = read(input)? else {
+                anyhow::bail!("map key is not a string");
+            };
+            map.insert(key, read(input)?);
+        }
+        Ok(Value::Object(map))
+    }
+}
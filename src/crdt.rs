@@ -0,0 +1,276 @@
+//! State-based CRDTs used by the gossiping nodes.
+//!
+//! The types here only hold state; a node keeps them behind a `Mutex` and
+//! calls into them from `handle`. Every gossip tick it runs a
+//! [`gossip_round`], which takes each live peer's [`Gossip::delta`] and hands
+//! it to the node to send, and it merges what peers sent with
+//! [`Gossip::merge_delta`].
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::hash::Hash;
+
+use tokio::sync::Mutex;
+
+use crate::liveness::Liveness;
+
+/// State that converges by anti-entropy gossip of deltas.
+pub trait Gossip {
+    /// What a gossip message carries.
+    type Delta;
+
+    /// What peer `to` may be missing, for this node `from` to gossip to it,
+    /// or `None` if `to` isn't gossiped to.
+    fn delta(&self, from: &str, to: &str) -> Option<Self::Delta>;
+
+    /// Merges the delta `peer` gossiped. Returns `false` if `peer` isn't part
+    /// of the state, and the delta was ignored.
+    fn merge_delta(&mut self, peer: &str, delta: Self::Delta) -> bool;
+}
+
+/// Runs one gossip round of `state` from node `from`: every one of `peers`
+/// that `liveness` doesn't have down is handed its delta through `send`,
+/// which may still hold it back, e.g. to pace gossip to a quiet peer.
+pub async fn gossip_round<'a, G, Fut>(
+    from: &str,
+    state: &Mutex<G>,
+    peers: &'a [String],
+    liveness: &Mutex<Liveness>,
+    mut send: impl FnMut(&'a str, G::Delta) -> Fut,
+) -> anyhow::Result<()>
+where
+    G: Gossip,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    for peer in peers {
+        if !liveness.lock().await.is_up(peer) {
+            continue;
+        }
+        let Some(delta) = state.lock().await.delta(from, peer) else {
+            continue;
+        };
+        send(peer, delta).await?;
+    }
+    Ok(())
+}
+
+/// A grow-only set replicated by anti-entropy gossip.
+///
+/// Besides its values, the set tracks for every peer which values the peer is
+/// known to have, so a gossip round only needs to send the difference. Values
+/// every peer of the compaction group (all peers, unless narrowed with
+/// [`GrowOnlySet::compact_over`]) is known to have are kept once, in
+/// `shared`, instead of once per peer.
+#[derive(Debug, Clone)]
+pub struct GrowOnlySet<T> {
+    values: HashSet<T>,
+    known: HashMap<String, HashSet<T>>,
+    /// Values known to every peer of `group`, left out of those peers' own
+    /// `known` sets.
+    shared: HashSet<T>,
+    group: HashSet<String>,
+}
+
+impl<T> GrowOnlySet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    pub fn new(peers: impl IntoIterator<Item = String>) -> Self {
+        let known: HashMap<String, HashSet<T>> = peers
+            .into_iter()
+            .map(|peer| (peer, HashSet::new()))
+            .collect();
+        Self {
+            values: HashSet::new(),
+            group: known.keys().cloned().collect(),
+            known,
+            shared: HashSet::new(),
+        }
+    }
+
+    pub fn values(&self) -> &HashSet<T> {
+        &self.values
+    }
+
+    pub fn is_peer(&self, peer: &str) -> bool {
+        self.known.contains_key(peer)
+    }
+
+    /// Adds a value. Returns whether it was new.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.values.insert(value)
+    }
+
+    /// Returns the values `peer` isn't known to have, or `None` if `peer` is
+    /// not a peer of this set.
+    pub fn missing(&self, peer: &str) -> Option<HashSet<T>> {
+        if !self.is_peer(peer) {
+            return None;
+        }
+        Some(
+            self.values
+                .iter()
+                .filter(|value| !self.is_known(peer, value))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Records that `peer` has `values`, e.g. because it acked them. Returns
+    /// `false` if `peer` is not a peer of this set.
+    pub fn mark_known(&mut self, peer: &str, values: impl IntoIterator<Item = T>) -> bool {
+        if !self.known.contains_key(peer) {
+            return false;
+        }
+        let grouped = self.group.contains(peer);
+        for value in values {
+            if grouped && self.shared.contains(&value) {
+                continue;
+            }
+            if let Some(known) = self.known.get_mut(peer) {
+                known.insert(value.clone());
+            }
+            if grouped {
+                self.settle(value);
+            }
+        }
+        true
+    }
+
+    /// Moves `value` to `shared` if every peer of the group has it.
+    fn settle(&mut self, value: T) {
+        let everywhere = self.group.iter().all(|peer| {
+            self.known
+                .get(peer)
+                .is_some_and(|known| known.contains(&value))
+        });
+        if !everywhere {
+            return;
+        }
+        for peer in &self.group {
+            if let Some(known) = self.known.get_mut(peer) {
+                known.remove(&value);
+            }
+        }
+        self.shared.insert(value);
+    }
+
+    /// Compacts the values known to every one of `peers`, e.g. the neighbors
+    /// this node gossips with, instead of to every peer of the set. Names
+    /// that aren't peers of the set are ignored.
+    pub fn compact_over(&mut self, peers: impl IntoIterator<Item = String>) {
+        let shared = std::mem::take(&mut self.shared);
+        for peer in &self.group {
+            if let Some(known) = self.known.get_mut(peer) {
+                known.extend(shared.iter().cloned());
+            }
+        }
+        self.group = peers
+            .into_iter()
+            .filter(|peer| self.known.contains_key(peer))
+            .collect();
+        let Some(first) = self.group.iter().next() else {
+            return;
+        };
+        let candidates: Vec<T> = self.known[first].iter().cloned().collect();
+        for value in candidates {
+            self.settle(value);
+        }
+    }
+
+    /// Whether `peer` is known to have `value`.
+    pub fn is_known(&self, peer: &str, value: &T) -> bool {
+        let Some(known) = self.known.get(peer) else {
+            return false;
+        };
+        known.contains(value) || (self.group.contains(peer) && self.shared.contains(value))
+    }
+
+    /// How many values are tracked for `peer` on its own, i.e. known to it
+    /// but not yet to every peer of the compaction group.
+    pub fn tracked(&self, peer: &str) -> usize {
+        self.known.get(peer).map_or(0, HashSet::len)
+    }
+
+    /// Merges values gossiped by `peer`, who by sending them evidently has
+    /// them. Gossip from a node that is not a peer is ignored and `false` is
+    /// returned.
+    pub fn merge(&mut self, peer: &str, seen: HashSet<T>) -> bool {
+        if !self.known.contains_key(peer) {
+            return false;
+        }
+        self.values.extend(seen.iter().cloned());
+        self.mark_known(peer, seen)
+    }
+}
+
+impl<T> Gossip for GrowOnlySet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    type Delta = HashSet<T>;
+
+    fn delta(&self, _from: &str, to: &str) -> Option<HashSet<T>> {
+        self.missing(to)
+    }
+
+    fn merge_delta(&mut self, peer: &str, seen: HashSet<T>) -> bool {
+        self.merge(peer, seen)
+    }
+}
+
+/// A grow-only counter: one monotonic sub-counter per node, merged by taking
+/// the maximum, with the counter's value being their sum.
+#[derive(Debug, Clone)]
+pub struct GrowOnlyCounter {
+    counters: HashMap<String, u64>,
+}
+
+impl GrowOnlyCounter {
+    pub fn new(nodes: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            counters: nodes.into_iter().map(|node| (node, 0)).collect(),
+        }
+    }
+
+    /// Returns the sub-counter of `node`, or 0 for an unknown node.
+    pub fn get(&self, node: &str) -> u64 {
+        self.counters.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counters.values().sum()
+    }
+
+    /// Adds `delta` to the sub-counter of `node`, which must be the local node.
+    pub fn increment(&mut self, node: &str, delta: u64) {
+        if let Some(current) = self.counters.get_mut(node) {
+            *current += delta;
+        }
+    }
+
+    /// Merges a sub-counter value reported by `node`. Returns `false` if
+    /// `node` is not part of the counter.
+    pub fn merge(&mut self, node: &str, value: u64) -> bool {
+        match self.counters.get_mut(node) {
+            Some(current) => {
+                *current = (*current).max(value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Gossips the sending node's own sub-counter, the only one it increments.
+impl Gossip for GrowOnlyCounter {
+    type Delta = u64;
+
+    fn delta(&self, from: &str, to: &str) -> Option<u64> {
+        self.counters.contains_key(to).then(|| self.get(from))
+    }
+
+    fn merge_delta(&mut self, peer: &str, value: u64) -> bool {
+        self.merge(peer, value)
+    }
+}
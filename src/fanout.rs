@@ -0,0 +1,55 @@
+//! Choice of gossip partners.
+//!
+//! Gossiping to every neighbor on every tick costs a message per neighbor per
+//! tick. A [`Fanout`] picks only a few neighbors each tick instead, epidemic
+//! style. It goes through the neighbors in a shuffled order, so each of them
+//! is picked at least once every `ceil(n / size)` ticks, and reshuffles on
+//! every pass. The shuffles draw from the node's seeded [`Rng`], so a run can
+//! be reproduced.
+
+use crate::Rng;
+
+#[derive(Debug)]
+pub struct Fanout {
+    /// Neighbors picked per tick; `None` picks about `sqrt(n)` of `n`.
+    size: Option<usize>,
+    rng: Rng,
+    /// The current pass over the neighbors, and how far into it we are.
+    order: Vec<String>,
+    next: usize,
+}
+
+impl Fanout {
+    pub fn new(size: Option<usize>, rng: Rng) -> Self {
+        Self {
+            size,
+            rng,
+            order: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns the neighbors to gossip to this tick. A change of `neighbors`
+    /// starts a new pass.
+    pub fn pick(&mut self, neighbors: &[String]) -> Vec<String> {
+        let n = neighbors.len();
+        if n == 0 {
+            return Vec::new();
+        }
+        let changed =
+            self.order.len() != n || !neighbors.iter().all(|node| self.order.contains(node));
+        if changed || self.next >= n {
+            self.order = neighbors.to_vec();
+            self.rng.shuffle(&mut self.order);
+            self.next = 0;
+        }
+        let size = self
+            .size
+            .unwrap_or_else(|| (n as f64).sqrt().ceil() as usize)
+            .clamp(1, n);
+        let end = (self.next + size).min(n);
+        let picked = self.order[self.next..end].to_vec();
+        self.next = end;
+        picked
+    }
+}
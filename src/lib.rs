@@ -1,15 +1,34 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::Poll;
+use std::time::Duration;
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use anyhow::{Context, Ok};
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncBufReadExt;
-use tokio::sync::Mutex;
-use tokio::task::JoinSet;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+pub mod bloom;
+pub mod breaker;
+pub mod codec;
+pub mod crdt;
+pub mod fanout;
+pub mod liveness;
+pub mod metrics;
+pub mod persist;
+pub mod retry;
+pub mod rpc;
+pub mod txn;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<Payload> {
@@ -20,8 +39,10 @@ pub struct Message<Payload> {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Body<Payload> {
-    #[serde(rename = "msg_id")]
+    // Maelstrom tells an absent field from a `null` one, so unset ids are left out
+    #[serde(rename = "msg_id", default, skip_serializing_if = "Option::is_none")]
     pub id: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub in_reply_to: Option<usize>,
     #[serde(flatten)]
     pub payload: Payload,
@@ -33,26 +54,341 @@ impl<Payload> Message<Payload> {
             src: self.dest,
             dest: self.src,
             body: Body {
-                id: id.map(|id| id.fetch_add(1, Ordering::SeqCst)),
+                // Ids only need to be unique, see `PendingRpc::next_id`
+                id: id.map(|id| id.fetch_add(1, Ordering::Relaxed)),
                 in_reply_to: self.body.id,
                 payload: self.body.payload,
             },
         }
     }
 
-    pub async fn send(&self, out: &Mutex<tokio::io::Stdout>) -> anyhow::Result<()>
+    /// Like [`Message::into_reply`], but replaces the payload of the reply.
+    pub fn into_reply_with(self, id: Option<&AtomicUsize>, payload: Payload) -> Self {
+        let mut reply = self.into_reply(id);
+        reply.body.payload = payload;
+        reply
+    }
+
+    /// Builds a message passing this one on from `from`, this node, to `to`.
+    /// The forward has no message id yet. Returns `None` if `to` is where this
+    /// message came from, so that forwarding to every neighbor doesn't echo a
+    /// message back to its sender.
+    pub fn forward<P>(&self, from: &str, to: &str, payload: P) -> Option<Message<P>> {
+        if to == self.src {
+            return None;
+        }
+        Some(Message {
+            src: from.to_string(),
+            dest: to.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload,
+            },
+        })
+    }
+
+    /// Builds a Maelstrom error reply to this message, for a request the node
+    /// can't fulfil, so the client sees a failure rather than a timeout.
+    pub fn into_error_reply(
+        self,
+        id: Option<&AtomicUsize>,
+        code: usize,
+        text: impl Into<String>,
+    ) -> Message<ErrorPayload> {
+        Message {
+            src: self.dest,
+            dest: self.src,
+            body: Body {
+                id: id.map(|id| id.fetch_add(1, Ordering::Relaxed)),
+                in_reply_to: self.body.id,
+                payload: ErrorPayload {
+                    code,
+                    text: text.into(),
+                },
+            },
+        }
+    }
+
+    pub async fn send(&self, out: &Output) -> anyhow::Result<()>
     where
         Payload: Serialize,
     {
-        let raw_msg = serde_json::to_string(self).context("deserialize message")?;
-        let mut out = out.lock().await;
-        out.write_all(raw_msg.as_bytes())
-            .await
-            .context("serialize response message")?;
-        out.write_all(b"\n")
+        let frame = codec::encode(self)?;
+        let limit = out.run.max_message_bytes();
+        if frame.len() > limit {
+            anyhow::bail!(
+                "message to {} is {} bytes, over the {} byte limit",
+                self.dest,
+                frame.len(),
+                limit
+            );
+        }
+        #[cfg(debug_assertions)]
+        if let Some(id) = self.body.id {
+            assert!(
+                out.first_use(id),
+                "message id {} sent twice, the second time to {}; a node must draw \
+                 the ids of all its messages from one counter, e.g. `PendingRpc::ids`",
+                id,
+                self.dest
+            );
+        }
+        out.write(frame, self.body.in_reply_to.is_some()).await
+    }
+
+    /// Builds one message per chunk of `items`, halving chunks until each
+    /// message fits in the [`Config::max_message_bytes`] of `out`. Meant for
+    /// payloads that can be spread over several messages, such as gossip;
+    /// `build` may be called more than once for the same items.
+    pub fn split_to_fit<T>(
+        out: &Output,
+        items: &[T],
+        build: &impl Fn(&[T]) -> Self,
+    ) -> anyhow::Result<Vec<Self>>
+    where
+        Payload: Serialize,
+    {
+        let msg = build(items);
+        let len = codec::encode(&msg)?.len();
+        let limit = out.run.max_message_bytes();
+        if len <= limit {
+            return Ok(vec![msg]);
+        }
+        if items.len() <= 1 {
+            anyhow::bail!("a single item doesn't fit in the {} byte limit", limit);
+        }
+        let (left, right) = items.split_at(items.len() / 2);
+        let mut msgs = Self::split_to_fit(out, left, build)?;
+        msgs.extend(Self::split_to_fit(out, right, build)?);
+        Ok(msgs)
+    }
+}
+
+/// Payload of a Maelstrom error message. See [`Message::into_error_reply`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename = "error")]
+pub struct ErrorPayload {
+    pub code: usize,
+    pub text: String,
+}
+
+impl ErrorPayload {
+    /// The request isn't supported by this node.
+    pub const NOT_SUPPORTED: usize = 10;
+    /// The request definitely didn't take effect and may be retried.
+    pub const TEMPORARILY_UNAVAILABLE: usize = 11;
+    /// The request is invalid, e.g. names something that doesn't exist.
+    pub const MALFORMED_REQUEST: usize = 12;
+    /// The request may or may not have taken effect.
+    pub const CRASH: usize = 13;
+    /// The transaction was aborted because it conflicted with another one. It
+    /// took no effect and may be retried.
+    pub const TXN_CONFLICT: usize = 30;
+}
+
+/// A clonable handle to the task that writes messages to stdout. All clones
+/// write through the same task, so messages sent concurrently from handlers and
+/// background tasks never interleave within a frame.
+///
+/// Replies jump the queue: of the messages waiting to be written, replies go
+/// out before the rest (mostly gossip), so background traffic doesn't add to
+/// the latency clients see. Every batch is written in full, so the rest is
+/// never held back by more than one batch of replies.
+#[derive(Debug, Clone)]
+pub struct Output {
+    tx: UnboundedSender<Queued>,
+    /// Notified when a write finds the reading end gone (`BrokenPipe`), i.e.
+    /// Maelstrom has stopped, so the event loop can shut down.
+    closed: Arc<tokio::sync::Notify>,
+    /// Every message id sent, to check that the node draws all of them,
+    /// replies and RPCs alike, from one counter.
+    #[cfg(debug_assertions)]
+    sent_ids: Arc<std::sync::Mutex<SentIds>>,
+    run: Arc<RunState>,
+}
+
+/// What one run of the event loop keeps for everything holding its
+/// [`Output`]. Several nodes may run in one process, see [`event_loop_with`],
+/// so none of it is process-wide.
+#[derive(Debug)]
+pub(crate) struct RunState {
+    /// Set from [`Config::max_message_bytes`] when the event loop starts.
+    max_message_bytes: AtomicUsize,
+    /// How many events the event loop has taken in, for
+    /// [`rpc::PendingRpc`] to tell a stalled event loop from a slow peer.
+    events_taken: AtomicUsize,
+    /// The limiter [`retry::pace`] draws from, set from
+    /// [`Config::retry_rate`] when the event loop starts.
+    pub(crate) retries: OnceLock<retry::RateLimiter>,
+}
+
+impl Default for RunState {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: AtomicUsize::new(usize::MAX),
+            events_taken: AtomicUsize::new(0),
+            retries: OnceLock::new(),
+        }
+    }
+}
+
+impl RunState {
+    fn max_message_bytes(&self) -> usize {
+        self.max_message_bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn events_taken(&self) -> usize {
+        self.events_taken.load(Ordering::Relaxed)
+    }
+}
+
+/// The message ids sent so far. Ids come from one increasing counter, so
+/// most of them form an unbroken run from 0, which is kept as its end; only
+/// the ids sent past a gap in it are kept one by one.
+#[cfg(debug_assertions)]
+#[derive(Debug, Default)]
+struct SentIds {
+    /// Every id below this was sent.
+    below: usize,
+    above: HashSet<usize>,
+}
+
+/// How many times in a row a write to stdout is retried after a transient
+/// error before the error is reported.
+const WRITE_RETRIES: u32 = 5;
+
+/// Whether a failed write to stdout may succeed if simply tried again.
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Writes all of `frames` to `writer` and flushes it, retrying each write that
+/// fails with a transient error up to `WRITE_RETRIES` times. A retry carries
+/// on after what was already written, so no frame is cut or doubled.
+async fn write_frames<W>(writer: &mut W, frames: &[&[u8]]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut backoff = retry::Backoff::new(
+        Duration::from_millis(1),
+        Duration::from_millis(50),
+        0.0,
+        Rng::with_seed(0),
+    );
+    let mut retries = 0;
+    for frame in frames {
+        let mut written = 0;
+        while written < frame.len() {
+            match writer.write(&frame[written..]).await {
+                Result::Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Result::Ok(n) => {
+                    written += n;
+                    retries = 0;
+                }
+                Err(e) if is_transient(&e) && retries < WRITE_RETRIES => {
+                    retries += 1;
+                    backoff.wait().await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    loop {
+        match writer.flush().await {
+            Err(e) if is_transient(&e) && retries < WRITE_RETRIES => {
+                retries += 1;
+                backoff.wait().await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Queued {
+    frame: Vec<u8>,
+    reply: bool,
+    done: oneshot::Sender<std::io::Result<()>>,
+}
+
+impl Output {
+    /// Spawns the task writing to `writer`. It stops once every handle is
+    /// dropped.
+    pub fn spawn<W>(mut writer: W) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Queued>();
+        let closed = Arc::new(tokio::sync::Notify::new());
+        let closed_clone = closed.clone();
+        tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                // Write whatever queued up in the meantime and flush once for all
+                let mut batch = vec![first];
+                while let Result::Ok(next) = rx.try_recv() {
+                    batch.push(next);
+                }
+                // Stable, so replies and the rest each keep their order
+                batch.sort_by_key(|queued| !queued.reply);
+                let frames: Vec<&[u8]> = batch.iter().map(|queued| &queued.frame[..]).collect();
+                let result = write_frames(&mut writer, &frames).await;
+                if let Err(e) = &result {
+                    if e.kind() == std::io::ErrorKind::BrokenPipe {
+                        closed_clone.notify_one();
+                    }
+                }
+                for queued in batch {
+                    let _ = queued.done.send(match &result {
+                        Result::Ok(()) => Result::Ok(()),
+                        Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+                    });
+                }
+            }
+        });
+        Self {
+            tx,
+            closed,
+            #[cfg(debug_assertions)]
+            sent_ids: Arc::default(),
+            run: Arc::default(),
+        }
+    }
+
+    pub(crate) fn run(&self) -> &Arc<RunState> {
+        &self.run
+    }
+
+    /// Records that a message with `id` is being sent, returning whether no
+    /// message sent before had it.
+    #[cfg(debug_assertions)]
+    fn first_use(&self, id: usize) -> bool {
+        let mut sent = self.sent_ids.lock().unwrap();
+        if id < sent.below || !sent.above.insert(id) {
+            return false;
+        }
+        let sent = &mut *sent;
+        while sent.above.remove(&sent.below) {
+            sent.below += 1;
+        }
+        true
+    }
+
+    /// Writes `frame` and waits until it has been flushed: stdout hands writes
+    /// to a background thread, so this is what guarantees nothing sent before
+    /// exit is lost.
+    async fn write(&self, frame: Vec<u8>, reply: bool) -> anyhow::Result<()> {
+        let (done, flushed) = oneshot::channel();
+        self.tx
+            .send(Queued { frame, reply, done })
+            .map_err(|_| anyhow::anyhow!("output writer has stopped"))?;
+        flushed
             .await
-            .context("write trailing newline")?;
-        Ok(())
+            .context("output writer has stopped")?
+            .context("write message to stdout")
     }
 }
 
@@ -64,35 +400,563 @@ enum InitPayload {
     InitOk,
 }
 
+/// Introspection any node answers, whatever its own payload: a `debug`
+/// request gets the node's [`Node::snapshot`] back.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum DebugPayload {
+    Debug,
+    DebugOk { snapshot: serde_json::Value },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Init {
     pub node_id: String,
     pub node_ids: Vec<String>,
 }
 
+impl Init {
+    /// Returns every node of the cluster but this one. Binaries whose workload
+    /// sends no topology gossip to all of them.
+    pub fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|id| **id != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a random number generator seeded from this node's id, so every
+    /// run of the same node draws the same sequence.
+    pub fn rng(&self) -> Rng {
+        Rng::for_node(&self.node_id)
+    }
+
+    /// Returns the node that owns `key`, see [`owner`].
+    pub fn owner(&self, key: &str) -> &str {
+        owner(key, &self.node_ids).expect("init lists at least this node")
+    }
+}
+
+/// Picks the node of `nodes` that owns `key`, for sharding keys over a
+/// cluster, or `None` if `nodes` is empty. Every node must agree on the owner,
+/// now and after restarts, so it is chosen by rendezvous hashing with fixed
+/// hashes: the owner is the node whose hash combined with the key's is
+/// highest. The order of `nodes` doesn't matter, and removing a node only
+/// moves the keys it owned.
+///
+/// Ownership must never depend on the std hashers: a `HashMap`'s are seeded
+/// randomly per process, so iterating one to pick an owner would make nodes
+/// disagree.
+pub fn owner<'a>(key: &str, nodes: &'a [String]) -> Option<&'a str> {
+    nodes
+        .iter()
+        .max_by_key(|node| {
+            // The separator keeps ("n1", "2k") and ("n12", "k") apart
+            let mut bytes = Vec::with_capacity(node.len() + key.len() + 1);
+            bytes.extend_from_slice(node.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(key.as_bytes());
+            // FNV-1a barely mixes its last bytes, so the key would decide
+            // the order of the nodes' hashes; SplitMix64 scrambles them
+            let hash = Rng::with_seed(fnv1a(&bytes)).next_u64();
+            (hash, node.as_str())
+        })
+        .map(String::as_str)
+}
+
+/// A small deterministic pseudo-random number generator (SplitMix64).
+///
+/// Not suitable for anything security related; it exists so that jitter and
+/// random choices made by nodes are reproducible.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn with_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeds the generator from a stable hash of `node_id`.
+    pub fn for_node(node_id: &str) -> Self {
+        Self::with_seed(fnv1a(node_id.as_bytes()))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..n`. Panics if `n` is zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "empty range");
+        self.next_u64() % n
+    }
+
+    /// Splits off a generator with its own sequence, e.g. for the jitter of
+    /// one retry loop.
+    pub fn fork(&mut self) -> Rng {
+        Rng::with_seed(self.next_u64())
+    }
+
+    /// Shuffles `items` in place (Fisher-Yates).
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike the std hashers, its output is fixed across runs,
+/// processes and Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
 #[async_trait]
 pub trait Node<Payload, InjectedPayload = ()>: Sync + Send {
+    /// Short name of the node type (e.g. `"kafka"`), used to tag log lines.
+    const NAME: &'static str;
+
     fn from_init(
         init: Init,
         tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
-        stdout: Mutex<tokio::io::Stdout>,
+        stdout: Output,
     ) -> anyhow::Result<Self>
     where
         Self: Sized;
 
+    /// Checks that the node built by `from_init` is configured sensibly, so a
+    /// misconfigured node fails before handling anything rather than with
+    /// confusing errors later. Nodes with nothing to check keep the default.
+    fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The banner the event loop logs once the node is up, so each node's log
+    /// says which configuration it ran with. Nodes with modes of their own
+    /// override it to add them to the default.
+    fn describe(&self, init: &Init, config: &Config) -> String {
+        banner(Self::NAME, init, config)
+    }
+
     async fn handle(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()>;
+
+    /// What the event loop calls to handle an event. Nodes that can be asked
+    /// for data that isn't there yet override it to hand the event back with
+    /// [`Handled::Defer`] instead of retrying in place; the default just runs
+    /// `handle`.
+    async fn handle_or_defer(
+        &self,
+        event: Event<Payload, InjectedPayload>,
+    ) -> anyhow::Result<Handled<Payload, InjectedPayload>>
+    where
+        Payload: Send + 'async_trait,
+        InjectedPayload: Send + 'async_trait,
+    {
+        self.handle(event).await?;
+        Ok(Handled::Done)
+    }
+
+    /// Handles a reply, i.e. a message with `in_reply_to` set. The event loop
+    /// hands every reply here instead of to `handle`, so `handle` only ever
+    /// sees requests, injected events and EOF. Nodes making RPCs route replies
+    /// to the calls waiting on them here, and drop those nobody waits for
+    /// anymore, like late replies to calls that timed out. The default drops
+    /// every reply.
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()>
+    where
+        Payload: Send + 'async_trait,
+    {
+        drop(reply);
+        Ok(())
+    }
+
+    /// A debug view of the node's internal state, for tests to make
+    /// assertions on between messages without the node exposing its fields.
+    /// It is also what the node answers a `debug` request with, as the
+    /// `snapshot` of a `debug_ok`. Nodes that have nothing worth showing keep
+    /// the default `null`.
+    async fn snapshot(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
 }
 
+/// The banner [`Node::describe`] logs by default: the node's `name`, id,
+/// peer count and version, and the modes `config` turns on.
+pub fn banner(name: &str, init: &Init, config: &Config) -> String {
+    let mut modes = Vec::new();
+    if config.ordered_per_source {
+        modes.push("ordered per source".to_string());
+    }
+    if config.panic_policy == PanicPolicy::Abort {
+        modes.push("abort on panic".to_string());
+    }
+    if let Some(rate) = config.retry_rate {
+        modes.push(format!("retries paced at {}/s", rate));
+    }
+    if config.shutdown_deadline.is_none() {
+        modes.push("no shutdown deadline".to_string());
+    }
+    if config.snapshot_at_exit {
+        modes.push("snapshot at exit".to_string());
+    }
+    if let Some(interval) = config.throughput_interval {
+        modes.push(format!("throughput every {:?}", interval));
+    }
+    if let Some(max) = config.max_handlers {
+        modes.push(format!("at most {} handlers", max));
+    }
+    if let Some(max) = config.max_handlers_per_source {
+        modes.push(format!("at most {} handlers per source", max));
+    }
+    if modes.is_empty() {
+        modes.push("default modes".to_string());
+    }
+    format!(
+        "{} {} started with {} peers, version {}: {}",
+        name,
+        init.node_id,
+        init.peers().len(),
+        env!("CARGO_PKG_VERSION"),
+        modes.join(", ")
+    )
+}
+
+/// A node that handles one event at a time and so can mutate itself directly,
+/// without the `Mutex`es and atomics a [`Node`] needs for its concurrent
+/// handlers. Run it by passing [`Serial`] to the event loop, e.g.
+/// `event_loop::<Serial<UniqueIdsNode>, _, _>()`.
+///
+/// A handler can't wait on a reply: the reply is handled only after it
+/// returns, so a handler awaiting one deadlocks, which [`Serial`] warns of.
+/// Nodes making RPCs implement [`Node`] instead.
+#[async_trait]
+pub trait SerialNode<Payload, InjectedPayload = ()>: Send + Sync {
+    /// See [`Node::NAME`].
+    const NAME: &'static str;
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    /// See [`Node::validate`].
+    fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// See [`Node::describe`].
+    fn describe(&self, init: &Init, config: &Config) -> String {
+        banner(Self::NAME, init, config)
+    }
+
+    /// Handles an event. Unlike with [`Node::handle`], replies come here too.
+    async fn handle(&mut self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()>;
+
+    /// See [`Node::snapshot`].
+    async fn snapshot(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+}
+
+/// Runs a [`SerialNode`] as a [`Node`], handing it events one at a time.
+pub struct Serial<N>(tokio::sync::Mutex<N>);
+
+#[async_trait]
+impl<N, P, IP> Node<P, IP> for Serial<N>
+where
+    N: SerialNode<P, IP>,
+    P: Send + 'static,
+    IP: Send + 'static,
+{
+    const NAME: &'static str = N::NAME;
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<P, IP>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self(tokio::sync::Mutex::new(N::from_init(
+            init, tx, stdout,
+        )?)))
+    }
+
+    // Both run before the first event, when nothing holds the node
+    fn validate(&self) -> anyhow::Result<()> {
+        match self.0.try_lock() {
+            Result::Ok(node) => node.validate(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn describe(&self, init: &Init, config: &Config) -> String {
+        match self.0.try_lock() {
+            Result::Ok(node) => node.describe(init, config),
+            Err(_) => banner(Self::NAME, init, config),
+        }
+    }
+
+    async fn handle(&self, event: Event<P, IP>) -> anyhow::Result<()> {
+        self.0.lock().await.handle(event).await
+    }
+
+    /// Hands the reply to `handle` once the node is free. A node that stays
+    /// busy for long is likely awaiting this very reply, which never comes.
+    async fn handle_reply(&self, reply: Message<P>) -> anyhow::Result<()>
+    where
+        P: Send + 'async_trait,
+    {
+        let lock = self.0.lock();
+        tokio::pin!(lock);
+        let mut node = match tokio::time::timeout(rpc::DEADLOCK_WARNING_AFTER, &mut lock).await {
+            Result::Ok(node) => node,
+            Err(_) => {
+                log!(
+                    "DEADLOCK? the reply from {} waits for a handler that has run for over \
+                     {:?}; a serial node's handler can't await a reply",
+                    reply.src,
+                    rpc::DEADLOCK_WARNING_AFTER
+                );
+                lock.await
+            }
+        };
+        node.handle(Event::Message(reply)).await
+    }
+
+    async fn snapshot(&self) -> serde_json::Value {
+        self.0.lock().await.snapshot().await
+    }
+}
+
+/// An error reported by a Maelstrom KV store, classified by its error code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    /// The request timed out (code 0).
+    Timeout,
+    /// The key does not exist (code 20).
+    KeyDoesNotExist,
+    /// The key already exists (code 21).
+    KeyAlreadyExists,
+    /// The store can't serve the request right now (code 11). Also reported
+    /// without asking the store, while its circuit breaker is open.
+    TemporarilyUnavailable,
+    /// The `from` value of a `cas` did not match (code 22).
+    PreconditionFailed,
+    /// Any other error code.
+    Other { code: usize, text: String },
+}
+
+impl KvError {
+    pub fn from_code(code: usize, text: String) -> Self {
+        match code {
+            0 => Self::Timeout,
+            11 => Self::TemporarilyUnavailable,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            _ => Self::Other { code, text },
+        }
+    }
+
+    /// The Maelstrom error code, the inverse of [`KvError::from_code`].
+    pub fn code(&self) -> usize {
+        match self {
+            Self::Timeout => 0,
+            Self::TemporarilyUnavailable => 11,
+            Self::KeyDoesNotExist => 20,
+            Self::KeyAlreadyExists => 21,
+            Self::PreconditionFailed => 22,
+            Self::Other { code, .. } => *code,
+        }
+    }
+
+    /// Classifies an error returned by a [`KV`] method. Errors that didn't
+    /// come from the store itself, e.g. a failed send, map to `Other`.
+    pub fn classify(e: &anyhow::Error) -> Self {
+        e.downcast_ref::<Self>()
+            .cloned()
+            .unwrap_or_else(|| Self::Other {
+                code: 13,
+                text: format!("{:#}", e),
+            })
+    }
+}
+
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timeout"),
+            Self::KeyDoesNotExist => write!(f, "key does not exist"),
+            Self::KeyAlreadyExists => write!(f, "key already exists"),
+            Self::TemporarilyUnavailable => write!(f, "temporarily unavailable"),
+            Self::PreconditionFailed => write!(f, "precondition failed"),
+            Self::Other { code, text } => write!(f, "error {}: {}", code, text),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// A value as held by a Maelstrom KV store, which stores whatever JSON it is
+/// given. Nodes that keep different kinds of values in one store read them as
+/// `StoredValue` and convert, so a key holding the wrong kind fails with a
+/// [`TypeMismatch`] instead of an opaque deserialization error.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum StoredValue {
+    Int(i64),
+    Str(String),
+    Json(serde_json::Value),
+}
+
+impl StoredValue {
+    /// Name of the kind of value, for error messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "int",
+            Self::Str(_) => "string",
+            Self::Json(_) => "json",
+        }
+    }
+
+    pub fn into_int(self) -> Result<i64, TypeMismatch> {
+        match self {
+            Self::Int(value) => Result::Ok(value),
+            other => Err(TypeMismatch::new("int", &other)),
+        }
+    }
+
+    pub fn into_str(self) -> Result<String, TypeMismatch> {
+        match self {
+            Self::Str(value) => Result::Ok(value),
+            other => Err(TypeMismatch::new("string", &other)),
+        }
+    }
+
+    /// Returns the value as JSON. Every kind of value converts.
+    pub fn into_json(self) -> serde_json::Value {
+        match self {
+            Self::Int(value) => value.into(),
+            Self::Str(value) => value.into(),
+            Self::Json(value) => value,
+        }
+    }
+}
+
+impl From<i64> for StoredValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<String> for StoredValue {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl From<serde_json::Value> for StoredValue {
+    fn from(value: serde_json::Value) -> Self {
+        Self::Json(value)
+    }
+}
+
+impl From<StoredValue> for serde_json::Value {
+    fn from(value: StoredValue) -> Self {
+        value.into_json()
+    }
+}
+
+/// Name of the kind of a JSON value, for error messages.
+fn json_kind(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "bool",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+/// A [`StoredValue`] was of a different kind than the reader expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl TypeMismatch {
+    fn new(expected: &'static str, found: &StoredValue) -> Self {
+        Self {
+            expected,
+            found: found.kind(),
+        }
+    }
+}
+
+impl std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected a stored {}, found a {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
 #[async_trait]
 pub trait KV<T>: Send + Sync {
     /// Read returns the value for a given key in the key/value store.
     /// Returns an RPCError error with a KeyDoesNotExist code if the key does not exist.
-    async fn read(&self, storage: &String, key: String) -> anyhow::Result<T>
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<T>
     where
         T: Deserialize<'static> + Send;
 
+    /// Reads `key` and deserializes it as a `U`. Unlike a plain serde error, a
+    /// value of the wrong type fails with an error naming the store, the key,
+    /// the type expected and the start of the JSON found.
+    async fn read_typed<U>(&self, storage: &str, key: String) -> anyhow::Result<U>
+    where
+        T: Deserialize<'static> + Into<serde_json::Value> + Send,
+        U: DeserializeOwned,
+    {
+        let raw: serde_json::Value = self.read(storage, key.clone()).await?.into();
+        serde_json::from_value(raw.clone()).with_context(|| {
+            let mut found = raw.to_string();
+            if found.len() > 64 {
+                let end = (0..=61).rev().find(|i| found.is_char_boundary(*i));
+                found.truncate(end.unwrap_or(0));
+                found.push_str("...");
+            }
+            format!(
+                "{} key {:?} holds {} {}, not a {}",
+                storage,
+                key,
+                json_kind(&raw),
+                found,
+                std::any::type_name::<U>()
+            )
+        })
+    }
+
     /// Write overwrites the value for a given key in the key/value store.
-    async fn write(&self, storage: &String, key: String, val: T) -> anyhow::Result<()>
+    async fn write(&self, storage: &str, key: String, val: T) -> anyhow::Result<()>
     where
         T: Serialize + Send;
 
@@ -103,7 +967,7 @@ pub trait KV<T>: Send + Sync {
     /// does not match. Return a code of KeyDoesNotExist if the key did not exist.
     async fn cas(
         &self,
-        storage: &String,
+        storage: &str,
         key: String,
         from: T,
         to: T,
@@ -111,6 +975,141 @@ pub trait KV<T>: Send + Sync {
     ) -> anyhow::Result<()>
     where
         T: Serialize + Deserialize<'static> + Send;
+
+    /// Create sets a key only if it does not exist yet, failing with
+    /// [`KvError::KeyAlreadyExists`] if it does.
+    ///
+    /// Built on a creating `cas` from `value` itself, so creating a key that
+    /// already holds the same value succeeds; a retried create whose first
+    /// attempt timed out after landing is not reported as a conflict.
+    async fn create(&self, storage: &str, key: String, value: T) -> anyhow::Result<()>
+    where
+        T: Serialize + Deserialize<'static> + Clone + Send + 'async_trait,
+    {
+        match self.cas(storage, key, value.clone(), value, true).await {
+            Err(e) if KvError::classify(&e) == KvError::PreconditionFailed => {
+                Err(KvError::KeyAlreadyExists.into())
+            }
+            res => res,
+        }
+    }
+
+    /// Atomically adds `delta` to the number under `key` and returns the new
+    /// value. A key that doesn't exist yet counts as zero.
+    ///
+    /// Runs a cas loop, so every concurrent update that gets in first costs a
+    /// retry, from the current value the failed cas reports.
+    async fn incr(&self, storage: &str, key: String, delta: T) -> anyhow::Result<T>
+    where
+        T: Serialize
+            + Deserialize<'static>
+            + Copy
+            + Default
+            + std::ops::Add<Output = T>
+            + Send
+            + 'async_trait,
+    {
+        let mut current = T::default();
+        loop {
+            let next = current + delta;
+            match self
+                .cas_or_current(storage, key.clone(), current, next, true)
+                .await
+            {
+                Result::Ok(()) => return Ok(next),
+                Err((KvError::PreconditionFailed, Some(value))) => current = value,
+                Err((KvError::PreconditionFailed, None)) => {
+                    current = self.read(storage, key.clone()).await?;
+                }
+                Err((err, _)) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Like `cas`, but a failure comes with its [`KvError`] and, on a
+    /// precondition failure, the key's current value, so an update loop can
+    /// retry without reading the key first.
+    ///
+    /// This default implementation reads the current value separately; stores
+    /// that report it in their error reply can override it to save the read.
+    async fn cas_or_current(
+        &self,
+        storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> Result<(), (KvError, Option<T>)>
+    where
+        T: Serialize + Deserialize<'static> + Send + 'async_trait,
+    {
+        let err = match self.cas(storage, key.clone(), from, to, put).await {
+            Result::Ok(()) => return Result::Ok(()),
+            Err(e) => KvError::classify(&e),
+        };
+        let current = match err {
+            KvError::PreconditionFailed => self.read(storage, key).await.ok(),
+            _ => None,
+        };
+        Err((err, current))
+    }
+
+    /// Watches `key` for changes, reading it every `interval`; see [`Watch`].
+    fn watch(&self, storage: &str, key: String, interval: Duration) -> Watch<'_, Self, T>
+    where
+        Self: Sized,
+    {
+        Watch {
+            kv: self,
+            storage: storage.to_string(),
+            key,
+            interval,
+            last: None,
+        }
+    }
+}
+
+/// Yields the values of a KV key as they change, from [`KV::watch`].
+///
+/// The first call to `next` yields the current value, later calls the first
+/// value read that differs from the one before; changes between two reads
+/// are missed. A key that doesn't exist yet, or a read that times out or
+/// finds the store unavailable, is read again next interval.
+pub struct Watch<'a, K, T> {
+    kv: &'a K,
+    storage: String,
+    key: String,
+    interval: Duration,
+    last: Option<T>,
+}
+
+impl<K, T> Watch<'_, K, T>
+where
+    K: KV<T>,
+    T: Deserialize<'static> + PartialEq + Clone + Send,
+{
+    /// Waits for the key's next value.
+    pub async fn next(&mut self) -> anyhow::Result<T> {
+        loop {
+            match self.kv.read(&self.storage, self.key.clone()).await {
+                Result::Ok(value) if self.last.as_ref() != Some(&value) => {
+                    self.last = Some(value.clone());
+                    return Ok(value);
+                }
+                Result::Ok(_) => {}
+                Err(e) => match KvError::classify(&e) {
+                    KvError::KeyDoesNotExist
+                    | KvError::Timeout
+                    | KvError::TemporarilyUnavailable => {}
+                    _ => {
+                        return Err(e)
+                            .with_context(|| format!("watch {} key {:?}", self.storage, self.key))
+                    }
+                },
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -149,6 +1148,32 @@ fn is_ref_false(b: &bool) -> bool {
     !*b
 }
 
+/// Serializes a set as an array in ascending order, for use with
+/// `#[serde(serialize_with = "...")]`. A `HashSet` otherwise serializes in hash
+/// order, which differs from run to run and makes captured output impossible
+/// to compare. Deserializing is unaffected and accepts any order.
+pub fn serialize_sorted<T, S>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Ord + Serialize,
+    S: serde::Serializer,
+{
+    let mut sorted: Vec<&T> = set.iter().collect();
+    sorted.sort_unstable();
+    sorted.serialize(serializer)
+}
+
+/// Serializes a map in ascending key order, like [`serialize_sorted`] does
+/// for sets, so replies carrying a map per key come out the same every time.
+pub fn serialize_sorted_map<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: serde::Serializer,
+{
+    let sorted: std::collections::BTreeMap<&K, &V> = map.iter().collect();
+    sorted.serialize(serializer)
+}
+
 #[derive(Debug, Clone)]
 pub enum Event<Payload, InjectedPayload = ()> {
     Message(Message<Payload>),
@@ -156,71 +1181,914 @@ pub enum Event<Payload, InjectedPayload = ()> {
     EOF,
 }
 
-pub async fn event_loop<N, P, IP>() -> anyhow::Result<()>
+tokio::task_local! {
+    static SPAN: String;
+}
+
+/// Writes a line to stderr. Inside the event loop the line is prefixed with the
+/// node type and id, e.g. `[kafka n1] ...`. While a request is being handled
+/// the prefix also names the request by its source and `msg_id`, e.g.
+/// `[kafka n1 c1#3] ...`, so every line logged on its behalf, including by the
+/// RPCs it fans out to, shares that id. Tasks spawned by a handler don't
+/// inherit the prefix.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::write_log(format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn write_log(args: std::fmt::Arguments<'_>) {
+    if SPAN
+        .try_with(|span| eprintln!("[{}] {}", span, args))
+        .is_err()
+    {
+        eprintln!("{}", args);
+    }
+}
+
+/// Runtime configuration for [`event_loop_with_config`].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Capacity of the channel feeding events into the event loop. Messages read
+    /// from stdin and injected events (e.g. from [`spawn_timer`]) share it.
+    ///
+    /// Stdin messages wait for free space, but periodic timers never block on a
+    /// full channel: a tick that doesn't fit is dropped and folded into the next
+    /// one. A stalled consumer therefore queues at most `channel_capacity` events,
+    /// and a larger capacity only buys room for bursts of client messages.
+    pub channel_capacity: usize,
+    /// Largest serialized message [`Message::send`] will write. Sending a
+    /// bigger one fails; gossip should be split with [`Message::split_to_fit`].
+    pub max_message_bytes: usize,
+    /// Handle the messages of each source one at a time, in the order they
+    /// were received, instead of all concurrently. Messages from different
+    /// sources are still handled in parallel.
+    ///
+    /// Replies (messages with `in_reply_to`) skip the per-source queues, since
+    /// a queued handler may be waiting for exactly that reply.
+    pub ordered_per_source: bool,
+    /// What to do when a handler panics.
+    pub panic_policy: PanicPolicy,
+    /// Retries per second allowed across all RPCs of the node, see
+    /// [`retry::pace`]. `None` leaves retries unpaced.
+    pub retry_rate: Option<f64>,
+    /// Most retries let through at once by `retry_rate`.
+    pub retry_burst: u32,
+    /// Log a warning when handling an event takes longer than this. The
+    /// handler keeps running; `None` turns the warning off.
+    pub slow_handler: Option<Duration>,
+    /// How many times one event may be deferred, see [`Handled::Defer`]. A
+    /// deferred event waits in its handler task, so with `ordered_per_source`
+    /// it holds up the messages after it from the same source.
+    pub max_deferrals: u32,
+    /// How long handlers still running at the end of the input get to finish.
+    /// Those that don't are aborted, so the event loop returns even if one of
+    /// them waits for a reply that never comes. `None` waits indefinitely.
+    pub shutdown_deadline: Option<Duration>,
+    /// Log the node's [`Node::snapshot`] as `final snapshot: {json}` once the
+    /// input has ended and its handlers are done. A test running several
+    /// nodes can then compare their final states, e.g. check that broadcast
+    /// nodes converged, without the Maelstrom checker.
+    pub snapshot_at_exit: bool,
+    /// Log every this often how many messages per second the node received
+    /// over the last interval, see [`metrics::Throughput`]. `None` logs
+    /// nothing.
+    pub throughput_interval: Option<Duration>,
+    /// Most handlers of requests running at once, counting those of queued
+    /// requests with `ordered_per_source`. A request over the limit waits in
+    /// its task for a running handler to finish. Replies, injected
+    /// events and EOF never wait, since a waiting handler may need exactly
+    /// them. `None` doesn't limit handlers.
+    pub max_handlers: Option<usize>,
+    /// Most handlers of requests from one source running at once, so that a
+    /// source flooding the node can't take every handler `max_handlers`
+    /// allows. With `ordered_per_source` each source has one anyway. `None`
+    /// doesn't limit sources.
+    pub max_handlers_per_source: Option<usize>,
+}
+
+/// Outcome of [`Node::handle_or_defer`].
+#[derive(Debug)]
+pub enum Handled<Payload, InjectedPayload = ()> {
+    Done,
+    /// Deliver the event again after the delay. An event is deferred at most
+    /// [`Config::max_deferrals`] times, after which handling it fails.
+    Defer(Duration, Event<Payload, InjectedPayload>),
+}
+
+/// What the event loop does when a handler task panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Log the panic and keep handling events. Only the panicking event is
+    /// lost.
+    #[default]
+    Continue,
+    /// Abort the process, so a test run fails loudly on the first panic.
+    Abort,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 16,
+            max_message_bytes: 1 << 20,
+            ordered_per_source: false,
+            panic_policy: PanicPolicy::default(),
+            retry_rate: None,
+            retry_burst: 10,
+            slow_handler: Some(Duration::from_secs(1)),
+            max_deferrals: 10,
+            shutdown_deadline: Some(Duration::from_secs(5)),
+            snapshot_at_exit: false,
+            throughput_interval: None,
+            max_handlers: None,
+            max_handlers_per_source: None,
+        }
+    }
+}
+
+/// Spawns a task that injects `payload` into the event loop every `period`.
+///
+/// Ticks are sent with `try_send`, so if the event channel is full the tick is
+/// skipped instead of blocking the timer. The task stops once the event loop
+/// has gone away.
+pub fn spawn_timer<P, IP>(
+    tx: tokio::sync::mpsc::Sender<Event<P, IP>>,
+    period: Duration,
+    payload: IP,
+) -> JoinHandle<()>
+where
+    P: Send + 'static,
+    IP: Clone + Send + 'static,
+{
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + period;
+        let mut interval = tokio::time::interval_at(start, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            match tx.try_send(Event::Injected(payload.clone())) {
+                Result::Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Closed(_)) => break,
+            }
+        }
+    })
+}
+
+/// The injected events of a node, declared in one place: a binary's injected
+/// payload type lists every event a timer should inject along with its period,
+/// and [`spawn_timers`] starts them all. Each variant arrives as
+/// [`Event::Injected`], so adding a timer is adding a variant and a schedule
+/// entry.
+///
+/// Nodes without timers use `()`, whose schedule is empty.
+pub trait Periodic: Clone + Send + Sized + 'static {
+    /// Every event to inject, with the period to inject it at.
+    fn schedule() -> Vec<(Duration, Self)>;
+}
+
+impl Periodic for () {
+    fn schedule() -> Vec<(Duration, Self)> {
+        Vec::new()
+    }
+}
+
+/// Spawns a [`spawn_timer`] for every entry of `IP::schedule()`.
+pub fn spawn_timers<P, IP>(tx: &tokio::sync::mpsc::Sender<Event<P, IP>>) -> Vec<JoinHandle<()>>
+where
+    P: Send + 'static,
+    IP: Periodic,
+{
+    IP::schedule()
+        .into_iter()
+        .map(|(period, payload)| spawn_timer(tx.clone(), period, payload))
+        .collect()
+}
+
+/// Drives all `futures` concurrently on the current task and returns their
+/// outputs in the order the futures were given.
+///
+/// Unlike spawning onto a `JoinSet`, the futures may borrow from the caller,
+/// which lets a handler fan out over `&self`.
+pub async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut done = true;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            let Some(f) = future else { continue };
+            match f.as_mut().poll(cx) {
+                Poll::Ready(value) => {
+                    *output = Some(value);
+                    *future = None;
+                }
+                Poll::Pending => done = false,
+            }
+        }
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs
+        .into_iter()
+        .map(|output| output.expect("future completed"))
+        .collect()
+}
+
+/// Drives all `futures` concurrently, like [`join_all`], but returns as soon
+/// as `needed` of them succeeded, with their outputs in the order they
+/// finished. Returns `None` once so many failed that `needed` can't be reached
+/// anymore. The futures still running are dropped.
+pub async fn join_quorum<T, E, F>(
+    futures: impl IntoIterator<Item = F>,
+    needed: usize,
+) -> Option<Vec<T>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let mut futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let mut pending = futures.len();
+    let mut outputs = Vec::with_capacity(needed);
+    std::future::poll_fn(|cx| {
+        for future in futures.iter_mut() {
+            let Some(f) = future else { continue };
+            if let Poll::Ready(result) = f.as_mut().poll(cx) {
+                *future = None;
+                pending -= 1;
+                if let Result::Ok(output) = result {
+                    outputs.push(output);
+                }
+            }
+            if outputs.len() >= needed {
+                break;
+            }
+        }
+        if outputs.len() >= needed || outputs.len() + pending < needed {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    (outputs.len() >= needed).then_some(outputs)
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Result::Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+fn usage(name: &str) -> String {
+    format!(
+        "\
+{name}: a Maelstrom node, meant to be run by Maelstrom, e.g.
+    maelstrom test -w <workload> --bin <path to this binary> ...
+
+It reads Maelstrom protocol messages, {format}, from stdin,
+starting with `init`, and writes its replies to stdout. Logs go to stderr.
+
+Options:
+    --node-id <id> --nodes <id,id,...>
+        Start as node <id> of the given cluster instead of waiting for
+        `init`, for running nodes by hand or from a test without Maelstrom.
+",
+        format = codec::FORMAT,
+    )
+}
+
+/// Builds the `Init` from `--node-id <id> --nodes <id,id,...>`, or returns
+/// `None` if neither is given.
+fn init_from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Init>> {
+    let mut node_id = None;
+    let mut nodes = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--node-id" => node_id = Some(args.next().context("--node-id needs a value")?),
+            "--nodes" => nodes = Some(args.next().context("--nodes needs a value")?),
+            _ => {}
+        }
+    }
+    let (node_id, nodes) = match (node_id, nodes) {
+        (None, None) => return Ok(None),
+        (Some(node_id), Some(nodes)) => (node_id, nodes),
+        _ => anyhow::bail!("--node-id and --nodes must be given together"),
+    };
+    let node_ids: Vec<String> = nodes
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .collect();
+    anyhow::ensure!(
+        node_ids.contains(&node_id),
+        "--nodes doesn't include --node-id {}",
+        node_id
+    );
+    Ok(Some(Init { node_id, node_ids }))
+}
+
+/// Runs `node.handle(event)`, logging when it takes longer than
+/// `threshold` and again once it finishes.
+async fn handle_watched<N, P, IP>(
+    node: &N,
+    event: Event<P, IP>,
+    threshold: Option<Duration>,
+) -> anyhow::Result<Handled<P, IP>>
+where
+    N: Node<P, IP>,
+    P: std::fmt::Debug + Send,
+    IP: Send,
+{
+    let Some(threshold) = threshold else {
+        return node.handle_or_defer(event).await;
+    };
+    let what = describe(&event);
+    let start = tokio::time::Instant::now();
+    let handling = node.handle_or_defer(event);
+    tokio::pin!(handling);
+    tokio::select! {
+        result = &mut handling => return result,
+        () = tokio::time::sleep(threshold) => {
+            log!("handling {} is taking longer than {:?}", what, threshold);
+        }
+    }
+    let result = handling.await;
+    log!("handling {} took {:?}", what, start.elapsed());
+    result
+}
+
+/// Handles `event`, delivering it again for as long as the node defers it, up
+/// to `max_deferrals` times.
+async fn handle_deferred<N, P, IP>(
+    node: &N,
+    event: Event<P, IP>,
+    threshold: Option<Duration>,
+    max_deferrals: u32,
+) -> anyhow::Result<()>
+where
+    N: Node<P, IP>,
+    P: std::fmt::Debug + Send,
+    IP: Send,
+{
+    let mut event = match event {
+        Event::Message(reply) if reply.body.in_reply_to.is_some() => {
+            return node.handle_reply(reply).await;
+        }
+        event => event,
+    };
+    let mut deferrals = 0;
+    loop {
+        match handle_watched(node, event, threshold).await? {
+            Handled::Done => return Ok(()),
+            Handled::Defer(after, deferred) => {
+                anyhow::ensure!(
+                    deferrals < max_deferrals,
+                    "gave up on {} after {} deferrals",
+                    describe(&deferred),
+                    deferrals
+                );
+                deferrals += 1;
+                tokio::time::sleep(after).await;
+                event = deferred;
+            }
+        }
+    }
+}
+
+/// Returns the log prefix for handling `event`: `span` followed by the
+/// request's source and message id, if `event` is a request.
+fn request_span<P, IP>(span: &str, event: &Event<P, IP>) -> String {
+    match event {
+        Event::Message(Message {
+            src,
+            body: Body { id: Some(id), .. },
+            ..
+        }) => format!("{} {}#{}", span, src, id),
+        _ => span.to_string(),
+    }
+}
+
+/// What a task handles, for logs, e.g. `Send from c1` once displayed.
+#[derive(Debug, Clone)]
+enum EventName {
+    Message { variant: String, src: String },
+    Queued { src: String },
+    Injected,
+    Eof,
+}
+
+impl std::fmt::Display for EventName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message { variant, src } => write!(f, "{} from {}", variant, src),
+            Self::Queued { src } => write!(f, "queued messages from {}", src),
+            Self::Injected => write!(f, "injected event"),
+            Self::Eof => write!(f, "EOF"),
+        }
+    }
+}
+
+/// Collects the `Debug` output of a payload up to the end of its variant
+/// name, then fails the write, which stops the formatting before any of the
+/// fields, however many values they hold.
+struct VariantName(String);
+
+impl std::fmt::Write for VariantName {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        match s.find(|c: char| !c.is_alphanumeric() && c != '_') {
+            Some(end) => {
+                self.0.push_str(&s[..end]);
+                Err(std::fmt::Error)
+            }
+            None => {
+                self.0.push_str(s);
+                Result::Ok(())
+            }
+        }
+    }
+}
+
+/// Names `event` for logs.
+fn describe<P: std::fmt::Debug, IP>(event: &Event<P, IP>) -> EventName {
+    match event {
+        Event::Message(msg) => {
+            let mut variant = VariantName(String::new());
+            let _ =
+                std::fmt::Write::write_fmt(&mut variant, format_args!("{:?}", msg.body.payload));
+            EventName::Message {
+                variant: variant.0,
+                src: msg.src.clone(),
+            }
+        }
+        Event::Injected(_) => EventName::Injected,
+        Event::EOF => EventName::Eof,
+    }
+}
+
+/// Registers what a task is doing in a shared table for as long as the task
+/// holds on to it, so tasks still running at shutdown can be named.
+struct Tracked {
+    running: Arc<std::sync::Mutex<HashMap<u64, EventName>>>,
+    id: u64,
+}
+
+impl Tracked {
+    fn new(
+        running: &Arc<std::sync::Mutex<HashMap<u64, EventName>>>,
+        id: u64,
+        what: EventName,
+    ) -> Self {
+        running.lock().unwrap().insert(id, what);
+        Self {
+            running: running.clone(),
+            id,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Aborts a task when dropped, so a task spawned by another is cancelled
+/// along with it.
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Logs the outcome of a finished event loop task, applying `policy` if it
+/// panicked.
+fn reap(result: Result<anyhow::Result<()>, JoinError>, policy: PanicPolicy) {
+    let e = match result {
+        Result::Ok(Result::Ok(())) => return,
+        Result::Ok(Err(e)) => {
+            log!("{:#}", e);
+            return;
+        }
+        Err(e) => e,
+    };
+    if !e.is_panic() {
+        return;
+    }
+    let panic = e.into_panic();
+    let reason = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown reason");
+    match policy {
+        PanicPolicy::Continue => log!("handler panicked: {}", reason),
+        PanicPolicy::Abort => {
+            log!("handler panicked, aborting: {}", reason);
+            std::process::abort();
+        }
+    }
+}
+
+pub async fn event_loop<N, P, IP>() -> anyhow::Result<Stopped>
 where
     N: Node<P, IP> + 'static,
     P: std::fmt::Debug + DeserializeOwned + Send + 'static,
     IP: Send + 'static,
 {
+    event_loop_with_config::<N, P, IP>(Config::default()).await
+}
+
+pub async fn event_loop_with_config<N, P, IP>(config: Config) -> anyhow::Result<Stopped>
+where
+    N: Node<P, IP> + 'static,
+    P: std::fmt::Debug + DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    if std::env::args()
+        .skip(1)
+        .any(|arg| arg == "--help" || arg == "-h")
+    {
+        eprint!("{}", usage(N::NAME));
+        return Ok(Stopped::Finished);
+    }
+    if std::io::stdin().is_terminal() {
+        eprintln!(
+            "{}: waiting for Maelstrom messages on stdin (see --help)",
+            N::NAME
+        );
+    }
+    let init = init_from_args(std::env::args().skip(1))?;
     let stdin = tokio::io::stdin();
-    let stdin = tokio::io::BufReader::new(stdin);
-    let stdout = Mutex::new(tokio::io::stdout());
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-
-    let init_msg: Message<InitPayload> = serde_json::from_str(
-        &stdin
-            .lines()
-            .next_line()
-            .await
-            .expect("read init")
-            .context("failed to read init message from stdin")?,
-    )
-    .context("init message could not be deserialized")?;
+    run::<N, P, IP, _, _>(stdin, tokio::io::stdout(), config, init).await
+}
 
-    let InitPayload::Init(init) = init_msg.body.payload else {
-        return Err(anyhow::anyhow!("expected init message"));
-    };
+/// How an event loop stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// The input ended, and every handler finished or was aborted.
+    Finished,
+    /// A signal or a closed stdout ended the input instead.
+    Signalled,
+}
+
+impl Stopped {
+    /// Exits the process if the event loop was signalled. Its read of stdin
+    /// may still be in flight then, and the runtime can't shut down until a
+    /// blocking read returns, so `main` would hang on returning.
+    pub fn exit_if_signalled(self) {
+        if self == Self::Signalled {
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Like [`event_loop_with_config`], but reads messages from `reader` and
+/// writes to `writer` instead of stdin and stdout, so a test can drive a node
+/// from memory and capture what it sends. The input must start with `init`.
+pub async fn event_loop_with<N, P, IP, R, W>(
+    reader: R,
+    writer: W,
+    config: Config,
+) -> anyhow::Result<Stopped>
+where
+    N: Node<P, IP> + 'static,
+    P: std::fmt::Debug + DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    run::<N, P, IP, R, W>(reader, writer, config, None).await
+}
+
+/// Takes a permit of each of `limits`, in order.
+async fn acquire_all(limits: Vec<Arc<Semaphore>>) -> Vec<OwnedSemaphorePermit> {
+    let mut permits = Vec::with_capacity(limits.len());
+    for limit in limits {
+        // The semaphores are never closed
+        if let Result::Ok(permit) = limit.acquire_owned().await {
+            permits.push(permit);
+        }
+    }
+    permits
+}
+
+/// Runs the node, starting from `init` if given, else from the `init`
+/// handshake on `reader`.
+async fn run<N, P, IP, R, W>(
+    reader: R,
+    writer: W,
+    config: Config,
+    init: Option<Init>,
+) -> anyhow::Result<Stopped>
+where
+    N: Node<P, IP> + 'static,
+    P: std::fmt::Debug + DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    // One reader for init and everything after it: input buffered along with
+    // init must not be lost.
+    let mut stdin = tokio::io::BufReader::new(reader);
+    let stdout = Output::spawn(writer);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(config.channel_capacity);
+    stdout
+        .run
+        .max_message_bytes
+        .store(config.max_message_bytes, Ordering::Relaxed);
+    if let Some(rate) = config.retry_rate {
+        let _ = stdout
+            .run
+            .retries
+            .set(retry::RateLimiter::new(rate, config.retry_burst));
+    }
 
-    let reply = Message {
-        src: init_msg.dest,
-        dest: init_msg.src,
-        body: Body {
-            id: Some(0),
-            in_reply_to: init_msg.body.id,
-            payload: InitPayload::InitOk,
-        },
+    let init = match init {
+        Some(init) => init,
+        None => {
+            let init_frame = codec::read_frame(&mut stdin)
+                .await
+                .context("failed to read init message from stdin")?
+                .context("stdin closed before init")?;
+            let init_msg: Message<InitPayload> =
+                codec::decode(&init_frame).context("init message could not be deserialized")?;
+
+            let InitPayload::Init(init) = init_msg.body.payload else {
+                return Err(anyhow::anyhow!("expected init message"));
+            };
+
+            let reply = Message {
+                src: init_msg.dest,
+                dest: init_msg.src,
+                body: Body {
+                    id: Some(0),
+                    in_reply_to: init_msg.body.id,
+                    payload: InitPayload::InitOk,
+                },
+            };
+            reply.send(&stdout).await.context("send response to init")?;
+            init
+        }
     };
-    reply.send(&stdout).await.context("send response to init")?;
 
-    let node = Arc::new(N::from_init(init, tx.clone(), stdout)?);
+    // Maelstrom wants `init_ok` before anything else. It has been flushed by
+    // now and the node only gets its output here, so nothing it sends, not even
+    // from a task spawned in `from_init`, can get ahead of it.
+    let span = format!("{} {}", N::NAME, init.node_id);
+    // For answering repeated inits, and noticing stdout was closed
+    let init_stdout = stdout.clone();
+    let run = stdout.run.clone();
+    let banner_init = init.clone();
+    let node = Arc::new(SPAN.sync_scope(span.clone(), || N::from_init(init, tx.clone(), stdout))?);
+    node.validate().context("node failed validation")?;
+    SPAN.sync_scope(span.clone(), || {
+        log!("{}", node.describe(&banner_init, &config))
+    });
 
+    let signalled = Arc::new(AtomicBool::new(false));
+    let signalled_clone = signalled.clone();
     let mut join_set = JoinSet::new();
-    join_set.spawn(async move {
-        let stdin = tokio::io::stdin();
-        let mut stdin = tokio::io::BufReader::new(stdin).lines();
-        while let Some(line) = stdin.next_line().await.expect("read line") {
-            let input: Message<P> = serde_json::from_str(&line)
-                .context("input from Maelstrom on stdin could not be deserialized")?;
-            if let Err(_) = tx.send(Event::Message(input)).await {
+    let debug_node = node.clone();
+    let debug_span = span.clone();
+    join_set.spawn(SPAN.scope(span.clone(), async move {
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        let stdout_closed = init_stdout.closed.clone();
+        loop {
+            let frame = tokio::select! {
+                frame = codec::read_frame(&mut stdin) => frame.context("read message from stdin")?,
+                () = &mut shutdown => {
+                    log!("received shutdown signal");
+                    signalled_clone.store(true, Ordering::SeqCst);
+                    None
+                }
+                () = stdout_closed.notified() => {
+                    log!("stdout was closed, shutting down");
+                    signalled_clone.store(true, Ordering::SeqCst);
+                    None
+                }
+            };
+            // A signal shuts the node down the same way as the end of stdin
+            let Some(frame) = frame else { break };
+            let input: Message<P> = match codec::decode(&frame) {
+                Result::Ok(input) => input,
+                Err(e) => {
+                    // Answered off to the side, so the snapshot doesn't hold up
+                    // reading the messages after it
+                    if let Result::Ok(debug) = codec::decode::<Message<DebugPayload>>(&frame) {
+                        if let DebugPayload::Debug = debug.body.payload {
+                            let node = debug_node.clone();
+                            let stdout = init_stdout.clone();
+                            tokio::spawn(SPAN.scope(debug_span.clone(), async move {
+                                let snapshot = node.snapshot().await;
+                                let reply =
+                                    debug.into_reply_with(None, DebugPayload::DebugOk { snapshot });
+                                if let Err(e) = reply.send(&stdout).await {
+                                    log!("failed to answer debug request: {:#}", e);
+                                }
+                            }));
+                        }
+                        continue;
+                    }
+                    // The node is set up already, so a repeated init only gets
+                    // its `init_ok`
+                    let Result::Ok(init) = codec::decode::<Message<InitPayload>>(&frame) else {
+                        return Err(e)
+                            .context("input from Maelstrom on stdin could not be deserialized");
+                    };
+                    log!("ignoring repeated init from {}", init.src);
+                    let reply = Message {
+                        src: init.dest,
+                        dest: init.src,
+                        body: Body {
+                            id: None,
+                            in_reply_to: init.body.id,
+                            payload: InitPayload::InitOk,
+                        },
+                    };
+                    reply
+                        .send(&init_stdout)
+                        .await
+                        .context("send response to init")?;
+                    continue;
+                }
+            };
+            if tx.send(Event::Message(input)).await.is_err() {
                 return Ok(());
             }
         }
-        let _ = tx.send(Event::EOF);
+        let _ = tx.send(Event::EOF).await;
         Ok(())
-    });
+    }));
 
-    while let Some(event) = rx.recv().await {
+    let slow_handler = config.slow_handler;
+    let max_deferrals = config.max_deferrals;
+    let mut queues: HashMap<String, UnboundedSender<Event<P, IP>>> = HashMap::new();
+    // What each handler task is doing, to name those aborted at shutdown
+    let running = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let mut next_task = 0;
+    // An interval is needed even if nothing is logged, the select skips it then
+    let throughput_interval = config.throughput_interval;
+    let period = throughput_interval.unwrap_or(Duration::from_secs(1));
+    let mut throughput_tick =
+        tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    throughput_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut throughput = throughput_interval.map(metrics::Throughput::new);
+    let handlers = config.max_handlers.map(|max| Arc::new(Semaphore::new(max)));
+    let mut handlers_per_source: HashMap<String, Arc<Semaphore>> = HashMap::new();
+    loop {
+        // Reap finished handlers as they go, so errors and panics surface
+        // right away rather than at shutdown
+        let event = tokio::select! {
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            Some(result) = join_set.join_next() => {
+                SPAN.sync_scope(span.clone(), || reap(result, config.panic_policy));
+                continue;
+            }
+            _ = throughput_tick.tick(), if throughput.is_some() => {
+                if let (Some(throughput), Some(interval)) = (&mut throughput, throughput_interval) {
+                    let rate = throughput.rate(std::time::Instant::now());
+                    SPAN.sync_scope(span.clone(), || {
+                        log!("throughput: {:.1} msgs/s over the last {:?}", rate, interval)
+                    });
+                }
+                continue;
+            }
+        };
+        run.events_taken.fetch_add(1, Ordering::Relaxed);
+        if let (Event::Message(_), Some(throughput)) = (&event, &mut throughput) {
+            throughput.record(std::time::Instant::now());
+        }
+        let eof = matches!(event, Event::EOF);
+        let ordered_src = match &event {
+            Event::Message(msg) if config.ordered_per_source && msg.body.in_reply_to.is_none() => {
+                Some(msg.src.clone())
+            }
+            _ => None,
+        };
+        if let Some(src) = ordered_src {
+            let event = match queues.get(&src) {
+                Some(queue) => match queue.send(event) {
+                    Result::Ok(()) => continue,
+                    Err(e) => e.0,
+                },
+                None => event,
+            };
+            let (queue, mut queued) = tokio::sync::mpsc::unbounded_channel();
+            let _ = queue.send(event);
+            queues.insert(src.clone(), queue);
+            next_task += 1;
+            let what = EventName::Queued { src: src.clone() };
+            let tracked = Tracked::new(&running, next_task, what);
+            let node_clone = node.clone();
+            let queue_span = span.clone();
+            let queue_handlers = handlers.clone();
+            let panic_policy = config.panic_policy;
+            join_set.spawn(SPAN.scope(span.clone(), async move {
+                let _tracked = tracked;
+                while let Some(event) = queued.recv().await {
+                    let span = request_span(&queue_span, &event);
+                    let _permits = acquire_all(queue_handlers.iter().cloned().collect()).await;
+                    // A task of its own, so a panic loses only this event and
+                    // not the rest of the queue
+                    let node = node_clone.clone();
+                    let handling = tokio::spawn(SPAN.scope(span.clone(), async move {
+                        handle_deferred(&*node, event, slow_handler, max_deferrals)
+                            .await
+                            .context("failed to handle event")
+                    }));
+                    let _abort = AbortOnDrop(handling.abort_handle());
+                    let result = handling.await;
+                    SPAN.sync_scope(span, || reap(result, panic_policy));
+                }
+                Ok(())
+            }));
+            continue;
+        }
+        // The source's permit first, so a flood waits on its own limit
+        // without holding any of the ones other sources need
+        let mut limits = Vec::new();
+        if let Event::Message(msg) = &event {
+            if msg.body.in_reply_to.is_none() {
+                if let Some(max) = config.max_handlers_per_source {
+                    let limit = handlers_per_source
+                        .entry(msg.src.clone())
+                        .or_insert_with(|| Arc::new(Semaphore::new(max)));
+                    limits.push(limit.clone());
+                }
+                limits.extend(handlers.clone());
+            }
+        }
+        next_task += 1;
+        let tracked = Tracked::new(&running, next_task, describe(&event));
         let node_clone = node.clone();
-        join_set.spawn(async move {
-            node_clone
-                .handle(event)
+        join_set.spawn(SPAN.scope(request_span(&span, &event), async move {
+            let _tracked = tracked;
+            let _permits = acquire_all(limits).await;
+            handle_deferred(&*node_clone, event, slow_handler, max_deferrals)
                 .await
                 .context("failed to handle event")?;
             Ok(())
-        });
+        }));
+        // Nothing but timer ticks can follow EOF, so stop once it's dispatched.
+        // Dropping `rx` also stops the timers.
+        if eof {
+            break;
+        }
     }
+    drop(rx);
+    drop(queues);
 
-    while let Some(_) = join_set.join_next().await {}
-
-    Ok(())
+    let drain = async {
+        while let Some(result) = join_set.join_next().await {
+            SPAN.sync_scope(span.clone(), || reap(result, config.panic_policy));
+        }
+    };
+    let drained = match config.shutdown_deadline {
+        Some(deadline) => tokio::time::timeout(deadline, drain).await.is_ok(),
+        None => {
+            drain.await;
+            true
+        }
+    };
+    if !drained {
+        SPAN.sync_scope(span.clone(), || {
+            for what in running.lock().unwrap().values() {
+                log!("shutdown deadline passed, aborting handling of {}", what);
+            }
+        });
+        join_set.shutdown().await;
+    }
+    if config.snapshot_at_exit {
+        let snapshot = node.snapshot().await;
+        SPAN.sync_scope(span.clone(), || log!("final snapshot: {}", snapshot));
+    }
+    if signalled.load(Ordering::SeqCst) {
+        return Ok(Stopped::Signalled);
+    }
+    Ok(Stopped::Finished)
 }
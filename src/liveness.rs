@@ -0,0 +1,40 @@
+//! Peer liveness, probed by periodic pings.
+//!
+//! A gossiping node pings its peers on a timer. A peer whose ping goes
+//! unanswered is marked down and left out of gossip, which would only pile up
+//! in its inbox, until it answers a ping again. Peers start out up, so gossip
+//! doesn't wait for the first round of pings.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::log;
+
+/// How often gossiping nodes ping their peers.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+pub struct Liveness {
+    down: HashSet<String>,
+}
+
+impl Liveness {
+    /// Records whether `peer` answered its latest ping. Changes are logged,
+    /// and returned.
+    pub fn record(&mut self, peer: &str, answered: bool) -> bool {
+        let changed = if answered {
+            self.down.remove(peer)
+        } else {
+            self.down.insert(peer.to_string())
+        };
+        if changed {
+            log!("{} is {}", peer, if answered { "up" } else { "down" });
+        }
+        changed
+    }
+
+    /// Whether `peer` answered its latest ping, or hasn't been pinged yet.
+    pub fn is_up(&self, peer: &str) -> bool {
+        !self.down.contains(peer)
+    }
+}
@@ -0,0 +1,51 @@
+//! Throughput of the event loop, so long runs show progress and stalls stand
+//! out in the logs. See [`Config::throughput_interval`](crate::Config::throughput_interval).
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Buckets a window is split into. Events leave the window a bucket at a time.
+const BUCKETS: u32 = 10;
+
+/// Counts events over a sliding window.
+#[derive(Debug)]
+pub struct Throughput {
+    window: Duration,
+    /// Start and event count of every bucket still in the window, oldest first.
+    buckets: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    pub fn new(window: Duration) -> Self {
+        assert!(!window.is_zero(), "empty window");
+        Self {
+            window,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// Counts an event that happened at `now`.
+    pub fn record(&mut self, now: Instant) {
+        self.expire(now);
+        match self.buckets.back_mut() {
+            Some((start, count)) if now < *start + self.window / BUCKETS => *count += 1,
+            _ => self.buckets.push_back((now, 1)),
+        }
+    }
+
+    /// Events per second over the window ending at `now`.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let events: u64 = self.buckets.iter().map(|(_, count)| count).sum();
+        events as f64 / self.window.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now.saturating_duration_since(*start) < self.window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
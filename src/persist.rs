@@ -0,0 +1,109 @@
+//! Opt-in persistence of node state to local disk.
+//!
+//! This is meant for testing recovery of a node whose process restarts, not as
+//! a replacement for the Maelstrom KV stores. It is enabled by pointing the
+//! `GLOMERS_STATE_DIR` environment variable at a directory; each node then
+//! keeps its state in `{dir}/{name}-{node_id}.json`.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::log;
+
+/// Environment variable naming the directory state files are kept in.
+pub const STATE_DIR_VAR: &str = "GLOMERS_STATE_DIR";
+
+/// How long the writer waits after a change before writing, so a burst of
+/// changes results in a single write.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// A node's state file.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// Returns the state file of node `node_id` of type `name`, or `None` if
+    /// persistence is not enabled.
+    pub fn from_env(name: &str, node_id: &str) -> Option<Self> {
+        let dir = std::env::var_os(STATE_DIR_VAR)?;
+        Some(Self::new(
+            PathBuf::from(dir).join(format!("{}-{}.json", name, node_id)),
+        ))
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Reads the state written by a previous run, if there is one.
+    pub fn load<T: DeserializeOwned>(&self) -> anyhow::Result<Option<T>> {
+        let raw = match std::fs::read(&self.path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read {}", self.path.display())),
+        };
+        let state = serde_json::from_slice(&raw)
+            .with_context(|| format!("deserialize {}", self.path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Spawns the background task that writes the node's state to disk and
+    /// returns the handle to tell it the state changed through. The task takes
+    /// the state from `snapshot` and serializes it itself, once per debounce,
+    /// so handlers never pay for it.
+    pub fn spawn_writer<T, F, Fut>(self, snapshot: F) -> StateWriter
+    where
+        T: Serialize,
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send,
+    {
+        let (tx, mut rx) = watch::channel(());
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                tokio::time::sleep(DEBOUNCE).await;
+                rx.borrow_and_update();
+                let raw = serde_json::to_vec(&snapshot().await);
+                let written = match raw {
+                    Ok(raw) => self.write(raw).await,
+                    Err(e) => Err(e).context("serialize state"),
+                };
+                if let Err(e) = written {
+                    log!("{:#}", e);
+                }
+            }
+        });
+        StateWriter { tx }
+    }
+
+    async fn write(&self, raw: Vec<u8>) -> anyhow::Result<()> {
+        // Write to a temporary file first so a crash never leaves a torn file
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, raw)
+            .await
+            .with_context(|| format!("write {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("rename to {}", self.path.display()))
+    }
+}
+
+/// Handle for telling the writer task a node's state changed. The task then
+/// writes the state after a short debounce.
+#[derive(Debug)]
+pub struct StateWriter {
+    tx: watch::Sender<()>,
+}
+
+impl StateWriter {
+    pub fn changed(&self) {
+        self.tx.send_replace(());
+    }
+}
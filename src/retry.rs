@@ -0,0 +1,125 @@
+//! Pacing of RPC retries.
+//!
+//! When a partition heals, every RPC that timed out during it retries at
+//! once. Retries therefore draw from one token bucket per node, whose rate is
+//! set from [`Config::retry_rate`](crate::Config::retry_rate). First attempts
+//! are never paced.
+//!
+//! Each retry loop also backs off on its own, with a [`Backoff`], so that a
+//! peer or store that keeps failing is tried ever more rarely.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{Output, Rng};
+
+/// A token bucket: `acquire` takes a token, waiting for one to be refilled if
+/// the bucket is empty.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: f64,
+    /// Most tokens the bucket holds, i.e. the largest burst let through.
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a full bucket letting `rate` acquisitions per second through,
+    /// in bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "rate must be positive");
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refill = (now - bucket.refilled_at).as_secs_f64() * self.rate;
+                bucket.tokens = (bucket.tokens + refill).min(self.burst);
+                bucket.refilled_at = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Waits until a retry through `out` may be sent. Returns right away if the
+/// node's retries aren't rate limited.
+pub async fn pace(out: &Output) {
+    if let Some(limiter) = out.run().retries.get() {
+        limiter.acquire().await;
+    }
+}
+
+/// Exponential backoff with jitter: the `n`th delay is `base * 2^n`, capped at
+/// `cap`, and then shortened by a random fraction of up to `jitter` of it, so
+/// that loops that started failing together spread out.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    jitter: f64,
+    rng: Rng,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// `jitter` is clamped to `0.0..=1.0`; zero makes the delays exact.
+    pub fn new(base: Duration, cap: Duration, jitter: f64, rng: Rng) -> Self {
+        Self {
+            base,
+            cap,
+            jitter: jitter.clamp(0.0, 1.0),
+            rng,
+            attempt: 0,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .checked_mul(1 << self.attempt.min(31))
+            .map_or(self.cap, |delay| delay.min(self.cap));
+        if delay < self.cap {
+            self.attempt += 1;
+        }
+        // 53 random bits make a uniform fraction in `0.0..1.0`
+        let fraction = (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        delay.mul_f64(1.0 - self.jitter * fraction)
+    }
+
+    /// Waits out [`next_delay`](Self::next_delay).
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    /// Starts over from `base`, e.g. once an attempt succeeded.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -0,0 +1,243 @@
+//! Bookkeeping of the RPCs a node is waiting on.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use tokio::sync::oneshot;
+use tokio::time::{Instant, Sleep};
+
+use serde::Serialize;
+
+use crate::{log, Body, Message, Output, RunState};
+
+/// How long an RPC waits in a debug build before checking the event loop
+/// still takes in input, see [`PendingRpc::warn_deadlock_after`].
+pub const DEADLOCK_WARNING_AFTER: Duration = Duration::from_secs(1);
+
+/// The RPCs a node sent and still waits on, by message id.
+///
+/// [`register`](Self::register) hands out a fresh message id along with a
+/// receiver for the reply, and the node's
+/// [`Node::handle_reply`](crate::Node::handle_reply) passes replies on to
+/// [`resolve`](Self::resolve). Replies to ids nobody waits on anymore are
+/// dropped.
+#[derive(Debug)]
+pub struct PendingRpc<P> {
+    ids: AtomicUsize,
+    pending: Mutex<HashMap<usize, Pending<P>>>,
+    deadlock_warning: Option<Duration>,
+    suspected_deadlocks: Arc<AtomicUsize>,
+    run: Arc<RunState>,
+}
+
+#[derive(Debug)]
+struct Pending<P> {
+    registered_at: Instant,
+    tx: oneshot::Sender<Message<P>>,
+}
+
+impl<P> PendingRpc<P> {
+    /// Creates the bookkeeping for the RPCs a node sends through `out`, whose
+    /// event loop is the one checked for a deadlock.
+    pub fn new(out: &Output) -> Self {
+        Self {
+            ids: AtomicUsize::new(1),
+            pending: Mutex::new(HashMap::new()),
+            deadlock_warning: cfg!(debug_assertions).then_some(DEADLOCK_WARNING_AFTER),
+            suspected_deadlocks: Arc::new(AtomicUsize::new(0)),
+            run: out.run().clone(),
+        }
+    }
+
+    /// Warns loudly when an RPC has waited `after` for its reply while the
+    /// event loop took in no input at all: the reply can't be routed then,
+    /// which is what happens when a handler awaiting an RPC is run inline
+    /// instead of in a task of its own. `None` turns the check off; debug
+    /// builds check after [`DEADLOCK_WARNING_AFTER`], release builds don't.
+    pub fn warn_deadlock_after(mut self, after: Option<Duration>) -> Self {
+        self.deadlock_warning = after;
+        self
+    }
+
+    /// How many RPCs warned of a deadlock so far.
+    pub fn suspected_deadlocks(&self) -> usize {
+        self.suspected_deadlocks.load(Ordering::Relaxed)
+    }
+
+    /// The counter `register` draws message ids from. A node's other messages
+    /// should draw their ids from it too, e.g. through
+    /// [`Message::into_reply`], so that no two of them share one.
+    pub fn ids(&self) -> &AtomicUsize {
+        &self.ids
+    }
+
+    /// Returns a fresh message id, for a message that isn't an RPC.
+    pub fn next_id(&self) -> usize {
+        // `fetch_add` never hands out a value twice, whatever the ordering.
+        // Nothing else is published through the counter, so `Relaxed` will do.
+        self.ids.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns a fresh message id for an RPC, and the [`Reply`] its reply
+    /// will be handed to.
+    pub fn register(&self) -> (usize, Reply<P>) {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        let pending = Pending {
+            registered_at: Instant::now(),
+            tx,
+        };
+        self.pending.lock().unwrap().insert(id, pending);
+        let reply = Reply {
+            id,
+            rx,
+            check: self
+                .deadlock_warning
+                .map(|after| (Instant::now() + after, self.run.events_taken())),
+            sleep: None,
+            suspected: self.suspected_deadlocks.clone(),
+            run: self.run.clone(),
+        };
+        (id, reply)
+    }
+
+    /// Hands `reply` to the RPC waiting on `id`, returning whether there was
+    /// one. Replies to unknown or cancelled ids are dropped.
+    pub fn resolve(&self, id: usize, reply: Message<P>) -> bool {
+        let Some(pending) = self.pending.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        pending.tx.send(reply).is_ok()
+    }
+
+    /// Hands `reply` to the RPC waiting on the id it answers, dropping it if
+    /// there is none, e.g. because the RPC timed out. Meant to be all of a
+    /// node's [`Node::handle_reply`](crate::Node::handle_reply).
+    pub fn resolve_reply(&self, reply: Message<P>) -> anyhow::Result<()> {
+        let id = reply
+            .body
+            .in_reply_to
+            .context("reply without in_reply_to")?;
+        self.resolve(id, reply);
+        Ok(())
+    }
+
+    /// Stops waiting on `id`, e.g. once its RPC timed out.
+    pub fn cancel(&self, id: usize) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    /// Stops waiting on every RPC. Their receivers see the sender dropped.
+    pub fn cancel_all(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Stops waiting on RPCs registered more than `ttl` ago, which their
+    /// callers most likely gave up on without cancelling, say because the
+    /// task awaiting them was aborted. Returns how many there were.
+    pub fn sweep(&self, ttl: Duration) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|_, rpc| rpc.registered_at.elapsed() <= ttl);
+        before - pending.len()
+    }
+
+    /// How many RPCs are waiting on their reply.
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sends `payload` from `src` to `dest` without a message id, for messages
+/// such as gossip that no reply is expected to: nothing is registered with a
+/// [`PendingRpc`] that would never be resolved.
+pub async fn send_oneway<P: Serialize>(
+    stdout: &Output,
+    src: &str,
+    dest: &str,
+    payload: P,
+) -> anyhow::Result<()> {
+    let msg = Message {
+        src: src.to_string(),
+        dest: dest.to_string(),
+        body: Body {
+            id: None,
+            in_reply_to: None,
+            payload,
+        },
+    };
+    msg.send(stdout).await.context("send oneway message")
+}
+
+/// Sends `payload` to each of `peers` concurrently through `rpc`, a node's
+/// own request-reply call, and folds the replies with `combine`. Peers whose
+/// `rpc` fails, e.g. because it timed out, are left out of the fold.
+pub async fn query_peers<'a, P, R, Fut>(
+    peers: &'a [String],
+    payload: P,
+    rpc: impl Fn(&'a str, P) -> Fut,
+    combine: impl FnOnce(Vec<Message<P>>) -> R,
+) -> R
+where
+    P: Clone,
+    Fut: Future<Output = anyhow::Result<Message<P>>>,
+{
+    let queries = peers.iter().map(|peer| rpc(peer, payload.clone()));
+    let replies = crate::join_all(queries).await;
+    combine(replies.into_iter().filter_map(Result::ok).collect())
+}
+
+/// Resolves to the reply of an RPC from [`PendingRpc::register`], or to an
+/// error once the RPC is cancelled.
+#[derive(Debug)]
+pub struct Reply<P> {
+    id: usize,
+    rx: oneshot::Receiver<Message<P>>,
+    /// When to check for a deadlock, and how many events the event loop had
+    /// taken in when the RPC was sent.
+    check: Option<(Instant, usize)>,
+    /// Wakes the RPC to check, made once it is first awaited, since only then
+    /// is there a runtime to make it in.
+    sleep: Option<Pin<Box<Sleep>>>,
+    suspected: Arc<AtomicUsize>,
+    run: Arc<RunState>,
+}
+
+impl<P> Future for Reply<P> {
+    type Output = Result<Message<P>, oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(reply) = Pin::new(&mut self.rx).poll(cx) {
+            return Poll::Ready(reply);
+        }
+        if let Some((at, taken)) = self.check {
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(at)));
+            if sleep.as_mut().poll(cx).is_ready() {
+                if self.run.events_taken() == taken {
+                    self.suspected.fetch_add(1, Ordering::Relaxed);
+                    log!(
+                        "DEADLOCK? RPC {} got no reply and the event loop took in no input \
+                         since it was sent; a handler awaiting an RPC must run in its own \
+                         task, not inline in the event loop, or the reply is never routed",
+                        self.id
+                    );
+                }
+                self.check = None;
+                self.sleep = None;
+            }
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,151 @@
+//! Optimistic transactions over a versioned map.
+//!
+//! A transaction runs in two steps. [`TxnStore::prepare`] runs its operations
+//! against the current state, buffering the writes and noting the version of
+//! every key it reads or writes. [`TxnStore::commit`] then applies the writes,
+//! unless another transaction committed a write to one of those keys in
+//! between, in which case the transaction is aborted and nothing of it is
+//! applied. Checking the keys read too keeps transactions serializable, e.g.
+//! of `r x, w y` and `r y, w x` run concurrently only one commits. The store
+//! can be unlocked between the two steps, so transactions only hold each other
+//! up while committing.
+//!
+//! Writes committed elsewhere come in through [`TxnStore::merge`], which
+//! bumps the versions of the keys they touch just like a local commit, so a
+//! transaction prepared before such a merge conflicts too.
+
+use std::collections::HashMap;
+
+/// A Maelstrom transaction operation: `["r", key, null]` or `["w", key, value]`.
+/// Reads are answered by filling in the value.
+pub type Operation = (String, u32, Option<u32>);
+
+#[derive(Debug, Default)]
+pub struct TxnStore {
+    /// Value and version of each key. A key that was never written is at
+    /// version 0.
+    values: HashMap<u32, (u32, u64)>,
+}
+
+/// A transaction that has run but not yet committed, see [`TxnStore::prepare`].
+#[derive(Debug)]
+pub struct Prepared {
+    ops: Vec<Operation>,
+    writes: HashMap<u32, u32>,
+    /// Version of every key read from the store or written when the
+    /// transaction ran.
+    versions: HashMap<u32, u64>,
+}
+
+impl Prepared {
+    /// Every key the transaction reads from the store or writes.
+    pub fn keys(&self) -> impl Iterator<Item = u32> + '_ {
+        self.versions.keys().copied()
+    }
+
+    /// The keys the transaction writes.
+    pub fn written(&self) -> impl Iterator<Item = u32> + '_ {
+        self.writes.keys().copied()
+    }
+}
+
+/// Returned by [`TxnStore::commit`] when a key the transaction reads or writes
+/// was written by another transaction since it was prepared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub key: u32,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "key {} was written by a concurrent transaction",
+            self.key
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+impl TxnStore {
+    pub fn get(&self, key: u32) -> Option<u32> {
+        self.values.get(&key).map(|(value, _)| *value)
+    }
+
+    fn version(&self, key: u32) -> u64 {
+        self.values.get(&key).map_or(0, |(_, version)| *version)
+    }
+
+    /// The value and version of each of `keys` that was ever written, as
+    /// `(key, value, version)`.
+    pub fn entries(&self, keys: impl IntoIterator<Item = u32>) -> Vec<(u32, u32, u64)> {
+        keys.into_iter()
+            .filter_map(|key| {
+                let (value, version) = self.values.get(&key)?;
+                Some((key, *value, *version))
+            })
+            .collect()
+    }
+
+    /// Takes `value` as written at `version` to `key` elsewhere, if that is
+    /// newer than what the store has. Returns whether it was.
+    pub fn merge(&mut self, key: u32, value: u32, version: u64) -> bool {
+        if version <= self.version(key) {
+            return false;
+        }
+        self.values.insert(key, (value, version));
+        true
+    }
+
+    /// Runs `txn` against the current state without changing it. Reads see
+    /// the transaction's own earlier writes. Operations other than `r` and `w`
+    /// are dropped.
+    pub fn prepare(&self, txn: Vec<Operation>) -> Prepared {
+        let mut prepared = Prepared {
+            ops: Vec::with_capacity(txn.len()),
+            writes: HashMap::new(),
+            versions: HashMap::new(),
+        };
+        for (f, key, value) in txn {
+            match f.as_str() {
+                "r" => {
+                    let value = match prepared.writes.get(&key) {
+                        Some(value) => Some(*value),
+                        None => {
+                            prepared.versions.entry(key).or_insert(self.version(key));
+                            self.get(key)
+                        }
+                    };
+                    prepared.ops.push((f, key, value));
+                }
+                "w" => {
+                    if let Some(value) = value {
+                        prepared.writes.insert(key, value);
+                        prepared.versions.entry(key).or_insert(self.version(key));
+                    }
+                    prepared.ops.push((f, key, value));
+                }
+                _ => {}
+            }
+        }
+        prepared
+    }
+
+    /// Applies the writes of `prepared` and returns its completed operations,
+    /// or fails without applying any of them if it conflicts.
+    pub fn commit(&mut self, prepared: Prepared) -> Result<Vec<Operation>, Conflict> {
+        if let Some((&key, _)) = prepared
+            .versions
+            .iter()
+            .find(|(&key, &version)| self.version(key) != version)
+        {
+            return Err(Conflict { key });
+        }
+        for (key, value) in prepared.writes {
+            let version = self.version(key) + 1;
+            self.values.insert(key, (value, version));
+        }
+        Ok(prepared.ops)
+    }
+}
@@ -0,0 +1,1722 @@
+//! End-to-end tests that drive the compiled binaries over stdin/stdout the way
+//! Maelstrom does, without needing Maelstrom itself.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+/// A node binary running as a child process.
+struct TestNode {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    node_id: String,
+    next_id: u64,
+}
+
+impl TestNode {
+    /// Starts `bin` and initializes it as `node_id` of a cluster of `node_ids`.
+    fn start(bin: &str, node_id: &str, node_ids: &[&str]) -> Self {
+        Self::start_with_stderr(bin, node_id, node_ids, Stdio::null())
+    }
+
+    /// Like `start`, with the node's stderr going to `stderr`.
+    fn start_with_stderr(bin: &str, node_id: &str, node_ids: &[&str], stderr: Stdio) -> Self {
+        Self::start_with(bin, node_id, node_ids, stderr, &[])
+    }
+
+    /// Like `start_with_stderr`, with the environment variables `env` set.
+    fn start_with(
+        bin: &str,
+        node_id: &str,
+        node_ids: &[&str],
+        stderr: Stdio,
+        env: &[(&str, &str)],
+    ) -> Self {
+        let mut child = Command::new(bin)
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .spawn()
+            .expect("spawn node binary");
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+        let mut node = Self {
+            child,
+            stdin,
+            stdout,
+            node_id: node_id.to_string(),
+            next_id: 1,
+        };
+        let reply = node.rpc(json!({
+            "type": "init",
+            "node_id": node_id,
+            "node_ids": node_ids,
+        }));
+        assert_eq!(reply["body"]["type"], "init_ok");
+        node
+    }
+
+    /// Sends `body` from `src`, with a fresh message id if it has none.
+    /// Returns the message id.
+    fn send(&mut self, src: &str, mut body: Value) -> Value {
+        if body.get("msg_id").is_none() && body.get("in_reply_to").is_none() {
+            body["msg_id"] = self.next_id.into();
+            self.next_id += 1;
+        }
+        let msg = json!({ "src": src, "dest": self.node_id, "body": body });
+        let stdin = self.stdin.as_mut().expect("stdin still open");
+        writeln!(stdin, "{}", msg).expect("write to node");
+        stdin.flush().expect("flush node stdin");
+        msg["body"]["msg_id"].clone()
+    }
+
+    /// Returns the next message the node sends that matches `pred`, skipping
+    /// the others.
+    fn recv(&mut self, pred: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let mut line = String::new();
+            let read = self.stdout.read_line(&mut line).expect("read from node");
+            assert_ne!(read, 0, "node exited while a message was expected");
+            let msg: Value = serde_json::from_str(&line).expect("message is JSON");
+            assert_eq!(msg["src"], self.node_id);
+            if pred(&msg) {
+                return msg;
+            }
+        }
+    }
+
+    /// Sends `body` from client `c1` and returns the reply to it.
+    fn rpc(&mut self, body: Value) -> Value {
+        let id = self.send("c1", body);
+        self.recv(|msg| msg["dest"] == "c1" && msg["body"]["in_reply_to"] == id)
+    }
+
+    /// Like `rpc`, but answers the KV requests the node makes in the meantime
+    /// from `kv`.
+    fn rpc_with_kv(&mut self, kv: &mut FakeKv, body: Value) -> Value {
+        let id = self.send("c1", body);
+        self.reply_with_kv(kv, &id)
+    }
+
+    /// Returns the reply to the client request `id`, answering the KV
+    /// requests the node makes in the meantime from `kv`.
+    fn reply_with_kv(&mut self, kv: &mut FakeKv, id: &Value) -> Value {
+        loop {
+            let msg = self.recv(|_| true);
+            if FakeKv::serves(&msg) {
+                kv.answer(self, &msg);
+            } else if msg["dest"] == "c1" && msg["body"]["in_reply_to"] == *id {
+                return msg;
+            }
+        }
+    }
+
+    /// Closes stdin and waits for the node to exit.
+    fn finish(self) {
+        self.finish_with_stderr();
+    }
+
+    /// Like `finish`, returning what the node logged if its stderr was piped.
+    fn finish_with_stderr(mut self) -> String {
+        drop(self.stdin.take());
+        let mut logs = String::new();
+        if let Some(mut stderr) = self.child.stderr.take() {
+            stderr.read_to_string(&mut logs).expect("read node stderr");
+        }
+        let status = self.child.wait().expect("wait for node");
+        assert!(status.success(), "node exited with {}", status);
+        logs
+    }
+}
+
+/// In-memory stand-in for Maelstrom's `lin-kv` and `seq-kv` services.
+#[derive(Default)]
+struct FakeKv {
+    values: HashMap<(String, String), Value>,
+    /// Number of `read`s served, per service.
+    reads: HashMap<String, usize>,
+    /// Values `seq-kv` reads still return in place of newer writes, if it is
+    /// modelled as lagging behind. A key's first write stays invisible.
+    stale: Option<HashMap<String, Option<Value>>>,
+    /// How many more requests to answer as temporarily unavailable, like a
+    /// store that is still starting.
+    unavailable: usize,
+}
+
+impl FakeKv {
+    fn serves(msg: &Value) -> bool {
+        matches!(msg["dest"].as_str(), Some("lin-kv" | "seq-kv"))
+    }
+
+    /// Answers the KV request `msg` that `node` sent.
+    fn answer(&mut self, node: &mut TestNode, msg: &Value) {
+        let service = msg["dest"].as_str().expect("dest is a string").to_string();
+        let body = &msg["body"];
+        if self.unavailable > 0 {
+            self.unavailable -= 1;
+            let reply = json!({
+                "type": "error",
+                "code": 11,
+                "text": "starting",
+                "in_reply_to": body["msg_id"],
+            });
+            node.send(&service, reply);
+            return;
+        }
+        let key = (
+            service.clone(),
+            body["key"].as_str().expect("key").to_string(),
+        );
+        if let (Some(stale), "seq-kv", Some("write" | "cas")) =
+            (&mut self.stale, service.as_str(), body["type"].as_str())
+        {
+            stale
+                .entry(key.1.clone())
+                .or_insert_with(|| self.values.get(&key).cloned());
+        }
+        let stale = match (&self.stale, service.as_str()) {
+            (Some(stale), "seq-kv") => stale.get(&key.1).cloned(),
+            _ => None,
+        };
+        let mut reply = match body["type"].as_str() {
+            Some("read") => {
+                *self.reads.entry(service.clone()).or_default() += 1;
+                match stale.unwrap_or_else(|| self.values.get(&key).cloned()) {
+                    Some(value) => json!({ "type": "read_ok", "value": value }),
+                    None => json!({ "type": "error", "code": 20, "text": "not found" }),
+                }
+            }
+            Some("write") => {
+                self.values.insert(key, body["value"].clone());
+                json!({ "type": "write_ok" })
+            }
+            Some("cas") => match self.values.get(&key) {
+                Some(value) if *value == body["from"] => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({ "type": "cas_ok" })
+                }
+                Some(_) => json!({ "type": "error", "code": 22, "text": "mismatch" }),
+                None if body["create_if_not_exists"] == true => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({ "type": "cas_ok" })
+                }
+                None => json!({ "type": "error", "code": 20, "text": "not found" }),
+            },
+            other => panic!("unexpected KV request {:?}", other),
+        };
+        reply["in_reply_to"] = body["msg_id"].clone();
+        node.send(&service, reply);
+    }
+}
+
+/// Returns an empty directory, for `GLOMERS_STATE_DIR`, unique to `test`.
+fn state_dir(test: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("glomers-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("create state dir");
+    dir
+}
+
+/// Longer than the state writer's debounce, so state saved before it is on
+/// disk after it.
+const STATE_WRITTEN: Duration = Duration::from_millis(300);
+
+#[test]
+fn echo_replies_with_the_echoed_value() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_echo"), "n1", &["n1"]);
+    for echo in ["hello", "world"] {
+        let reply = node.rpc(json!({ "type": "echo", "echo": echo }));
+        assert_eq!(reply["body"]["type"], "echo_ok");
+        assert_eq!(reply["body"]["echo"], echo);
+    }
+    node.finish();
+}
+
+#[test]
+fn nodes_log_a_banner_with_their_id_and_peer_count() {
+    let node = TestNode::start_with_stderr(
+        env!("CARGO_BIN_EXE_echo"),
+        "n2",
+        &["n1", "n2", "n3"],
+        Stdio::piped(),
+    );
+    let logs = node.finish_with_stderr();
+    assert!(
+        logs.contains("echo n2 started with 2 peers, version "),
+        "logs: {}",
+        logs
+    );
+}
+
+#[test]
+fn log_lines_and_the_final_snapshot_are_tagged_with_the_node_name() {
+    let mut node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_broadcast"),
+        "n1",
+        &["n1", "n2"],
+        Stdio::piped(),
+        &[("GLOMERS_SNAPSHOT_AT_EXIT", "1")],
+    );
+    node.send("n9", json!({ "type": "gossip", "seen": [1], "round": 1 }));
+    node.rpc(json!({ "type": "read" }));
+    let logs = node.finish_with_stderr();
+    for line in logs.lines().filter(|line| !line.is_empty()) {
+        assert!(line.starts_with("[broadcast n1"), "untagged line: {}", line);
+    }
+    assert!(
+        logs.contains("[broadcast n1 n9#") && logs.contains("ignoring gossip from unknown node n9"),
+        "logs: {}",
+        logs
+    );
+    assert!(
+        logs.contains("[broadcast n1] final snapshot: "),
+        "logs: {}",
+        logs
+    );
+}
+
+#[test]
+fn help_prints_usage_and_exits_without_reading_stdin() {
+    for (bin, name) in [
+        (env!("CARGO_BIN_EXE_echo"), "echo"),
+        (env!("CARGO_BIN_EXE_kafka"), "kafka"),
+    ] {
+        let mut child = Command::new(bin)
+            .arg("--help")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("spawn node binary");
+        // Stdin stays open and empty, so a node reading it would never exit
+        let stdin = child.stdin.take();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let status = loop {
+            if let Some(status) = child.try_wait().expect("poll node") {
+                break status;
+            }
+            assert!(Instant::now() < deadline, "{} --help kept running", name);
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        drop(stdin);
+        assert!(status.success(), "{} exited with {}", name, status);
+        let mut usage = String::new();
+        child
+            .stderr
+            .take()
+            .expect("piped stderr")
+            .read_to_string(&mut usage)
+            .expect("read node stderr");
+        assert!(
+            usage.starts_with(&format!("{}: a Maelstrom node", name)),
+            "usage: {}",
+            usage
+        );
+        assert!(usage.contains("--node-id"), "usage: {}", usage);
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .expect("piped stdout")
+            .read_to_string(&mut out)
+            .expect("read node stdout");
+        assert_eq!(out, "", "nothing goes to stdout");
+    }
+}
+
+#[test]
+fn nodes_shut_down_cleanly_on_sigterm_with_stdin_still_open() {
+    let mut node =
+        TestNode::start_with_stderr(env!("CARGO_BIN_EXE_echo"), "n1", &["n1"], Stdio::piped());
+    // Once a request has been answered the node is waiting on stdin, with
+    // its signal handler in place
+    node.rpc(json!({ "type": "echo", "echo": "hello" }));
+    std::thread::sleep(Duration::from_millis(100));
+    let status = Command::new("kill")
+        .args(["-TERM", &node.child.id().to_string()])
+        .status()
+        .expect("run kill");
+    assert!(status.success());
+    let mut logs = String::new();
+    if let Some(mut stderr) = node.child.stderr.take() {
+        stderr.read_to_string(&mut logs).expect("read node stderr");
+    }
+    let status = node.child.wait().expect("wait for node");
+    assert!(status.success(), "node exited with {}", status);
+    assert!(logs.contains("received shutdown signal"), "logs: {}", logs);
+}
+
+#[test]
+fn broadcast_reads_back_broadcast_messages() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1"]);
+    let reply = node.rpc(json!({ "type": "topology", "topology": { "n1": [] } }));
+    assert_eq!(reply["body"]["type"], "topology_ok");
+    for message in [3, 1, 2] {
+        let reply = node.rpc(json!({ "type": "broadcast", "message": message }));
+        assert_eq!(reply["body"]["type"], "broadcast_ok");
+    }
+    let reply = node.rpc(json!({ "type": "read" }));
+    assert_eq!(reply["body"]["type"], "read_ok");
+    let mut messages: Vec<u64> = serde_json::from_value(reply["body"]["messages"].clone())
+        .expect("messages is a list of numbers");
+    messages.sort_unstable();
+    assert_eq!(messages, [1, 2, 3]);
+    node.finish();
+}
+
+#[test]
+fn broadcast_accepts_msg_as_well_as_message() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1"]);
+    for body in [
+        json!({ "type": "broadcast", "message": 1 }),
+        json!({ "type": "broadcast", "msg": 2 }),
+    ] {
+        let reply = node.rpc(body);
+        assert_eq!(reply["body"]["type"], "broadcast_ok");
+    }
+    // Replies use the spelling of the protocol
+    let reply = node.rpc(json!({ "type": "read" }));
+    assert_eq!(reply["body"]["messages"], json!([1, 2]));
+    assert!(reply["body"].get("msgs").is_none(), "sent {}", reply);
+    node.finish();
+}
+
+#[test]
+fn broadcast_reloads_the_messages_it_saved_before_a_restart() {
+    let dir = state_dir("broadcast");
+    let env = [("GLOMERS_STATE_DIR", dir.to_str().unwrap())];
+    let start = || {
+        TestNode::start_with(
+            env!("CARGO_BIN_EXE_broadcast"),
+            "n1",
+            &["n1"],
+            Stdio::null(),
+            &env,
+        )
+    };
+    let mut node = start();
+    for message in [3, 1, 2] {
+        node.rpc(json!({ "type": "broadcast", "message": message }));
+    }
+    std::thread::sleep(STATE_WRITTEN);
+    node.finish();
+
+    let mut node = start();
+    let reply = node.rpc(json!({ "type": "read" }));
+    let mut messages: Vec<u64> = serde_json::from_value(reply["body"]["messages"].clone())
+        .expect("messages is a list of numbers");
+    messages.sort_unstable();
+    assert_eq!(messages, [1, 2, 3]);
+    node.finish();
+}
+
+#[test]
+fn single_node_broadcast_sends_nothing_but_replies() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1"]);
+    assert_eq!(
+        node.rpc(json!({ "type": "topology", "topology": { "n1": [] } }))["body"]["type"],
+        "topology_ok"
+    );
+    node.rpc(json!({ "type": "broadcast", "message": 7 }));
+    // Long enough for a couple of gossip ticks, if there were any
+    std::thread::sleep(Duration::from_millis(1100));
+    let read = node.send("c1", json!({ "type": "read" }));
+    let reply = node.recv(|msg| {
+        assert_eq!(msg["dest"], "c1", "sent {}", msg);
+        msg["body"]["in_reply_to"] == read
+    });
+    assert_eq!(reply["body"]["messages"], json!([7]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_forwards_to_every_neighbor_but_the_sender() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2", "n3"]);
+    node.rpc(json!({ "type": "topology", "topology": {
+        "n1": ["n2", "n3"], "n2": ["n1"], "n3": ["n1"],
+    }}));
+    node.send("n2", json!({ "type": "broadcast", "message": 9 }));
+    let forward = node.recv(|msg| msg["body"]["type"] == "broadcast");
+    assert_eq!(forward["src"], "n1");
+    assert_eq!(forward["dest"], "n3");
+    assert_eq!(forward["body"]["message"], 9);
+    node.send(
+        "n3",
+        json!({ "type": "broadcast_ok", "in_reply_to": forward["body"]["msg_id"] }),
+    );
+    // Nothing was forwarded back to n2
+    let read = node.send("c1", json!({ "type": "read" }));
+    let reply = node.recv(|msg| {
+        assert!(
+            msg["dest"] != "n2" || msg["body"]["type"] != "broadcast",
+            "sent {}",
+            msg
+        );
+        msg["body"]["in_reply_to"] == read
+    });
+    assert_eq!(reply["body"]["messages"], json!([9]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_retries_a_forward_that_was_dropped() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    node.rpc(json!({ "type": "broadcast", "message": 4 }));
+    let mut forwards = Vec::new();
+    let retry = loop {
+        let msg = node.recv(|msg| msg["dest"] == "n2");
+        match msg["body"]["type"].as_str() {
+            // n2 is up, it just lost the first forward
+            Some("ping") => {
+                node.send(
+                    "n2",
+                    json!({ "type": "ping_ok", "in_reply_to": msg["body"]["msg_id"] }),
+                );
+            }
+            Some("broadcast") => {
+                assert_eq!(msg["body"]["message"], 4);
+                forwards.push(msg["body"]["msg_id"].clone());
+                if forwards.len() == 2 {
+                    break msg;
+                }
+            }
+            _ => {}
+        }
+    };
+    assert_ne!(forwards[0], forwards[1], "a retry is a new RPC");
+    node.send(
+        "n2",
+        json!({ "type": "broadcast_ok", "in_reply_to": retry["body"]["msg_id"] }),
+    );
+    let reply = node.rpc(json!({ "type": "read" }));
+    assert_eq!(reply["body"]["messages"], json!([4]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_ignores_gossip_from_an_unknown_node() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    for body in [
+        json!({ "type": "gossip", "seen": [7], "round": 1 }),
+        json!({ "type": "gossip_ok", "seen": [7], "in_reply_to": 1 }),
+    ] {
+        node.send("n9", body);
+    }
+    // The node is still up, and took nothing from n9
+    let reply = node.rpc(json!({ "type": "read" }));
+    assert_eq!(reply["body"]["messages"], json!([]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_routes_gossip_around_a_neighbor_that_is_down() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2", "n3"]);
+    // n3 is only reachable through n2
+    node.rpc(json!({ "type": "topology", "topology": {
+        "n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"],
+    }}));
+    node.rpc(json!({ "type": "broadcast", "message": 5 }));
+    // n2 answers nothing, so the first ping marks it down and the next
+    // gossip tick goes to n3 directly
+    let mut pinged = false;
+    let detour = loop {
+        let msg = node.recv(|_| true);
+        if msg["dest"] == "n3" {
+            break msg;
+        }
+        pinged |= msg["body"]["type"] == "ping";
+    };
+    assert!(pinged, "gossip went to n3 while n2 was up");
+    assert_eq!(detour["body"]["type"], "gossip");
+    assert_eq!(detour["body"]["seen"], json!([5]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_gossips_to_only_the_configured_number_of_neighbors() {
+    let nodes = ["n1", "n2", "n3", "n4"];
+    let mut node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_broadcast"),
+        "n3",
+        &nodes,
+        Stdio::null(),
+        &[("GLOMERS_GOSSIP_NEIGHBORS", "2")],
+    );
+    // Before the topology, so there's no neighbor to forward it to
+    node.rpc(json!({ "type": "broadcast", "message": 5 }));
+    let topology: HashMap<&str, Vec<&str>> = nodes
+        .iter()
+        .map(|node| {
+            (
+                *node,
+                nodes.iter().filter(|n| *n != node).copied().collect(),
+            )
+        })
+        .collect();
+    node.rpc(json!({ "type": "topology", "topology": topology }));
+    // n1 is n3's parent in the spanning tree and n2 its nearest other neighbor
+    let mut gossiped = HashSet::new();
+    for _ in 0..8 {
+        let msg = node.recv(|msg| matches!(msg["body"]["type"].as_str(), Some("gossip" | "ping")));
+        if msg["body"]["type"] == "ping" {
+            let dest = msg["dest"].as_str().unwrap().to_string();
+            node.send(
+                &dest,
+                json!({ "type": "ping_ok", "in_reply_to": msg["body"]["msg_id"] }),
+            );
+            continue;
+        }
+        gossiped.insert(msg["dest"].as_str().unwrap().to_string());
+    }
+    assert_eq!(
+        gossiped,
+        HashSet::from(["n1".to_string(), "n2".to_string()])
+    );
+    node.finish();
+}
+
+#[test]
+fn durable_broadcasts_are_acked_only_once_a_neighbor_has_the_value() {
+    let mut node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_broadcast"),
+        "n1",
+        &["n1", "n2"],
+        Stdio::null(),
+        &[("GLOMERS_DURABLE_ACKS", "1")],
+    );
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    let id = node.send("c1", json!({ "type": "broadcast", "message": 5 }));
+    // Play an n2 that is up but holds on to its ack of the forward
+    let answer_pings = |node: &mut TestNode, msg: &Value| {
+        if msg["body"]["type"] == "ping" {
+            node.send(
+                "n2",
+                json!({ "type": "ping_ok", "in_reply_to": msg["body"]["msg_id"] }),
+            );
+        }
+    };
+    let forward = loop {
+        let msg = node.recv(|_| true);
+        assert_ne!(msg["dest"], "c1", "acked before n2 had it: {}", msg);
+        answer_pings(&mut node, &msg);
+        if msg["dest"] == "n2" && msg["body"]["type"] == "broadcast" {
+            break msg;
+        }
+    };
+    // Anything the node sent before answering this read comes first
+    let read = node.send("c2", json!({ "type": "read" }));
+    loop {
+        let msg = node.recv(|_| true);
+        if msg["dest"] == "c2" && msg["body"]["in_reply_to"] == read {
+            break;
+        }
+        assert_ne!(msg["dest"], "c1", "acked before n2 had it: {}", msg);
+        answer_pings(&mut node, &msg);
+    }
+    node.send(
+        "n2",
+        json!({ "type": "broadcast_ok", "in_reply_to": forward["body"]["msg_id"] }),
+    );
+    let reply = node.recv(|msg| msg["dest"] == "c1" && msg["body"]["in_reply_to"] == id);
+    assert_eq!(reply["body"]["type"], "broadcast_ok");
+    node.finish();
+}
+
+#[test]
+fn broadcast_never_reuses_a_message_id() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2", "n3"]);
+    let topology = json!({ "n1": ["n2", "n3"], "n2": ["n1"], "n3": ["n1"] });
+    let mut ids = HashSet::new();
+    let mut note = |msg: &Value| {
+        if let Some(id) = msg["body"]["msg_id"].as_u64() {
+            assert!(ids.insert(id), "msg_id {} reused: {}", id, msg);
+        }
+    };
+    note(&node.rpc(json!({ "type": "topology", "topology": topology })));
+    // Replies, forwards, gossip and pings, with n2 acking everything and n3
+    // nothing, so forwards to it are retried
+    let broadcasts: Vec<Value> = (0..10)
+        .map(|i| node.send("c1", json!({ "type": "broadcast", "message": i })))
+        .collect();
+    let (mut replies, mut gossip) = (0, 0);
+    while replies < broadcasts.len() || gossip < 4 {
+        let msg = node.recv(|_| true);
+        note(&msg);
+        let kind = msg["body"]["type"].as_str().unwrap_or_default();
+        match (msg["dest"].as_str().unwrap_or_default(), kind) {
+            ("c1", _) => replies += 1,
+            (_, "gossip") => gossip += 1,
+            _ => {}
+        }
+        let ack = match kind {
+            "broadcast" => "broadcast_ok",
+            "ping" => "ping_ok",
+            _ => continue,
+        };
+        if msg["dest"] == "n2" {
+            node.send(
+                "n2",
+                json!({ "type": ack, "in_reply_to": msg["body"]["msg_id"] }),
+            );
+        }
+    }
+    note(&node.rpc(json!({ "type": "read" })));
+    node.finish();
+}
+
+#[test]
+fn broadcast_digests_only_go_to_neighbors_that_advertised_them() {
+    let mut node = TestNode::start_with(
+        env!("CARGO_BIN_EXE_broadcast"),
+        "n1",
+        &["n1", "n2"],
+        Stdio::null(),
+        &[("GLOMERS_DIGEST_GOSSIP", "1")],
+    );
+    // Before the topology, so there's no neighbor to forward it to
+    node.rpc(json!({ "type": "broadcast", "message": 5 }));
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    // Play an n2 whose ping answers advertise nothing, like an older node
+    let gossip = loop {
+        let msg = node.recv(|msg| msg["dest"] == "n2");
+        match msg["body"]["type"].as_str() {
+            Some("ping") => {
+                node.send(
+                    "n2",
+                    json!({ "type": "ping_ok", "in_reply_to": msg["body"]["msg_id"] }),
+                );
+            }
+            Some("digest") => panic!("sent a digest to a node that can't decode it: {}", msg),
+            Some("gossip") => break msg,
+            _ => {}
+        }
+    };
+    assert_eq!(gossip["body"]["seen"], json!([5]));
+    // Once n2 advertises digests, ticks send those instead
+    node.send("n2", json!({ "type": "ping", "capabilities": ["digest"] }));
+    node.recv(|msg| msg["body"]["type"] == "digest");
+    node.finish();
+}
+
+#[test]
+fn broadcast_answers_debug_requests_with_its_snapshot() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1"]);
+    node.rpc(json!({ "type": "broadcast", "message": 5 }));
+    let reply = node.rpc(json!({ "type": "debug" }));
+    assert_eq!(reply["body"]["type"], "debug_ok");
+    assert_eq!(reply["body"]["snapshot"]["seen"], json!([5]));
+    // The node carries on as before
+    node.rpc(json!({ "type": "broadcast", "message": 6 }));
+    let reply = node.rpc(json!({ "type": "read" }));
+    let mut messages: Vec<u64> = serde_json::from_value(reply["body"]["messages"].clone())
+        .expect("messages is a list of numbers");
+    messages.sort_unstable();
+    assert_eq!(messages, [5, 6]);
+    node.finish();
+}
+
+#[test]
+fn broadcast_reconciles_with_its_neighbors_on_topology() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    node.rpc(json!({ "type": "broadcast", "message": 1 }));
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    // Play n2, which has more than n1, all of which one exchange brings over
+    let reconcile = node.recv(|msg| msg["body"]["type"] == "reconcile");
+    assert_eq!(reconcile["body"]["have"], json!([1]));
+    node.send(
+        "n2",
+        json!({
+            "type": "reconcile_ok",
+            "in_reply_to": reconcile["body"]["msg_id"],
+            "missing": [2, 3, 4],
+            "more": false,
+        }),
+    );
+    let reply = node.rpc(json!({ "type": "read" }));
+    let mut messages: Vec<u64> = serde_json::from_value(reply["body"]["messages"].clone())
+        .expect("messages is a list of numbers");
+    messages.sort_unstable();
+    assert_eq!(messages, [1, 2, 3, 4]);
+    // And answers n2's reconcile with what n2 lacks
+    let id = node.send("n2", json!({ "type": "reconcile", "have": [1, 3] }));
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == id);
+    assert_eq!(reply["body"]["type"], "reconcile_ok");
+    assert_eq!(reply["body"]["missing"], json!([2, 4]));
+    assert_eq!(reply["body"]["more"], false);
+    node.finish();
+}
+
+#[test]
+fn broadcast_fresh_read_includes_values_pulled_from_neighbors() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    let read = node.send("c1", json!({ "type": "read", "fresh": true }));
+    let pull = node.recv(|msg| msg["body"]["type"] == "pull");
+    assert_eq!(pull["dest"], "n2");
+    node.send(
+        "n2",
+        json!({
+            "type": "pull_ok",
+            "in_reply_to": pull["body"]["msg_id"],
+            "seen": [42],
+        }),
+    );
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == read);
+    assert_eq!(reply["body"]["type"], "read_ok");
+    assert_eq!(reply["body"]["messages"], json!([42]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_read_staleness_is_bounded_once_neighbors_gossip() {
+    let mut node = TestNode::start_with_stderr(
+        env!("CARGO_BIN_EXE_broadcast"),
+        "n1",
+        &["n1", "n2"],
+        Stdio::piped(),
+    );
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    node.rpc(json!({ "type": "read" }));
+    node.send("n2", json!({ "type": "gossip", "seen": [3], "round": 1 }));
+    // Gossip from n2 is recent enough to answer a fresh read without a pull
+    let read = node.send(
+        "c1",
+        json!({ "type": "read", "fresh": true, "max_staleness_ms": 60_000 }),
+    );
+    let reply = node.recv(|msg| {
+        assert_ne!(msg["body"]["type"], "pull", "sent {}", msg);
+        msg["body"]["in_reply_to"] == read
+    });
+    assert_eq!(reply["body"]["messages"], json!([3]));
+    let logs = node.finish_with_stderr();
+    let never = logs
+        .find("answering read with a neighbor never heard from")
+        .unwrap_or_else(|| panic!("logs: {}", logs));
+    let bounded = logs
+        .find("behind neighbors")
+        .unwrap_or_else(|| panic!("logs: {}", logs));
+    assert!(never < bounded, "logs: {}", logs);
+}
+
+#[test]
+fn broadcast_quorum_read_includes_values_of_a_majority() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2", "n3"]);
+    node.rpc(json!({ "type": "topology", "topology": {
+        "n1": ["n2"], "n2": ["n1", "n3"], "n3": ["n2"],
+    }}));
+    let read = node.send("c1", json!({ "type": "read", "quorum": true }));
+    // n2 has 8, n3 doesn't answer: n1 and n2 are a majority
+    let pull = node.recv(|msg| msg["body"]["type"] == "pull" && msg["dest"] == "n2");
+    node.send(
+        "n2",
+        json!({ "type": "pull_ok", "in_reply_to": pull["body"]["msg_id"], "seen": [8] }),
+    );
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == read);
+    assert_eq!(reply["body"]["messages"], json!([8]));
+    // Nobody answers now, so the read falls back to what n1 has
+    let read = node.send("c1", json!({ "type": "read", "quorum": true }));
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == read);
+    assert_eq!(reply["body"]["messages"], json!([8]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_ignores_gossip_rounds_older_than_the_last_one_applied() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    // Before the topology, so n2 lacks it: every gossip applied is acked with it
+    node.rpc(json!({ "type": "broadcast", "message": 9 }));
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    let gossip = |node: &mut TestNode, round: u64, seen: u64| {
+        node.send(
+            "n2",
+            json!({ "type": "gossip", "seen": [seen], "round": round }),
+        )
+    };
+    let acked = |node: &mut TestNode, gossip: Value| {
+        node.recv(|msg| msg["body"]["type"] == "gossip_ok" && msg["body"]["in_reply_to"] == gossip);
+    };
+    let first = gossip(&mut node, 5, 1);
+    acked(&mut node, first);
+    // Round 3 arrives late and round 5 twice; only round 6 is newer
+    gossip(&mut node, 3, 2);
+    gossip(&mut node, 5, 4);
+    let last = gossip(&mut node, 6, 3);
+    acked(&mut node, last);
+    let reply = node.rpc(json!({ "type": "read" }));
+    assert_eq!(reply["body"]["messages"], json!([1, 3, 9]));
+    node.finish();
+}
+
+#[test]
+fn broadcast_gossips_ever_more_rarely_to_a_synced_neighbor_until_there_is_news() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    // Returns when the next gossip to n2 went out, keeping n2 up meanwhile
+    let next_gossip = |node: &mut TestNode| loop {
+        let msg = node.recv(|msg| msg["dest"] == "n2");
+        match msg["body"]["type"].as_str() {
+            Some("ping") => {
+                node.send(
+                    "n2",
+                    json!({ "type": "ping_ok", "in_reply_to": msg["body"]["msg_id"] }),
+                );
+            }
+            Some("gossip") => return (Instant::now(), msg),
+            _ => {}
+        }
+    };
+    // Neither node has anything, so every tick finds n2 synced
+    let gossips: Vec<Instant> = (0..5).map(|_| next_gossip(&mut node).0).collect();
+    let first_gap = gossips[1] - gossips[0];
+    let last_gap = gossips[4] - gossips[3];
+    assert!(
+        last_gap > first_gap * 5 / 2,
+        "gaps of {:?} then {:?}",
+        first_gap,
+        last_gap
+    );
+    // n2 doesn't ack the forward, so the next tick has news for it
+    let sent = Instant::now();
+    node.send("c1", json!({ "type": "broadcast", "message": 7 }));
+    let (at, gossip) = loop {
+        let (at, gossip) = next_gossip(&mut node);
+        if gossip["body"]["seen"] != json!([]) {
+            break (at, gossip);
+        }
+    };
+    assert_eq!(gossip["body"]["seen"], json!([7]));
+    assert!(at - sent < first_gap * 2, "news waited {:?}", at - sent);
+    node.finish();
+}
+
+#[test]
+fn broadcast_gossip_exchange_syncs_both_nodes() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_broadcast"), "n1", &["n1", "n2"]);
+    // Before the topology, so there's no neighbor to forward it to
+    node.rpc(json!({ "type": "broadcast", "message": 1 }));
+    node.rpc(json!({ "type": "topology", "topology": { "n1": ["n2"], "n2": ["n1"] } }));
+    // Play n2, which only has 2
+    let gossip = node.send("n2", json!({ "type": "gossip", "seen": [2], "round": 1 }));
+    let ack = node.recv(|msg| msg["body"]["in_reply_to"] == gossip);
+    assert_eq!(ack["body"]["type"], "gossip_ok");
+    assert_eq!(ack["body"]["seen"], json!([1]));
+    let reply = node.rpc(json!({ "type": "read" }));
+    let mut messages: Vec<u64> = serde_json::from_value(reply["body"]["messages"].clone())
+        .expect("messages is a list of numbers");
+    messages.sort_unstable();
+    assert_eq!(messages, [1, 2]);
+    node.finish();
+}
+
+#[test]
+fn kafka_sends_guess_the_next_offset_instead_of_reading_it() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for (i, msg) in [10, 11, 12, 13, 14].into_iter().enumerate() {
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": msg }));
+        assert_eq!(reply["body"]["type"], "send_ok");
+        assert_eq!(reply["body"]["offset"], i);
+    }
+    assert_eq!(kv.reads.get("lin-kv"), None);
+    node.finish();
+}
+
+#[test]
+fn kafka_reloads_its_offsets_after_a_restart() {
+    let dir = state_dir("kafka");
+    let env = [("GLOMERS_STATE_DIR", dir.to_str().unwrap())];
+    let start = || {
+        TestNode::start_with(
+            env!("CARGO_BIN_EXE_kafka"),
+            "n1",
+            &["n1"],
+            Stdio::null(),
+            &env,
+        )
+    };
+    let mut kv = FakeKv::default();
+    let mut node = start();
+    for msg in [10, 11] {
+        node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": msg }));
+    }
+    let commit = json!({ "type": "commit_offsets", "offsets": { "k": 1 } });
+    node.rpc_with_kv(&mut kv, commit);
+    std::thread::sleep(STATE_WRITTEN);
+    node.finish();
+    let saved: Value =
+        serde_json::from_slice(&std::fs::read(dir.join("kafka-n1.json")).expect("state file"))
+            .expect("state file is JSON");
+    assert_eq!(
+        saved,
+        json!({ "next_offsets": { "k": 2 }, "committed": { "committed:k": 1 } })
+    );
+
+    // The restarted node's first send guesses the next offset right, so it
+    // never needs to read the latest one
+    let mut node = start();
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": 12 }));
+    assert_eq!(reply["body"]["offset"], 2);
+    assert_eq!(kv.reads.get("lin-kv"), None);
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_stops_at_the_first_gap() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for (offset, msg) in [(3, 30), (4, 40), (6, 60)] {
+        kv.values.insert(
+            ("seq-kv".into(), format!("k:{}", offset)),
+            json!({ "msg": msg }),
+        );
+    }
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(6));
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "poll", "offsets": { "k": 3 } }));
+    assert_eq!(reply["body"]["type"], "poll_ok");
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[3, 30], [4, 40]]));
+    node.finish();
+}
+
+#[test]
+fn kafka_polls_fifty_keys_with_at_most_eight_reads_in_flight() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    let mut offsets = serde_json::Map::new();
+    for i in 0..50 {
+        let key = format!("k{}", i);
+        kv.values
+            .insert(("lin-kv".into(), format!("latest:{}", key)), json!(1));
+        for (offset, msg) in [(0, i), (1, i + 100)] {
+            kv.values.insert(
+                ("seq-kv".into(), format!("{}:{}", key, offset)),
+                json!({ "msg": msg }),
+            );
+        }
+        offsets.insert(key, json!(0));
+    }
+    let poll = node.send("c1", json!({ "type": "poll", "offsets": offsets }));
+    let mut held = Vec::new();
+    let mut done = 0;
+    let reply = loop {
+        let msg = node.recv(|_| true);
+        if !FakeKv::serves(&msg) {
+            assert_eq!(msg["body"]["in_reply_to"], poll, "sent {}", msg);
+            break msg;
+        }
+        held.push(msg);
+        if held.len() == 8 {
+            // Every permit is taken, so nothing but the answer to a request
+            // that doesn't poll goes out before a read is answered
+            std::thread::sleep(Duration::from_millis(100));
+            let barrier = node.send("c2", json!({ "type": "debug" }));
+            let next = node.recv(|_| true);
+            assert_eq!(next["body"]["in_reply_to"], barrier, "sent {}", next);
+        }
+        // A key's last read is of its offset 1
+        if held.len() == 8.min(50 - done) {
+            for msg in held.drain(..) {
+                done += usize::from(
+                    msg["body"]["key"]
+                        .as_str()
+                        .is_some_and(|key| key.ends_with(":1")),
+                );
+                kv.answer(&mut node, &msg);
+            }
+        }
+    };
+    assert_eq!(done, 50);
+    let msgs = reply["body"]["msgs"].as_object().expect("msgs is a map");
+    assert_eq!(msgs.len(), 50);
+    for i in 0..50 {
+        assert_eq!(msgs[&format!("k{}", i)], json!([[0, i], [1, i + 100]]));
+    }
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_waits_for_a_reserved_message_to_land() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(0));
+    let poll = node.send("c1", json!({ "type": "poll", "offsets": { "k": 0 } }));
+    let mut landed = false;
+    let reply = loop {
+        let msg = node.recv(|_| true);
+        if FakeKv::serves(&msg) {
+            kv.answer(&mut node, &msg);
+            // The send writes its message just after the first read misses it
+            if msg["body"]["key"] == "k:0" && !landed {
+                kv.values
+                    .insert(("seq-kv".into(), "k:0".into()), json!({ "msg": 7 }));
+                landed = true;
+            }
+        } else if msg["body"]["in_reply_to"] == poll {
+            break msg;
+        }
+    };
+    assert!(landed);
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, 7]]));
+    node.finish();
+}
+
+#[test]
+fn kafka_send_that_crashed_before_writing_leaves_a_pollable_log() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    // A send reserved offset 1 and crashed before writing its message. The
+    // message before it is one a bare sentinel value would take for a tombstone
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(1));
+    kv.values
+        .insert(("seq-kv".into(), "k:0".into()), json!({ "msg": i64::MIN }));
+    let poll = json!({ "type": "poll", "offsets": { "k": 0 } });
+    let reply = node.rpc_with_kv(&mut kv, poll.clone());
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, i64::MIN]]));
+
+    // Once the hole timed out, polls skip it
+    std::thread::sleep(Duration::from_millis(1100));
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": 8 }));
+    assert_eq!(reply["body"]["offset"], 2);
+    let reply = node.rpc_with_kv(&mut kv, poll);
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, i64::MIN], [2, 8]]));
+    assert_eq!(
+        kv.values[&("seq-kv".to_string(), "k:1".to_string())],
+        json!("tombstone")
+    );
+    node.finish();
+}
+
+#[test]
+fn kafka_seek_to_an_earlier_offset_replays_from_there() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for msg in [10, 11, 12] {
+        node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": msg }));
+    }
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "seek", "key": "k", "offset": 1 }));
+    assert_eq!(reply["body"]["type"], "seek_ok");
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "poll", "offsets": { "k": 1 } }));
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[1, 11], [2, 12]]));
+    for (key, offset) in [("k", 3), ("k", -1), ("empty", 0)] {
+        let reply = node.rpc_with_kv(
+            &mut kv,
+            json!({ "type": "seek", "key": key, "offset": offset }),
+        );
+        assert_eq!(reply["body"]["type"], "error", "seek {} to {}", key, offset);
+        assert_eq!(reply["body"]["code"], 12);
+    }
+    node.finish();
+}
+
+#[test]
+fn kafka_reads_wait_for_a_store_that_is_still_starting() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv {
+        unavailable: 2,
+        ..FakeKv::default()
+    };
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(0));
+    kv.values
+        .insert(("seq-kv".into(), "k:0".into()), json!({ "msg": 7 }));
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "poll", "offsets": { "k": 0 } }));
+    assert_eq!(kv.unavailable, 0);
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, 7]]));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["k"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "k": 0 }));
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_latest_returns_the_tail_in_offset_order() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for offset in 0..10 {
+        kv.values.insert(
+            ("seq-kv".into(), format!("k:{}", offset)),
+            json!({ "msg": 100 + offset }),
+        );
+    }
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(9));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "poll_latest", "keys": ["k", "empty"], "count": 3 }),
+    );
+    assert_eq!(reply["body"]["type"], "poll_ok");
+    assert_eq!(
+        reply["body"]["msgs"]["k"],
+        json!([[7, 107], [8, 108], [9, 109]])
+    );
+    assert_eq!(reply["body"]["msgs"]["empty"], json!([]));
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_with_markers_reports_latest_and_committed_offsets() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for (key, latest) in [("a", 2), ("b", 0)] {
+        kv.values
+            .insert(("lin-kv".into(), format!("latest:{}", key)), json!(latest));
+    }
+    for (key, msg) in [("a:0", 10), ("a:1", 11), ("a:2", 12), ("b:0", 20)] {
+        kv.values
+            .insert(("seq-kv".into(), key.into()), json!({ "msg": msg }));
+    }
+    // Only group g's commit counts; b was never committed
+    kv.values
+        .insert(("seq-kv".into(), "committed:g:a".into()), json!(1));
+    kv.values
+        .insert(("seq-kv".into(), "committed:a".into()), json!(2));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({
+            "type": "poll", "offsets": { "a": 1, "b": 0 }, "markers": true, "group": "g",
+        }),
+    );
+    assert_eq!(reply["body"]["type"], "poll_batch_ok");
+    assert_eq!(
+        reply["body"]["msgs"],
+        json!({ "a": [[1, 11], [2, 12]], "b": [[0, 20]] })
+    );
+    assert_eq!(reply["body"]["latest"], json!({ "a": 2, "b": 0 }));
+    assert_eq!(reply["body"]["committed"], json!({ "a": 1, "b": 0 }));
+    // Without the flag the reply keeps the standard shape
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "poll", "offsets": { "b": 0 } }));
+    assert_eq!(reply["body"]["type"], "poll_ok");
+    assert!(reply["body"].get("latest").is_none(), "sent {}", reply);
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_with_a_small_budget_returns_a_prefix() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for offset in 0..5 {
+        kv.values.insert(
+            ("seq-kv".into(), format!("k:{}", offset)),
+            json!({ "msg": 100 + offset }),
+        );
+    }
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(4));
+    let poll = |max_bytes| json!({ "type": "poll", "offsets": { "k": 0 }, "max_bytes": max_bytes });
+    let large = node.rpc_with_kv(&mut kv, poll(10_000));
+    assert_eq!(large["body"]["msgs"]["k"].as_array().unwrap().len(), 5);
+    let small = node.rpc_with_kv(&mut kv, poll(24));
+    assert_eq!(small["body"]["msgs"]["k"], json!([[0, 100], [1, 101]]));
+    // Even a budget too small for one message lets the consumer make progress
+    let tiny = node.rpc_with_kv(&mut kv, poll(1));
+    assert_eq!(tiny["body"]["msgs"]["k"], json!([[0, 100]]));
+    node.finish();
+}
+
+#[test]
+fn kafka_answers_requests_it_cannot_serve_with_an_error() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let id = node.send("c1", json!({ "type": "read", "key": "k" }));
+    let reply = node.recv(|msg| msg["dest"] == "c1");
+    assert_eq!(reply["body"]["type"], "error");
+    assert_eq!(reply["body"]["code"], 10);
+    assert_eq!(reply["body"]["in_reply_to"], id);
+    assert!(reply["body"]["text"].is_string());
+    node.finish();
+}
+
+#[test]
+fn kafka_repeated_polls_are_served_from_cache_until_a_send() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for (offset, msg) in [(0, 10), (1, 11)] {
+        kv.values.insert(
+            ("seq-kv".into(), format!("k:{}", offset)),
+            json!({ "msg": msg }),
+        );
+    }
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(1));
+    let poll = json!({ "type": "poll", "offsets": { "k": 0 } });
+    let reply = node.rpc_with_kv(&mut kv, poll.clone());
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, 10], [1, 11]]));
+    let reads = kv.reads.clone();
+    let reply = node.rpc_with_kv(&mut kv, poll.clone());
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[0, 10], [1, 11]]));
+    assert_eq!(kv.reads, reads, "the repeated poll read the store");
+    node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": 12 }));
+    let reply = node.rpc_with_kv(&mut kv, poll);
+    assert_eq!(
+        reply["body"]["msgs"]["k"],
+        json!([[0, 10], [1, 11], [2, 12]])
+    );
+    node.finish();
+}
+
+#[test]
+fn kafka_poll_commit_commits_the_last_returned_offset() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for (offset, msg) in [(3, 30), (4, 40), (6, 60)] {
+        kv.values.insert(
+            ("seq-kv".into(), format!("k:{}", offset)),
+            json!({ "msg": msg }),
+        );
+    }
+    kv.values
+        .insert(("lin-kv".into(), "latest:k".into()), json!(6));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "poll_commit", "offsets": { "k": 3, "empty": 0 } }),
+    );
+    assert_eq!(reply["body"]["type"], "poll_commit_ok");
+    assert_eq!(reply["body"]["msgs"]["k"], json!([[3, 30], [4, 40]]));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["k", "empty"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "k": 4, "empty": 0 }));
+    node.finish();
+}
+
+#[test]
+fn kafka_commits_all_offsets_in_one_round_trip() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    let offsets: serde_json::Map<String, Value> =
+        (0..10).map(|i| (format!("k{}", i), json!(i))).collect();
+    let id = node.send(
+        "c1",
+        json!({ "type": "commit_offsets", "offsets": offsets.clone() }),
+    );
+    // Every write goes out before the first one is answered
+    let writes: Vec<Value> = (0..10)
+        .map(|_| node.recv(|msg| msg["body"]["type"] == "cas"))
+        .collect();
+    for write in &writes {
+        kv.answer(&mut node, write);
+    }
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == id);
+    assert_eq!(reply["body"]["type"], "commit_offsets_ok");
+    let keys: Vec<&String> = offsets.keys().collect();
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": keys }),
+    );
+    assert_eq!(reply["body"]["offsets"], Value::Object(offsets));
+    node.finish();
+}
+
+#[test]
+fn kafka_groups_commit_independently_and_share_subscriptions_across_nodes() {
+    let mut n1 = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1", "n2"]);
+    let mut n2 = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n2", &["n1", "n2"]);
+    let mut kv = FakeKv::default();
+    let subscribe = |group, keys| json!({ "type": "subscribe", "group": group, "keys": keys });
+    let reply = n1.rpc_with_kv(&mut kv, subscribe("g1", json!(["a", "b"])));
+    assert_eq!(reply["body"]["type"], "subscribe_ok");
+    n2.rpc_with_kv(&mut kv, subscribe("g1", json!(["c"])));
+    let commit = |group, offset| json!({ "type": "commit_offsets", "offsets": { "a": offset }, "group": group });
+    n1.rpc_with_kv(&mut kv, commit("g1", 3));
+    n2.rpc_with_kv(&mut kv, commit("g2", 7));
+
+    // n2 lists what g1 subscribed to through either node
+    let list =
+        |group, keys| json!({ "type": "list_committed_offsets", "keys": keys, "group": group });
+    let reply = n2.rpc_with_kv(&mut kv, list("g1", json!([])));
+    assert_eq!(reply["body"]["offsets"], json!({ "a": 3, "b": 0, "c": 0 }));
+    let reply = n1.rpc_with_kv(&mut kv, list("g2", json!(["a"])));
+    assert_eq!(reply["body"]["offsets"], json!({ "a": 7 }));
+    let reply = n1.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["a"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "a": 0 }));
+    n1.finish();
+    n2.finish();
+}
+
+#[test]
+fn kafka_reports_a_commit_the_store_turned_down() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv {
+        unavailable: 1,
+        ..FakeKv::default()
+    };
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "commit_offsets", "offsets": { "k": 3 } }),
+    );
+    assert_eq!(reply["body"]["type"], "error");
+    assert_eq!(reply["body"]["code"], 11);
+    node.finish();
+}
+
+#[test]
+fn kafka_lists_a_key_never_committed_at_zero_but_reports_a_read_that_timed_out() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    kv.values
+        .insert(("seq-kv".into(), "committed:a".into()), json!(3));
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["a", "b"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "a": 3, "b": 0 }));
+    // Now that seq-kv has answered, a read it leaves unanswered is a failure
+    let id = node.send(
+        "c1",
+        json!({ "type": "list_committed_offsets", "keys": ["a"] }),
+    );
+    let reply = node.recv(|msg| msg["dest"] == "c1");
+    assert_eq!(reply["body"]["in_reply_to"], id);
+    assert_eq!(reply["body"]["type"], "error");
+    assert_eq!(reply["body"]["code"], 11);
+    node.finish();
+}
+
+#[test]
+fn kafka_ignores_commits_that_would_move_the_offset_back() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for offset in [3, 2] {
+        let reply = node.rpc_with_kv(
+            &mut kv,
+            json!({ "type": "commit_offsets", "offsets": { "k": offset } }),
+        );
+        assert_eq!(reply["body"]["type"], "commit_offsets_ok");
+    }
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["k"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "k": 3 }));
+    node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "commit_offsets", "offsets": { "k": 5 } }),
+    );
+    let reply = node.rpc_with_kv(
+        &mut kv,
+        json!({ "type": "list_committed_offsets", "keys": ["k"] }),
+    );
+    assert_eq!(reply["body"]["offsets"], json!({ "k": 5 }));
+    node.finish();
+}
+
+#[test]
+fn counter_syncs_with_every_other_node_without_a_topology() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_counter"), "n1", &["n1", "n2", "n3"]);
+    let mut synced = std::collections::BTreeSet::new();
+    while synced.len() < 2 {
+        let sync = node.recv(|msg| msg["body"]["type"] == "sync");
+        synced.insert(sync["dest"].as_str().expect("dest").to_string());
+    }
+    assert_eq!(synced, ["n2".to_string(), "n3".to_string()].into());
+    node.finish();
+}
+
+#[test]
+fn counter_skips_syncs_to_a_peer_that_does_not_answer_pings() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_counter"), "n1", &["n1", "n2"]);
+    let to_n2 = |msg: &Value| msg["dest"] == "n2";
+    // Syncs go out every 500ms, the first ping after 1s
+    let mut syncs = 0;
+    let ping = loop {
+        let msg = node.recv(to_n2);
+        match msg["body"]["type"].as_str() {
+            Some("sync") => syncs += 1,
+            Some("ping") => break msg,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    assert!(syncs > 0, "no sync before the first ping");
+    // Leave the ping unanswered: no sync due after it timed out goes out
+    let pinged = Instant::now();
+    let ping = loop {
+        let msg = node.recv(to_n2);
+        match msg["body"]["type"].as_str() {
+            Some("sync") => assert!(
+                pinged.elapsed() < Duration::from_millis(100),
+                "sync to a peer that is down"
+            ),
+            Some("ping") if msg["body"]["msg_id"] != ping["body"]["msg_id"] => break msg,
+            other => panic!("unexpected {:?}", other),
+        }
+    };
+    // Once it answers again, syncs resume
+    node.send(
+        "n2",
+        json!({ "type": "ping_ok", "in_reply_to": ping["body"]["msg_id"] }),
+    );
+    let msg = node.recv(|msg| to_n2(msg) && msg["body"]["type"] == "sync");
+    assert_eq!(msg["body"]["value"], 0);
+    node.finish();
+}
+
+#[test]
+fn lin_kv_reports_the_current_value_when_a_cas_fails() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_lin_kv"), "lin-kv", &["lin-kv"]);
+    let reply = node.rpc(json!({ "type": "write", "key": "k", "value": 1 }));
+    assert_eq!(reply["body"]["type"], "write_ok");
+    let reply = node.rpc(json!({ "type": "cas", "key": "k", "from": 2, "to": 3 }));
+    assert_eq!(reply["body"]["type"], "error");
+    assert_eq!(reply["body"]["code"], 22);
+    assert_eq!(reply["body"]["current"], 1);
+    let reply = node.rpc(json!({ "type": "cas", "key": "k", "from": 1, "to": 3 }));
+    assert_eq!(reply["body"]["type"], "cas_ok");
+    let reply = node.rpc(json!({ "type": "read", "key": "k" }));
+    assert_eq!(reply["body"]["value"], 3);
+    let reply = node.rpc(json!({ "type": "read", "key": "missing" }));
+    assert_eq!(reply["body"]["code"], 20);
+    node.finish();
+}
+
+#[test]
+fn counter_read_repair_reads_a_stale_store_again_after_a_barrier_write() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_counter"), "n1", &["n1", "n2"]);
+    node.send("n2", json!({ "type": "sync", "value": 5 }));
+    // Let the sync land before the read looks at n2
+    std::thread::sleep(Duration::from_millis(50));
+    let read = node.send("c1", json!({ "type": "read" }));
+    let kv_read = |msg: &Value| msg["dest"] == "seq-kv" && msg["body"]["type"] == "read";
+    let first = node.recv(kv_read);
+    assert_eq!(first["body"]["key"], "counter:n2");
+    // The store serves a view from before n2's sync
+    node.send(
+        "seq-kv",
+        json!({ "type": "read_ok", "in_reply_to": first["body"]["msg_id"], "value": 3 }),
+    );
+    let barrier = node.recv(|msg| msg["body"]["type"] == "write");
+    assert_eq!(barrier["body"]["key"], "barrier:n1");
+    node.send(
+        "seq-kv",
+        json!({ "type": "write_ok", "in_reply_to": barrier["body"]["msg_id"] }),
+    );
+    // n2 took more adds since its sync
+    let again = node.recv(kv_read);
+    assert_eq!(again["body"]["key"], "counter:n2");
+    node.send(
+        "seq-kv",
+        json!({ "type": "read_ok", "in_reply_to": again["body"]["msg_id"], "value": 7 }),
+    );
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == read);
+    assert_eq!(reply["body"]["value"], 7);
+    node.finish();
+}
+
+#[test]
+fn counter_publishes_its_sub_counter_on_add() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_counter"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for delta in [2, 3] {
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "add", "delta": delta }));
+        assert_eq!(reply["body"]["type"], "add_ok");
+    }
+    assert_eq!(
+        kv.values[&("seq-kv".to_string(), "counter:n1".to_string())],
+        5
+    );
+    node.finish();
+}
+
+#[test]
+fn kafka_logs_cas_iterations_of_contended_keys_at_shutdown() {
+    let mut node =
+        TestNode::start_with_stderr(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"], Stdio::piped());
+    let mut kv = FakeKv::default();
+    // Both sends of the hot key try to reserve the same offset
+    node.send("c1", json!({ "type": "send", "key": "hot", "msg": 1 }));
+    node.send("c1", json!({ "type": "send", "key": "hot", "msg": 2 }));
+    let reservations = [node.recv(FakeKv::serves), node.recv(FakeKv::serves)];
+    for cas in &reservations {
+        kv.answer(&mut node, cas);
+    }
+    let mut sent = 0;
+    while sent < 2 {
+        let msg = node.recv(|_| true);
+        if FakeKv::serves(&msg) {
+            kv.answer(&mut node, &msg);
+        } else if msg["body"]["type"] == "send_ok" {
+            sent += 1;
+        }
+    }
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "cold", "msg": 3 }));
+    assert_eq!(reply["body"]["type"], "send_ok");
+    let logs = node.finish_with_stderr();
+    assert!(
+        logs.contains("sends to cold took {1: 1} cas iterations (max 1)"),
+        "logs: {}",
+        logs
+    );
+    assert!(
+        logs.contains("sends to hot took {1: 1, 2: 1} cas iterations (max 2)"),
+        "logs: {}",
+        logs
+    );
+}
+
+#[test]
+fn kafka_polls_from_lin_kv_see_a_just_sent_message() {
+    for (store, visible) in [("seq", false), ("lin", true)] {
+        let mut node = TestNode::start_with(
+            env!("CARGO_BIN_EXE_kafka"),
+            "n1",
+            &["n1"],
+            Stdio::null(),
+            &[("GLOMERS_MSG_STORE", store)],
+        );
+        let mut kv = FakeKv {
+            stale: Some(HashMap::new()),
+            ..FakeKv::default()
+        };
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "send", "key": "k", "msg": 7 }));
+        assert_eq!(reply["body"]["offset"], 0);
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "poll", "offsets": { "k": 0 } }));
+        let msgs = if visible { json!([[0, 7]]) } else { json!([]) };
+        assert_eq!(reply["body"]["msgs"]["k"], msgs, "store: {}", store);
+        node.finish();
+    }
+}
+
+#[test]
+fn kafka_with_an_empty_store_name_fails_validation() {
+    let output = Command::new(env!("CARGO_BIN_EXE_kafka"))
+        .args(["--node-id", "n1", "--nodes", "n1"])
+        .env("GLOMERS_SEQ_KV", "")
+        .stdin(Stdio::null())
+        .output()
+        .expect("run node binary");
+    assert!(!output.status.success());
+    let logs = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        logs.contains("node failed validation") && logs.contains("GLOMERS_SEQ_KV is empty"),
+        "logs: {}",
+        logs
+    );
+}
+
+#[test]
+fn slow_handlers_are_logged() {
+    let mut node =
+        TestNode::start_with_stderr(env!("CARGO_BIN_EXE_kafka"), "n1", &["n1"], Stdio::piped());
+    let mut kv = FakeKv::default();
+    let id = node.send("c1", json!({ "type": "send", "key": "k", "msg": 1 }));
+    // Stall the send's first KV request past the 1s threshold
+    let cas = node.recv(FakeKv::serves);
+    std::thread::sleep(Duration::from_millis(1200));
+    kv.answer(&mut node, &cas);
+    loop {
+        let msg = node.recv(|_| true);
+        if FakeKv::serves(&msg) {
+            kv.answer(&mut node, &msg);
+        } else if msg["body"]["type"] == "send_ok" {
+            break;
+        }
+    }
+    let logs = node.finish_with_stderr();
+    assert!(
+        logs.contains("handling Send from c1 is taking longer than 1s"),
+        "logs: {}",
+        logs
+    );
+    // Lines logged on behalf of the send name it by its message id
+    let prefix = format!("[kafka n1 c1#{}] handling Send", id);
+    assert!(logs.contains(&prefix), "logs: {}", logs);
+}
+
+#[test]
+fn tob_nodes_appending_concurrently_agree_on_one_order() {
+    let nodes = ["n1", "n2"];
+    let mut n1 = TestNode::start(env!("CARGO_BIN_EXE_tob"), "n1", &nodes);
+    let mut n2 = TestNode::start(env!("CARGO_BIN_EXE_tob"), "n2", &nodes);
+    let mut kv = FakeKv::default();
+    let first = n1.send("c1", json!({ "type": "broadcast", "message": 1 }));
+    let second = n2.send("c1", json!({ "type": "broadcast", "message": 2 }));
+    // Both appends cas from the empty log before either lands, so n2's
+    // loses and has to append after n1's
+    let cas1 = n1.recv(FakeKv::serves);
+    let cas2 = n2.recv(FakeKv::serves);
+    assert_eq!(cas1["body"]["from"], cas2["body"]["from"]);
+    kv.answer(&mut n1, &cas1);
+    kv.answer(&mut n2, &cas2);
+    assert_eq!(
+        n1.reply_with_kv(&mut kv, &first)["body"]["type"],
+        "broadcast_ok"
+    );
+    assert_eq!(
+        n2.reply_with_kv(&mut kv, &second)["body"]["type"],
+        "broadcast_ok"
+    );
+    for node in [&mut n1, &mut n2] {
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "read" }));
+        assert_eq!(reply["body"]["messages"], json!([1, 2]));
+    }
+    n1.finish();
+    n2.finish();
+}
+
+#[test]
+fn tob_keeps_the_same_message_broadcast_twice() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_tob"), "n1", &["n1"]);
+    let mut kv = FakeKv::default();
+    for _ in 0..2 {
+        let reply = node.rpc_with_kv(&mut kv, json!({ "type": "broadcast", "message": 7 }));
+        assert_eq!(reply["body"]["type"], "broadcast_ok");
+    }
+    let reply = node.rpc_with_kv(&mut kv, json!({ "type": "read" }));
+    assert_eq!(reply["body"]["messages"], json!([7, 7]));
+    node.finish();
+}
+
+#[test]
+fn txn_committed_on_a_peer_meanwhile_aborts_with_txn_conflict() {
+    let mut node = TestNode::start(env!("CARGO_BIN_EXE_txn"), "n1", &["n1", "n2"]);
+    let id = node.send(
+        "c1",
+        json!({ "type": "txn", "txn": [["r", 1, null], ["w", 2, 20]] }),
+    );
+    // n2 committed a write to key 1 since n1 read it
+    let pull = node.recv(|msg| msg["body"]["type"] == "versions");
+    assert_eq!(pull["dest"], "n2");
+    node.send(
+        "n2",
+        json!({
+            "type": "versions_ok",
+            "in_reply_to": pull["body"]["msg_id"],
+            "entries": [[1, 5, 1]],
+        }),
+    );
+    let abort = node.recv(|msg| msg["body"]["in_reply_to"] == id);
+    assert_eq!(abort["body"]["type"], "error");
+    assert_eq!(abort["body"]["code"], 30);
+
+    // The retry sees n2's write, and nothing of the aborted one
+    let id = node.send(
+        "c1",
+        json!({ "type": "txn", "txn": [["r", 1, null], ["r", 2, null]] }),
+    );
+    let pull = node.recv(|msg| msg["body"]["type"] == "versions");
+    node.send(
+        "n2",
+        json!({
+            "type": "versions_ok",
+            "in_reply_to": pull["body"]["msg_id"],
+            "entries": [[1, 5, 1]],
+        }),
+    );
+    let reply = node.recv(|msg| msg["body"]["in_reply_to"] == id);
+    assert_eq!(reply["body"]["type"], "txn_ok");
+    assert_eq!(reply["body"]["txn"], json!([["r", 1, 5], ["r", 2, null]]));
+    node.finish();
+}
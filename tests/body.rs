@@ -0,0 +1,83 @@
+use std::sync::atomic::AtomicUsize;
+
+use gossip_glomers::{Body, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Gossip { seen: Vec<usize> },
+    GossipOk { seen: Vec<usize> },
+}
+
+fn gossip(id: Option<usize>) -> Message<Payload> {
+    Message {
+        src: "n1".to_string(),
+        dest: "n2".to_string(),
+        body: Body {
+            id,
+            in_reply_to: None,
+            payload: Payload::Gossip { seen: vec![1] },
+        },
+    }
+}
+
+#[test]
+fn notifications_have_no_msg_id_key() {
+    let json = serde_json::to_value(gossip(None)).unwrap();
+    assert_eq!(
+        json,
+        json!({ "src": "n1", "dest": "n2", "body": { "type": "gossip", "seen": [1] } })
+    );
+}
+
+#[test]
+fn replies_carry_in_reply_to() {
+    let reply = gossip(Some(7)).into_reply(Some(&AtomicUsize::new(3)));
+    let json = serde_json::to_value(reply).unwrap();
+    assert_eq!(json["body"]["in_reply_to"], 7);
+    assert_eq!(json["body"]["msg_id"], 3);
+    // A reply without an id of its own still leaves `msg_id` out
+    let reply = gossip(Some(7)).into_reply(None);
+    let json = serde_json::to_value(reply).unwrap();
+    assert_eq!(json["body"]["in_reply_to"], 7);
+    assert!(
+        json["body"].get("msg_id").is_none(),
+        "body: {}",
+        json["body"]
+    );
+    let parsed: Message<Payload> = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.body.id, None);
+}
+
+#[test]
+fn a_reply_with_a_payload_swaps_the_route_and_answers_the_request() {
+    let ids = AtomicUsize::new(3);
+    let reply = gossip(Some(7)).into_reply_with(Some(&ids), Payload::GossipOk { seen: vec![2] });
+    assert_eq!((reply.src.as_str(), reply.dest.as_str()), ("n2", "n1"));
+    assert_eq!(reply.body.in_reply_to, Some(7));
+    assert_eq!(reply.body.id, Some(3));
+    assert!(matches!(reply.body.payload, Payload::GossipOk { seen } if seen == [2]));
+    let reply = gossip(Some(8)).into_reply_with(None, Payload::GossipOk { seen: vec![] });
+    assert_eq!(reply.body.in_reply_to, Some(8));
+    assert_eq!(reply.body.id, None);
+}
+
+#[test]
+fn forwards_go_from_this_node_to_every_neighbor_but_the_source() {
+    let received = gossip(Some(7));
+    let forwards: Vec<Message<Payload>> = ["n1", "n3", "n4"]
+        .iter()
+        .filter_map(|neighbor| received.forward("n2", neighbor, Payload::Gossip { seen: vec![1] }))
+        .collect();
+    let routes: Vec<(&str, &str)> = forwards
+        .iter()
+        .map(|fwd| (fwd.src.as_str(), fwd.dest.as_str()))
+        .collect();
+    assert_eq!(routes, [("n2", "n3"), ("n2", "n4")]);
+    assert!(forwards
+        .iter()
+        .all(|fwd| fwd.body.id.is_none() && fwd.body.in_reply_to.is_none()));
+}
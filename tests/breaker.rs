@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use gossip_glomers::breaker::CircuitBreaker;
+use gossip_glomers::KvError;
+
+#[test]
+fn breaker_opens_after_consecutive_timeouts() {
+    let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+    for _ in 0..2 {
+        breaker.record_timeout("lin-kv");
+    }
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+    breaker.record_timeout("lin-kv");
+    assert_eq!(
+        breaker.check("lin-kv"),
+        Err(KvError::TemporarilyUnavailable)
+    );
+    // Circuits are per store
+    assert_eq!(breaker.check("seq-kv"), Ok(()));
+}
+
+#[test]
+fn an_answer_resets_the_timeout_count() {
+    let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+    for _ in 0..2 {
+        breaker.record_timeout("lin-kv");
+    }
+    breaker.record_answer("lin-kv");
+    breaker.record_timeout("lin-kv");
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+}
+
+#[tokio::test]
+async fn an_open_breaker_lets_one_probe_through_after_the_cooldown() {
+    let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+    breaker.record_timeout("lin-kv");
+    assert!(breaker.check("lin-kv").is_err());
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+    assert!(
+        breaker.check("lin-kv").is_err(),
+        "a second probe got through"
+    );
+    breaker.record_answer("lin-kv");
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+}
+
+#[tokio::test]
+async fn a_failed_probe_reopens_the_breaker_for_longer() {
+    let breaker = CircuitBreaker::new(1, Duration::from_millis(30));
+    breaker.record_timeout("lin-kv");
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+    breaker.record_timeout("lin-kv");
+    // Open for 60ms now, so still open after the first cooldown
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert!(breaker.check("lin-kv").is_err());
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(breaker.check("lin-kv"), Ok(()));
+}
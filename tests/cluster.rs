@@ -0,0 +1,315 @@
+//! Runs several node binaries that talk to each other directly, started with
+//! `--node-id`/`--nodes` instead of a Maelstrom `init`.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+type Stdin = Arc<Mutex<Option<ChildStdin>>>;
+
+/// A cluster of node binaries. Messages between nodes are routed from each
+/// node's stdout to the destination's stdin; everything else is handed to
+/// the test.
+struct Cluster {
+    children: Vec<Child>,
+    stdins: HashMap<String, Stdin>,
+    to_clients: Receiver<Value>,
+    /// What each node logs, collected until it exits.
+    logs: Vec<thread::JoinHandle<String>>,
+    next_id: u64,
+}
+
+impl Cluster {
+    fn start(bin: &str, node_ids: &[&str]) -> Self {
+        Self::start_with_env(bin, node_ids, &[])
+    }
+
+    fn start_with_env(bin: &str, node_ids: &[&str], env: &[(&str, &str)]) -> Self {
+        let nodes = node_ids.join(",");
+        let (client_tx, to_clients) = mpsc::channel();
+        let mut children = Vec::new();
+        let mut stdins = HashMap::new();
+        let mut stdouts = Vec::new();
+        let mut logs = Vec::new();
+        for node_id in node_ids {
+            let mut child = Command::new(bin)
+                .args(["--node-id", node_id, "--nodes", &nodes])
+                .envs(env.iter().copied())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .expect("spawn node binary");
+            stdins.insert(
+                node_id.to_string(),
+                Arc::new(Mutex::new(child.stdin.take())),
+            );
+            stdouts.push(child.stdout.take().expect("piped stdout"));
+            let mut stderr = child.stderr.take().expect("piped stderr");
+            logs.push(thread::spawn(move || {
+                let mut logs = String::new();
+                let _ = stderr.read_to_string(&mut logs);
+                logs
+            }));
+            children.push(child);
+        }
+        for stdout in stdouts {
+            let stdins = stdins.clone();
+            let client_tx = client_tx.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else { break };
+                    let msg: Value = serde_json::from_str(&line).expect("message is JSON");
+                    match msg["dest"].as_str().and_then(|dest| stdins.get(dest)) {
+                        Some(stdin) => write_line(stdin, &msg),
+                        None => {
+                            let _ = client_tx.send(msg);
+                        }
+                    }
+                }
+            });
+        }
+        Self {
+            children,
+            stdins,
+            to_clients,
+            logs,
+            next_id: 1,
+        }
+    }
+
+    /// Sends `body` from client `c1` to `node` and waits for the reply.
+    fn rpc(&mut self, node: &str, mut body: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        body["msg_id"] = id.into();
+        write_line(
+            &self.stdins[node],
+            &json!({ "src": "c1", "dest": node, "body": body }),
+        );
+        loop {
+            match self.to_clients.recv_timeout(Duration::from_secs(5)) {
+                Ok(msg) if msg["body"]["in_reply_to"] == id => return msg,
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => panic!("{} didn't reply", node),
+                Err(RecvTimeoutError::Disconnected) => panic!("all nodes exited"),
+            }
+        }
+    }
+
+    /// Closes every node's stdin and waits for the nodes to exit.
+    fn finish(self) {
+        self.finish_with_logs();
+    }
+
+    /// Like `finish`, returning what each node logged, in start order.
+    fn finish_with_logs(mut self) -> Vec<String> {
+        for stdin in self.stdins.values() {
+            stdin.lock().unwrap().take();
+        }
+        for child in &mut self.children {
+            let status = child.wait().expect("wait for node");
+            assert!(status.success(), "node exited with {}", status);
+        }
+        self.logs
+            .into_iter()
+            .map(|logs| logs.join().expect("read node stderr"))
+            .collect()
+    }
+}
+
+fn write_line(stdin: &Stdin, msg: &Value) {
+    if let Some(stdin) = stdin.lock().unwrap().as_mut() {
+        let _ = writeln!(stdin, "{}", msg).and_then(|()| stdin.flush());
+    }
+}
+
+/// Reads `node` until its messages are `expected`, failing after 5 seconds.
+fn wait_for_messages(cluster: &mut Cluster, node: &str, expected: &[u64]) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let reply = cluster.rpc(node, json!({ "type": "read" }));
+        let mut messages: Vec<u64> =
+            serde_json::from_value(reply["body"]["messages"].clone()).unwrap();
+        messages.sort_unstable();
+        if messages == expected {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{} only has {:?}",
+            node,
+            messages
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn broadcast_nodes_started_without_init_converge() {
+    let mut cluster = Cluster::start(env!("CARGO_BIN_EXE_broadcast"), &["n1", "n2"]);
+    let topology = json!({ "n1": ["n2"], "n2": ["n1"] });
+    for node in ["n1", "n2"] {
+        let reply = cluster.rpc(node, json!({ "type": "topology", "topology": topology }));
+        assert_eq!(reply["body"]["type"], "topology_ok");
+    }
+    cluster.rpc("n1", json!({ "type": "broadcast", "message": 1 }));
+    cluster.rpc("n2", json!({ "type": "broadcast", "message": 2 }));
+
+    for node in ["n1", "n2"] {
+        wait_for_messages(&mut cluster, node, &[1, 2]);
+    }
+    cluster.finish();
+}
+
+#[test]
+fn broadcast_nodes_converge_through_digest_gossip() {
+    let mut cluster = Cluster::start_with_env(
+        env!("CARGO_BIN_EXE_broadcast"),
+        &["n1", "n2"],
+        &[("GLOMERS_DIGEST_GOSSIP", "1")],
+    );
+    // Broadcast before the topology, so only gossip can spread the messages
+    for message in 1..=20 {
+        cluster.rpc("n1", json!({ "type": "broadcast", "message": message }));
+        cluster.rpc(
+            "n2",
+            json!({ "type": "broadcast", "message": message + 20 }),
+        );
+    }
+    let topology = json!({ "n1": ["n2"], "n2": ["n1"] });
+    for node in ["n1", "n2"] {
+        cluster.rpc(node, json!({ "type": "topology", "topology": topology }));
+    }
+    let all: Vec<u64> = (1..=40).collect();
+    for node in ["n1", "n2"] {
+        wait_for_messages(&mut cluster, node, &all);
+    }
+    cluster.finish();
+}
+
+#[test]
+fn broadcast_nodes_converge_gossiping_to_one_neighbor_per_tick() {
+    let nodes = ["n1", "n2", "n3", "n4"];
+    let mut cluster = Cluster::start_with_env(
+        env!("CARGO_BIN_EXE_broadcast"),
+        &nodes,
+        &[("GLOMERS_GOSSIP_FANOUT", "1")],
+    );
+    // Broadcast before the topology, so only gossip can spread the messages
+    for (i, node) in nodes.iter().enumerate() {
+        cluster.rpc(node, json!({ "type": "broadcast", "message": i + 1 }));
+    }
+    let topology: HashMap<&str, Vec<&str>> = nodes
+        .iter()
+        .map(|node| {
+            (
+                *node,
+                nodes.iter().filter(|n| *n != node).copied().collect(),
+            )
+        })
+        .collect();
+    for node in nodes {
+        cluster.rpc(node, json!({ "type": "topology", "topology": topology }));
+    }
+    for node in nodes {
+        wait_for_messages(&mut cluster, node, &[1, 2, 3, 4]);
+    }
+    cluster.finish();
+}
+
+#[test]
+fn broadcast_nodes_converge_gossiping_with_fewer_neighbors_than_the_topology() {
+    let nodes = ["n1", "n2", "n3", "n4", "n5"];
+    let mut cluster = Cluster::start_with_env(
+        env!("CARGO_BIN_EXE_broadcast"),
+        &nodes,
+        &[("GLOMERS_GOSSIP_NEIGHBORS", "1")],
+    );
+    // Broadcast before the topology, so only gossip can spread the messages
+    for (i, node) in nodes.iter().enumerate() {
+        cluster.rpc(node, json!({ "type": "broadcast", "message": i + 1 }));
+    }
+    let topology: HashMap<&str, Vec<&str>> = nodes
+        .iter()
+        .map(|node| {
+            (
+                *node,
+                nodes.iter().filter(|n| *n != node).copied().collect(),
+            )
+        })
+        .collect();
+    for node in nodes {
+        cluster.rpc(node, json!({ "type": "topology", "topology": topology }));
+    }
+    for node in nodes {
+        wait_for_messages(&mut cluster, node, &[1, 2, 3, 4, 5]);
+    }
+    cluster.finish();
+}
+
+#[test]
+fn broadcast_nodes_log_identical_sets_at_exit() {
+    let nodes = ["n1", "n2"];
+    let mut cluster = Cluster::start_with_env(
+        env!("CARGO_BIN_EXE_broadcast"),
+        &nodes,
+        &[("GLOMERS_SNAPSHOT_AT_EXIT", "1")],
+    );
+    let topology = json!({ "n1": ["n2"], "n2": ["n1"] });
+    for node in nodes {
+        cluster.rpc(node, json!({ "type": "topology", "topology": topology }));
+    }
+    cluster.rpc("n1", json!({ "type": "broadcast", "message": 1 }));
+    cluster.rpc("n2", json!({ "type": "broadcast", "message": 2 }));
+    for node in nodes {
+        wait_for_messages(&mut cluster, node, &[1, 2]);
+    }
+    let sets: Vec<Value> = cluster
+        .finish_with_logs()
+        .iter()
+        .map(|logs| {
+            let snapshot = logs
+                .lines()
+                .find_map(|line| line.split_once("final snapshot: "))
+                .unwrap_or_else(|| panic!("no snapshot in {}", logs))
+                .1;
+            serde_json::from_str::<Value>(snapshot).unwrap()["seen"].clone()
+        })
+        .collect();
+    assert_eq!(sets, [json!([1, 2]), json!([1, 2])]);
+}
+
+#[test]
+fn gset_nodes_adding_disjoint_elements_converge() {
+    let nodes = ["n1", "n2", "n3"];
+    let mut cluster = Cluster::start(env!("CARGO_BIN_EXE_gset"), &nodes);
+    let elements = [json!(1), json!("two"), json!({ "three": 3 })];
+    for (node, element) in nodes.iter().zip(&elements) {
+        let reply = cluster.rpc(node, json!({ "type": "add", "element": element }));
+        assert_eq!(reply["body"]["type"], "add_ok");
+    }
+    let mut expected: Vec<String> = elements.iter().map(Value::to_string).collect();
+    expected.sort_unstable();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    for node in nodes {
+        loop {
+            let reply = cluster.rpc(node, json!({ "type": "read" }));
+            let value = reply["body"]["value"].as_array().expect("value is a list");
+            let mut have: Vec<String> = value.iter().map(Value::to_string).collect();
+            have.sort_unstable();
+            if have == expected {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{} only has {:?}", node, have);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+    cluster.finish();
+}
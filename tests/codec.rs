@@ -0,0 +1,104 @@
+//! Tests of the MessagePack wire format, against bytes laid out by hand from
+//! the MessagePack spec. Run with `cargo test --features msgpack`.
+#![cfg(feature = "msgpack")]
+
+use gossip_glomers::{codec, Body, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Echo { echo: String },
+    Poll { offsets: Vec<(String, i64)> },
+}
+
+/// A MessagePack fixstr.
+fn str(s: &str) -> Vec<u8> {
+    assert!(s.len() < 32);
+    let mut bytes = vec![0xa0 | s.len() as u8];
+    bytes.extend(s.as_bytes());
+    bytes
+}
+
+#[test]
+fn a_message_is_a_length_prefixed_messagepack_map() {
+    let msg = Message {
+        src: "n1".to_string(),
+        dest: "n2".to_string(),
+        body: Body {
+            id: Some(1),
+            in_reply_to: None,
+            payload: Payload::Echo {
+                echo: "hi".to_string(),
+            },
+        },
+    };
+    // Keys come out sorted, as serde_json orders them
+    let mut map = vec![0x83];
+    map.extend(str("body"));
+    map.push(0x83);
+    map.extend(str("echo"));
+    map.extend(str("hi"));
+    map.extend(str("msg_id"));
+    map.push(0x01);
+    map.extend(str("type"));
+    map.extend(str("echo"));
+    map.extend(str("dest"));
+    map.extend(str("n2"));
+    map.extend(str("src"));
+    map.extend(str("n1"));
+    let mut frame = (map.len() as u32).to_be_bytes().to_vec();
+    frame.extend(&map);
+
+    assert_eq!(codec::encode(&msg).unwrap(), frame);
+    let decoded: Message<Payload> = codec::decode(&map).unwrap();
+    assert_eq!(decoded.body.payload, msg.body.payload);
+    assert_eq!(decoded.body.id, Some(1));
+    assert_eq!(decoded.body.in_reply_to, None);
+}
+
+#[test]
+fn every_number_format_of_the_spec_decodes() {
+    let mut array = vec![0x9a];
+    array.extend([0xcc, 0xc8]); // uint 8
+    array.extend([0xcd, 0x01, 0x00]); // uint 16
+    array.extend([0xce, 0x00, 0x01, 0x00, 0x00]); // uint 32
+    array.extend([0xd0, 0x9c]); // int 8
+    array.extend([0xd1, 0xff, 0x00]); // int 16
+    array.extend([0xd2, 0xff, 0xff, 0x00, 0x00]); // int 32
+    array.push(0xff); // negative fixint
+    array.extend([0xca, 0x3f, 0xc0, 0x00, 0x00]); // float 32
+    array.extend([0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]); // float 64
+    array.push(0xc0); // nil
+    let decoded: Value = codec::decode(&array).unwrap();
+    assert_eq!(
+        decoded,
+        json!([200, 256, 65536, -100, -256, -65536, -1, 1.5, 1.5, null])
+    );
+}
+
+#[test]
+fn a_message_round_trips_through_the_msgpack_frame() {
+    let msg = Message {
+        src: "c1".to_string(),
+        dest: "n1".to_string(),
+        body: Body {
+            id: Some(7),
+            in_reply_to: Some(1 << 40),
+            payload: Payload::Poll {
+                offsets: vec![("k".repeat(40), i64::MIN), ("k2".to_string(), 300)],
+            },
+        },
+    };
+    let frame = codec::encode(&msg).unwrap();
+    let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    assert_eq!(len, frame.len() - 4);
+    let decoded: Message<Payload> = codec::decode(&frame[4..]).unwrap();
+    assert_eq!(decoded.src, msg.src);
+    assert_eq!(decoded.dest, msg.dest);
+    assert_eq!(decoded.body.id, msg.body.id);
+    assert_eq!(decoded.body.in_reply_to, msg.body.in_reply_to);
+    assert_eq!(decoded.body.payload, msg.body.payload);
+}
@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+
+use gossip_glomers::crdt::{gossip_round, Gossip, GrowOnlyCounter, GrowOnlySet};
+use gossip_glomers::liveness::Liveness;
+use tokio::sync::Mutex;
+
+fn set(peers: &[&str]) -> GrowOnlySet<usize> {
+    let mut set = GrowOnlySet::new(peers.iter().map(|peer| peer.to_string()));
+    for value in [1, 2, 3] {
+        set.insert(value);
+    }
+    set
+}
+
+#[test]
+fn values_every_peer_acked_are_not_tracked_per_peer() {
+    let mut set = set(&["n2", "n3"]);
+    set.mark_known("n2", [1, 2]);
+    assert_eq!(set.tracked("n2"), 2);
+    set.mark_known("n3", [1]);
+    assert_eq!(set.tracked("n2"), 1);
+    assert_eq!(set.tracked("n3"), 0);
+    // Compaction doesn't change what each peer is known to have
+    assert!(set.is_known("n3", &1));
+    assert!(!set.is_known("n3", &2));
+    assert_eq!(set.missing("n2"), Some(HashSet::from([3])));
+    assert_eq!(set.missing("n3"), Some(HashSet::from([2, 3])));
+    // Acking a value again doesn't bring it back
+    set.mark_known("n2", [1]);
+    assert_eq!(set.tracked("n2"), 1);
+}
+
+#[test]
+fn compacting_over_neighbors_ignores_what_other_peers_know() {
+    let mut set = set(&["n2", "n3", "n4"]);
+    set.mark_known("n2", [1, 2]);
+    set.mark_known("n3", [1]);
+    assert_eq!(set.tracked("n2"), 2);
+    set.compact_over(["n2".to_string(), "n3".to_string()]);
+    assert_eq!(set.tracked("n2"), 1);
+    assert_eq!(set.tracked("n3"), 0);
+    assert!(!set.is_known("n4", &1));
+    assert_eq!(set.missing("n4"), Some(HashSet::from([1, 2, 3])));
+    // Widening the group again keeps what was compacted known
+    set.compact_over(["n2".to_string(), "n3".to_string(), "n4".to_string()]);
+    assert_eq!(set.tracked("n2"), 2);
+    assert!(set.is_known("n3", &1));
+}
+
+/// Runs a gossip round from every node, delivering each delta straight into
+/// the state of the peer it was sent to.
+async fn gossip_everywhere<G: Gossip>(
+    nodes: &HashMap<String, Mutex<G>>,
+    peers: &[String],
+    liveness: &Mutex<Liveness>,
+) {
+    for from in peers {
+        let others: Vec<String> = peers.iter().filter(|peer| *peer != from).cloned().collect();
+        gossip_round(
+            from,
+            &nodes[from],
+            &others,
+            liveness,
+            |peer, delta| async move {
+                nodes[peer].lock().await.merge_delta(from, delta);
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+    }
+}
+
+fn cluster<G>(new: impl Fn(Vec<String>) -> G) -> (Vec<String>, HashMap<String, Mutex<G>>) {
+    let ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).into();
+    let nodes = ids
+        .iter()
+        .map(|id| {
+            let peers = ids.iter().filter(|peer| *peer != id).cloned().collect();
+            (id.clone(), Mutex::new(new(peers)))
+        })
+        .collect();
+    (ids, nodes)
+}
+
+#[tokio::test]
+async fn sets_converge_after_a_gossip_round_from_every_node() {
+    let (ids, nodes) = cluster(GrowOnlySet::new);
+    for (value, id) in ids.iter().enumerate() {
+        nodes[id].lock().await.insert(value);
+    }
+    gossip_everywhere(&nodes, &ids, &Mutex::new(Liveness::default())).await;
+    for id in &ids {
+        assert_eq!(nodes[id].lock().await.values(), &HashSet::from([0, 1, 2]));
+    }
+}
+
+#[tokio::test]
+async fn counters_converge_but_skip_peers_that_are_down() {
+    let (ids, nodes) = cluster(|_| GrowOnlyCounter::new(["n1", "n2", "n3"].map(String::from)));
+    for (delta, id) in ids.iter().enumerate() {
+        nodes[id].lock().await.increment(id, delta as u64 + 1);
+    }
+    let mut liveness = Liveness::default();
+    liveness.record("n3", false);
+    gossip_everywhere(&nodes, &ids, &Mutex::new(liveness)).await;
+    assert_eq!(nodes["n1"].lock().await.value(), 6);
+    assert_eq!(nodes["n2"].lock().await.value(), 6);
+    assert_eq!(nodes["n3"].lock().await.value(), 3);
+    gossip_everywhere(&nodes, &ids, &Mutex::new(Liveness::default())).await;
+    assert_eq!(nodes["n3"].lock().await.value(), 6);
+}
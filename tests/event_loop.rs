@@ -0,0 +1,965 @@
+//! Tests that run a node in-process through `event_loop_with`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use gossip_glomers::{
+    event_loop_with, retry, spawn_timer, spawn_timers, Body, Config, Event, Handled, Init, Message,
+    Node, Output, PanicPolicy, Periodic, Serial, SerialNode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+struct EchoNode {
+    id: AtomicUsize,
+    stdout: Output,
+}
+
+#[async_trait]
+impl Node<Payload> for EchoNode {
+    const NAME: &'static str = "echo";
+
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: 1.into(),
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        if let Payload::Echo { echo } = message.body.payload.clone() {
+            message
+                .into_reply_with(Some(&self.id), Payload::EchoOk { echo })
+                .send(&self.stdout)
+                .await
+                .context("send response message")?;
+        }
+        Ok(())
+    }
+}
+
+/// A node that starts sending from a background task as soon as it exists.
+struct EagerNode;
+
+#[async_trait]
+impl Node<Payload> for EagerNode {
+    const NAME: &'static str = "eager";
+
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        let hello = Message {
+            src: init.node_id,
+            dest: "n2".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Echo {
+                    echo: "early".to_string(),
+                },
+            },
+        };
+        tokio::spawn(async move { hello.send(&stdout).await });
+        Ok(Self)
+    }
+
+    async fn handle(&self, _event: Event<Payload>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// An echo node that defers each echo twice before answering it, as if the
+/// data it needed took a while to show up.
+struct SlowEchoNode {
+    echo: EchoNode,
+    deliveries: AtomicUsize,
+}
+
+#[async_trait]
+impl Node<Payload> for SlowEchoNode {
+    const NAME: &'static str = "slow-echo";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+            deliveries: AtomicUsize::new(0),
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        self.echo.handle(event).await
+    }
+
+    async fn handle_or_defer(&self, event: Event<Payload>) -> anyhow::Result<Handled<Payload>> {
+        if let Event::Message(_) = event {
+            if self.deliveries.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Ok(Handled::Defer(DEFER, event));
+            }
+        }
+        self.handle(event).await?;
+        Ok(Handled::Done)
+    }
+}
+
+const DEFER: Duration = Duration::from_millis(50);
+
+/// Injected events of `TickNode`, on two timers.
+#[derive(Debug, Clone)]
+enum Tick {
+    Fast,
+    Slow,
+}
+
+impl Periodic for Tick {
+    fn schedule() -> Vec<(Duration, Self)> {
+        vec![
+            (Duration::from_millis(10), Self::Fast),
+            (Duration::from_millis(25), Self::Slow),
+        ]
+    }
+}
+
+/// A node that echoes every tick of its timers to `n2`.
+struct TickNode {
+    node: String,
+    stdout: Output,
+}
+
+#[async_trait]
+impl Node<Payload, Tick> for TickNode {
+    const NAME: &'static str = "tick";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload, Tick>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        spawn_timers(&tx);
+        Ok(Self {
+            node: init.node_id,
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload, Tick>) -> anyhow::Result<()> {
+        let Event::Injected(tick) = event else {
+            return Ok(());
+        };
+        let echo = Message {
+            src: self.node.clone(),
+            dest: "n2".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Echo {
+                    echo: format!("{:?}", tick),
+                },
+            },
+        };
+        echo.send(&self.stdout).await
+    }
+}
+
+/// A node whose handlers wait forever, like an RPC whose reply never comes.
+struct StuckNode;
+
+#[async_trait]
+impl Node<Payload> for StuckNode {
+    const NAME: &'static str = "stuck";
+
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        _stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::Message(_) = event {
+            std::future::pending::<()>().await;
+        }
+        Ok(())
+    }
+}
+
+/// An echo node whose handlers for `c1` wait forever, like a client
+/// flooding the node with requests that each take long to serve.
+struct FloodedNode {
+    echo: EchoNode,
+}
+
+#[async_trait]
+impl Node<Payload> for FloodedNode {
+    const NAME: &'static str = "flooded";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::Message(message) = &event {
+            if message.src == "c1" {
+                std::future::pending::<()>().await;
+            }
+        }
+        self.echo.handle(event).await
+    }
+}
+
+/// An echo node that panics on echoes of `boom`.
+struct PanickyNode {
+    echo: EchoNode,
+}
+
+#[async_trait]
+impl Node<Payload> for PanickyNode {
+    const NAME: &'static str = "panicky";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::Message(message) = &event {
+            if matches!(&message.body.payload, Payload::Echo { echo } if echo == "boom") {
+                panic!("malformed echo");
+            }
+        }
+        self.echo.handle(event).await
+    }
+}
+
+/// An echo node that takes a while over echoes of `slow`.
+struct SlowOnDemandNode {
+    echo: EchoNode,
+}
+
+#[async_trait]
+impl Node<Payload> for SlowOnDemandNode {
+    const NAME: &'static str = "slow-on-demand";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::Message(message) = &event {
+            if matches!(&message.body.payload, Payload::Echo { echo } if echo == "slow") {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+        self.echo.handle(event).await
+    }
+}
+
+/// An echo node that tells `n2` about every reply it's handed, and about every
+/// reply that reached `handle` instead.
+struct ReplyNode {
+    echo: EchoNode,
+}
+
+impl ReplyNode {
+    async fn report(&self, echo: &str) -> anyhow::Result<()> {
+        let report = Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: Payload::Echo {
+                    echo: echo.to_string(),
+                },
+            },
+        };
+        report.send(&self.echo.stdout).await
+    }
+}
+
+#[async_trait]
+impl Node<Payload> for ReplyNode {
+    const NAME: &'static str = "reply";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::Message(message) = &event {
+            if message.body.in_reply_to.is_some() {
+                return self.report("reply in handle").await;
+            }
+        }
+        self.echo.handle(event).await
+    }
+
+    async fn handle_reply(&self, reply: Message<Payload>) -> anyhow::Result<()> {
+        let Payload::EchoOk { echo } = reply.body.payload else {
+            anyhow::bail!("not a reply payload");
+        };
+        self.report(&format!("reply {}", echo)).await
+    }
+}
+
+/// An echo node that numbers its echoes in a plain field, with no
+/// interior mutability.
+struct CountingNode {
+    echoes: usize,
+    stdout: Output,
+}
+
+#[async_trait]
+impl SerialNode<Payload> for CountingNode {
+    const NAME: &'static str = "counting";
+
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self { echoes: 0, stdout })
+    }
+
+    async fn handle(&mut self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        if let Payload::Echo { echo } = message.body.payload.clone() {
+            self.echoes += 1;
+            let echo = format!("{} #{}", echo, self.echoes);
+            message
+                .into_reply_with(None, Payload::EchoOk { echo })
+                .send(&self.stdout)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// A serial node with a check of its own that always fails, and a banner
+/// of its own.
+struct PickyNode;
+
+#[async_trait]
+impl SerialNode<Payload> for PickyNode {
+    const NAME: &'static str = "picky";
+
+    fn from_init(
+        _init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        _stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::bail!("picky about everything")
+    }
+
+    fn describe(&self, init: &Init, _config: &Config) -> String {
+        format!("picky {}", init.node_id)
+    }
+
+    async fn handle(&mut self, _event: Event<Payload>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// An echo node that answers with as many echoes as it takes to fit the echo
+/// in `max_message_bytes`, each carrying a part of it.
+struct SplitEchoNode {
+    echo: EchoNode,
+}
+
+#[async_trait]
+impl Node<Payload> for SplitEchoNode {
+    const NAME: &'static str = "split-echo";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        let Event::Message(message) = event else {
+            return Ok(());
+        };
+        let Payload::Echo { echo } = message.body.payload.clone() else {
+            return Ok(());
+        };
+        let chars: Vec<char> = echo.chars().collect();
+        let parts = Message::split_to_fit(&self.echo.stdout, &chars, &|part: &[char]| {
+            message.clone().into_reply_with(
+                None,
+                Payload::EchoOk {
+                    echo: part.iter().collect(),
+                },
+            )
+        })?;
+        for part in parts {
+            part.send(&self.echo.stdout).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A node that says goodbye to `n2` once its input ends, a little later than
+/// it could, so the goodbye is still being sent as the event loop winds down.
+struct FarewellNode {
+    node: String,
+    stdout: Output,
+}
+
+#[async_trait]
+impl Node<Payload> for FarewellNode {
+    const NAME: &'static str = "farewell";
+
+    fn from_init(
+        init: Init,
+        _tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            node: init.node_id,
+            stdout,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        if let Event::EOF = event {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let goodbye = Message {
+                src: self.node.clone(),
+                dest: "n2".to_string(),
+                body: Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Echo {
+                        echo: "goodbye".to_string(),
+                    },
+                },
+            };
+            goodbye.send(&self.stdout).await?;
+        }
+        Ok(())
+    }
+}
+
+/// An echo node that paces five retries before answering.
+struct PacedEchoNode {
+    echo: EchoNode,
+}
+
+#[async_trait]
+impl Node<Payload> for PacedEchoNode {
+    const NAME: &'static str = "paced-echo";
+
+    fn from_init(
+        init: Init,
+        tx: tokio::sync::mpsc::Sender<Event<Payload>>,
+        stdout: Output,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            echo: EchoNode::from_init(init, tx, stdout)?,
+        })
+    }
+
+    async fn handle(&self, event: Event<Payload>) -> anyhow::Result<()> {
+        for _ in 0..5 {
+            retry::pace(&self.echo.stdout).await;
+        }
+        self.echo.handle(event).await
+    }
+}
+
+/// Runs node `N` on `input` and returns the messages it sent.
+async fn run<N: Node<Payload> + 'static>(input: &[Value]) -> Vec<Value> {
+    run_with::<N>(Config::default(), input).await
+}
+
+async fn run_with<N: Node<Payload> + 'static>(config: Config, input: &[Value]) -> Vec<Value> {
+    let input: String = input.iter().map(|msg| format!("{}\n", msg)).collect();
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    event_loop_with::<N, _, _, _, _>(std::io::Cursor::new(input.into_bytes()), writer, config)
+        .await
+        .unwrap();
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    raw.lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn init() -> Value {
+    json!({ "src": "c0", "dest": "n1", "body": {
+        "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"],
+    }})
+}
+
+#[tokio::test]
+async fn a_node_runs_from_an_in_memory_reader() {
+    let sent = run::<EchoNode>(&[
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "hello",
+        }}),
+    ])
+    .await;
+    assert_eq!(sent.len(), 2, "sent: {:?}", sent);
+    assert_eq!(sent[0]["body"]["type"], "init_ok");
+    assert_eq!(sent[1]["dest"], "c1");
+    assert_eq!(sent[1]["body"]["echo"], "hello");
+    assert_eq!(sent[1]["body"]["in_reply_to"], 2);
+}
+
+#[tokio::test]
+async fn a_deferred_message_is_delivered_again_until_handled() {
+    let start = Instant::now();
+    let sent = run::<SlowEchoNode>(&[
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "hello",
+        }}),
+    ])
+    .await;
+    assert!(start.elapsed() >= 2 * DEFER);
+    assert_eq!(sent.len(), 2, "sent: {:?}", sent);
+    assert_eq!(sent[1]["body"]["echo"], "hello");
+    assert_eq!(sent[1]["body"]["in_reply_to"], 2);
+}
+
+#[tokio::test]
+async fn nothing_is_sent_before_init_ok() {
+    let sent = run::<EagerNode>(&[init()]).await;
+    assert_eq!(sent.len(), 2, "sent: {:?}", sent);
+    assert_eq!(sent[0]["body"]["type"], "init_ok");
+    assert_eq!(sent[1]["body"]["echo"], "early");
+}
+
+#[tokio::test]
+async fn every_timer_of_the_schedule_injects_its_event() {
+    let (mut input, reader) = tokio::io::duplex(1 << 16);
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move {
+        input
+            .write_all(format!("{}\n", init()).as_bytes())
+            .await
+            .unwrap();
+        // Keep the input open for a few ticks of both timers
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    });
+    event_loop_with::<TickNode, _, _, _, _>(reader, writer, Config::default())
+        .await
+        .unwrap();
+
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    let echoes: Vec<Value> = raw
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"]["echo"].clone())
+        .collect();
+    assert!(echoes.contains(&json!("Fast")), "echoes: {:?}", echoes);
+    assert!(echoes.contains(&json!("Slow")), "echoes: {:?}", echoes);
+}
+
+#[tokio::test]
+async fn shutdown_aborts_handlers_that_outlive_the_deadline() {
+    let input = [
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "hello",
+        }}),
+    ];
+    let input: String = input.iter().map(|msg| format!("{}\n", msg)).collect();
+    let config = Config {
+        shutdown_deadline: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let (writer, _output) = tokio::io::duplex(1 << 16);
+    let start = Instant::now();
+    let run = event_loop_with::<StuckNode, _, _, _, _>(
+        std::io::Cursor::new(input.into_bytes()),
+        writer,
+        config,
+    );
+    tokio::time::timeout(Duration::from_secs(5), run)
+        .await
+        .expect("the event loop waited for the stuck handler")
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn replies_go_to_handle_reply_and_requests_to_handle() {
+    let sent = run::<ReplyNode>(&[
+        init(),
+        json!({ "src": "n2", "dest": "n1", "body": {
+            "type": "echo_ok", "in_reply_to": 5, "echo": "pong",
+        }}),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "hello",
+        }}),
+    ])
+    .await;
+    let echoes: Vec<&Value> = sent[1..].iter().map(|msg| &msg["body"]["echo"]).collect();
+    assert_eq!(sent.len(), 3, "sent: {:?}", sent);
+    assert!(echoes.contains(&&json!("reply pong")), "sent: {:?}", sent);
+    assert!(echoes.contains(&&json!("hello")), "sent: {:?}", sent);
+}
+
+#[tokio::test]
+async fn a_serial_node_mutates_itself_once_per_event() {
+    let echo = |id: usize| {
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": id, "echo": "hello",
+        }})
+    };
+    let sent = run::<Serial<CountingNode>>(&[init(), echo(2), echo(3), echo(4)]).await;
+    let mut echoes: Vec<&str> = sent[1..]
+        .iter()
+        .map(|msg| msg["body"]["echo"].as_str().unwrap())
+        .collect();
+    echoes.sort_unstable();
+    assert_eq!(echoes, ["hello #1", "hello #2", "hello #3"]);
+}
+
+#[tokio::test]
+async fn a_repeated_init_is_answered_without_resetting_the_node() {
+    let echo = |id: usize| {
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": id, "echo": "hello",
+        }})
+    };
+    let mut again = init();
+    again["body"]["msg_id"] = json!(7);
+    let sent = run::<Serial<CountingNode>>(&[init(), echo(2), again, echo(3)]).await;
+    let init_oks: Vec<&Value> = sent
+        .iter()
+        .filter(|msg| msg["body"]["type"] == "init_ok")
+        .map(|msg| &msg["body"]["in_reply_to"])
+        .collect();
+    assert_eq!(init_oks, [&json!(1), &json!(7)], "sent: {:?}", sent);
+    let mut echoes: Vec<&str> = sent
+        .iter()
+        .filter_map(|msg| msg["body"]["echo"].as_str())
+        .collect();
+    echoes.sort_unstable();
+    // The count carried on across the second init
+    assert_eq!(echoes, ["hello #1", "hello #2"]);
+}
+
+#[tokio::test]
+async fn a_flooding_source_leaves_handlers_for_the_others() {
+    let mut input = vec![init()];
+    for id in 2..12 {
+        input.push(json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": id, "echo": "flood",
+        }}));
+    }
+    input.push(json!({ "src": "c2", "dest": "n1", "body": {
+        "type": "echo", "msg_id": 12, "echo": "hello",
+    }}));
+    let input: String = input.iter().map(|msg| format!("{}\n", msg)).collect();
+    let config = Config {
+        max_handlers: Some(4),
+        max_handlers_per_source: Some(2),
+        shutdown_deadline: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    event_loop_with::<FloodedNode, _, _, _, _>(
+        std::io::Cursor::new(input.into_bytes()),
+        writer,
+        config,
+    )
+    .await
+    .unwrap();
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    let sent: Vec<Value> = raw
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let echo = sent
+        .iter()
+        .find(|msg| msg["dest"] == "c2")
+        .unwrap_or_else(|| panic!("c2 got no answer: {:?}", sent));
+    assert_eq!(echo["body"]["echo"], "hello");
+}
+
+#[tokio::test]
+async fn queued_requests_count_towards_max_handlers() {
+    let input = [
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "stuck",
+        }}),
+        json!({ "src": "c2", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 3, "echo": "hello",
+        }}),
+    ];
+    let input: String = input.iter().map(|msg| format!("{}\n", msg)).collect();
+    let config = Config {
+        ordered_per_source: true,
+        max_handlers: Some(1),
+        shutdown_deadline: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    event_loop_with::<FloodedNode, _, _, _, _>(
+        std::io::Cursor::new(input.into_bytes()),
+        writer,
+        config,
+    )
+    .await
+    .unwrap();
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    // c1's stuck handler holds the only one, so c2 waits in its queue
+    assert!(!raw.contains("\"c2\""), "sent: {}", raw);
+}
+
+#[tokio::test]
+async fn a_serial_node_is_validated_and_described_like_any_node() {
+    let init_of = || Init {
+        node_id: "n1".to_string(),
+        node_ids: vec!["n1".to_string()],
+    };
+    let (tx, _rx) = tokio::sync::mpsc::channel(1);
+    let (writer, _output) = tokio::io::duplex(1 << 16);
+    let node =
+        <Serial<PickyNode> as Node<Payload>>::from_init(init_of(), tx, Output::spawn(writer))
+            .unwrap();
+    assert_eq!(node.describe(&init_of(), &Config::default()), "picky n1");
+
+    let input = format!("{}\n", init());
+    let (writer, _output) = tokio::io::duplex(1 << 16);
+    let err = event_loop_with::<Serial<PickyNode>, _, _, _, _>(
+        std::io::Cursor::new(input.into_bytes()),
+        writer,
+        Config::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("picky about everything"),
+        "{:#}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn oversize_messages_are_split_to_the_limit_of_their_own_node() {
+    let echo = "x".repeat(400);
+    let input = [
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": echo,
+        }}),
+    ];
+    let small = Config {
+        max_message_bytes: 200,
+        ..Config::default()
+    };
+    // Two nodes in one process, each held to its own limit
+    let (split, whole) = tokio::join!(
+        run_with::<SplitEchoNode>(small, &input),
+        run::<SplitEchoNode>(&input)
+    );
+    let parts = &split[1..];
+    assert!(parts.len() > 2, "sent: {:?}", split);
+    for part in parts {
+        assert!(part.to_string().len() <= 200, "too big: {}", part);
+    }
+    let joined: String = parts
+        .iter()
+        .map(|part| part["body"]["echo"].as_str().unwrap())
+        .collect();
+    assert_eq!(joined, echo);
+    assert_eq!(whole.len(), 2, "sent: {:?}", whole);
+    assert_eq!(whole[1]["body"]["echo"], echo.as_str());
+}
+
+/// Runs `run` and returns how long it took to echo.
+async fn timed(run: impl std::future::Future<Output = Vec<Value>>) -> Duration {
+    let start = Instant::now();
+    let sent = run.await;
+    assert_eq!(sent[1]["body"]["echo"], "hello");
+    start.elapsed()
+}
+
+#[tokio::test]
+async fn retries_are_paced_by_the_rate_of_their_own_node() {
+    let input = [
+        init(),
+        json!({ "src": "c1", "dest": "n1", "body": {
+            "type": "echo", "msg_id": 2, "echo": "hello",
+        }}),
+    ];
+    let paced = Config {
+        retry_rate: Some(20.0),
+        retry_burst: 1,
+        ..Config::default()
+    };
+    // Two nodes in one process: only the paced one waits for tokens
+    let (slow, fast) = tokio::join!(
+        timed(run_with::<PacedEchoNode>(paced, &input)),
+        timed(run::<PacedEchoNode>(&input))
+    );
+    assert!(
+        slow >= Duration::from_millis(180),
+        "paced run took {:?}",
+        slow
+    );
+    assert!(
+        fast < Duration::from_millis(100),
+        "unpaced run took {:?}",
+        fast
+    );
+}
+
+#[tokio::test]
+async fn ticks_coalesce_in_a_full_channel_instead_of_blocking_the_timer() {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Event<Payload, ()>>(2);
+    let timer = spawn_timer(tx, Duration::from_millis(5), ());
+    // A stalled consumer: some 20 ticks come due, and only two fit
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut queued = 0;
+    while rx.try_recv().is_ok() {
+        queued += 1;
+    }
+    assert_eq!(queued, 2);
+    // The timer was never stuck on the full channel, and ticks on
+    let tick = tokio::time::timeout(Duration::from_millis(50), rx.recv()).await;
+    assert!(matches!(tick, Result::Ok(Some(Event::Injected(())))));
+    assert!(!timer.is_finished());
+    drop(rx);
+    tokio::time::timeout(Duration::from_millis(50), timer)
+        .await
+        .expect("the timer stops once the event loop is gone")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn a_message_sent_on_eof_is_flushed_before_the_event_loop_returns() {
+    let sent = run::<FarewellNode>(&[init()]).await;
+    assert_eq!(sent.len(), 2, "sent: {:?}", sent);
+    assert_eq!(sent[1]["dest"], "n2");
+    assert_eq!(sent[1]["body"]["echo"], "goodbye");
+}
+
+#[tokio::test]
+async fn ordered_sources_are_handled_in_receive_order_and_each_other_in_parallel() {
+    let echo = |src: &str, id: u64, echo: &str| {
+        json!({ "src": src, "dest": "n1", "body": {
+            "type": "echo", "msg_id": id, "echo": echo,
+        }})
+    };
+    let input = [
+        init(),
+        echo("c1", 2, "slow"),
+        echo("c1", 3, "fast"),
+        echo("c2", 4, "fast"),
+    ];
+    // Who got which echo, in the order the replies went out
+    let replies = |sent: Vec<Value>| -> Vec<String> {
+        sent[1..]
+            .iter()
+            .map(|msg| {
+                let dest = msg["dest"].as_str().unwrap();
+                format!("{} {}", dest, msg["body"]["echo"].as_str().unwrap())
+            })
+            .collect()
+    };
+    let ordered = Config {
+        ordered_per_source: true,
+        ..Config::default()
+    };
+    // c1's fast echo waits behind its slow one, c2's doesn't
+    let sent = run_with::<SlowOnDemandNode>(ordered, &input).await;
+    assert_eq!(replies(sent), ["c2 fast", "c1 slow", "c1 fast"]);
+    // Unordered, c1's fast echo overtakes the slow one
+    let sent = run::<SlowOnDemandNode>(&input).await;
+    assert_eq!(replies(sent).last().unwrap(), "c1 slow");
+}
+
+#[tokio::test]
+async fn a_panicking_handler_only_loses_its_own_event_under_continue() {
+    let echo = |src: &str, id: u64, echo: &str| {
+        json!({ "src": src, "dest": "n1", "body": {
+            "type": "echo", "msg_id": id, "echo": echo,
+        }})
+    };
+    let input = [
+        init(),
+        echo("c1", 2, "boom"),
+        echo("c1", 3, "hello"),
+        echo("c2", 4, "world"),
+    ];
+    // Ordered, so c1's next message waits on the handler that panicked
+    let config = Config {
+        panic_policy: PanicPolicy::Continue,
+        ordered_per_source: true,
+        ..Config::default()
+    };
+    let sent = run_with::<PanickyNode>(config, &input).await;
+    let mut echoes: Vec<&str> = sent[1..]
+        .iter()
+        .map(|msg| msg["body"]["echo"].as_str().unwrap())
+        .collect();
+    echoes.sort_unstable();
+    assert_eq!(echoes, ["hello", "world"]);
+}
@@ -0,0 +1,33 @@
+use std::collections::HashSet;
+
+use gossip_glomers::{fanout::Fanout, Rng};
+
+fn neighbors(n: usize) -> Vec<String> {
+    (1..=n).map(|i| format!("n{}", i)).collect()
+}
+
+#[test]
+fn every_neighbor_is_picked_once_per_pass() {
+    let neighbors = neighbors(10);
+    let mut fanout = Fanout::new(Some(3), Rng::with_seed(7));
+    // A pass over 10 neighbors 3 at a time takes 4 ticks
+    for _ in 0..25 {
+        let mut seen = HashSet::new();
+        for _ in 0..4 {
+            let picked = fanout.pick(&neighbors);
+            assert!(!picked.is_empty() && picked.len() <= 3, "{:?}", picked);
+            for node in picked {
+                assert!(seen.insert(node.clone()), "{} picked twice in a pass", node);
+            }
+        }
+        assert_eq!(seen.len(), neighbors.len());
+    }
+}
+
+#[test]
+fn the_default_size_is_about_the_square_root() {
+    let mut fanout = Fanout::new(None, Rng::with_seed(7));
+    assert_eq!(fanout.pick(&neighbors(16)).len(), 4);
+    assert_eq!(fanout.pick(&neighbors(1)).len(), 1);
+    assert!(fanout.pick(&[]).is_empty());
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use gossip_glomers::{KvError, KV};
+use serde_json::{json, Value};
+
+/// A store with the semantics of Maelstrom's KV services, held in memory.
+struct MemoryKv<T = i64> {
+    values: Mutex<HashMap<String, T>>,
+    /// Number of `read`s served.
+    reads: AtomicUsize,
+}
+
+impl<T> Default for MemoryKv<T> {
+    fn default() -> Self {
+        Self {
+            values: Mutex::new(HashMap::new()),
+            reads: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Clone + PartialEq + Send + Sync + 'static> KV<T> for MemoryKv<T> {
+    async fn read(&self, _storage: &str, key: String) -> anyhow::Result<T> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let values = self.values.lock().unwrap();
+        Ok(values.get(&key).ok_or(KvError::KeyDoesNotExist)?.clone())
+    }
+
+    async fn write(&self, _storage: &str, key: String, val: T) -> anyhow::Result<()> {
+        self.values.lock().unwrap().insert(key, val);
+        Ok(())
+    }
+
+    async fn cas(
+        &self,
+        _storage: &str,
+        key: String,
+        from: T,
+        to: T,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        // Let concurrent callers interleave, as with a store over the network
+        tokio::task::yield_now().await;
+        let mut values = self.values.lock().unwrap();
+        match values.get(&key) {
+            Some(current) if *current != from => Err(KvError::PreconditionFailed.into()),
+            None if !put => Err(KvError::KeyDoesNotExist.into()),
+            _ => {
+                values.insert(key, to);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A [`MemoryKv`] whose failed cas reports the current value, like
+/// Maelstrom's `lin-kv` with `current` in its error.
+#[derive(Default)]
+struct ReportingKv(MemoryKv);
+
+#[async_trait]
+impl KV<i64> for ReportingKv {
+    async fn read(&self, storage: &str, key: String) -> anyhow::Result<i64> {
+        self.0.read(storage, key).await
+    }
+
+    async fn write(&self, storage: &str, key: String, val: i64) -> anyhow::Result<()> {
+        self.0.write(storage, key, val).await
+    }
+
+    async fn cas(
+        &self,
+        storage: &str,
+        key: String,
+        from: i64,
+        to: i64,
+        put: bool,
+    ) -> anyhow::Result<()> {
+        self.0.cas(storage, key, from, to, put).await
+    }
+
+    async fn cas_or_current(
+        &self,
+        storage: &str,
+        key: String,
+        from: i64,
+        to: i64,
+        put: bool,
+    ) -> Result<(), (KvError, Option<i64>)> {
+        match self.0.cas(storage, key.clone(), from, to, put).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let current = self.0.values.lock().unwrap().get(&key).copied();
+                Err((KvError::classify(&e), current))
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn create_sets_a_fresh_key() {
+    let kv = MemoryKv::<i64>::default();
+    kv.create("seq-kv", "k".into(), 1).await.unwrap();
+    assert_eq!(kv.read("seq-kv", "k".into()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn create_fails_if_the_key_exists() {
+    let kv = MemoryKv::<i64>::default();
+    kv.write("seq-kv", "k".into(), 1).await.unwrap();
+    let err = kv.create("seq-kv", "k".into(), 2).await.unwrap_err();
+    assert_eq!(KvError::classify(&err), KvError::KeyAlreadyExists);
+    assert_eq!(kv.read("seq-kv", "k".into()).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn incr_creates_a_missing_key_from_zero() {
+    let kv = MemoryKv::<i64>::default();
+    assert_eq!(kv.incr("seq-kv", "k".into(), 5).await.unwrap(), 5);
+    assert_eq!(kv.incr("seq-kv", "k".into(), 2).await.unwrap(), 7);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_incrs_add_up() {
+    let kv = Arc::new(MemoryKv::<i64>::default());
+    let callers: Vec<_> = (0..4)
+        .map(|_| {
+            let kv = kv.clone();
+            tokio::spawn(async move {
+                let mut last = 0;
+                for _ in 0..50 {
+                    let value = kv.incr("seq-kv", "k".into(), 1).await.unwrap();
+                    assert!(value > last, "{} after {}", value, last);
+                    last = value;
+                }
+            })
+        })
+        .collect();
+    for caller in callers {
+        caller.await.unwrap();
+    }
+    assert_eq!(kv.read("seq-kv", "k".into()).await.unwrap(), 200);
+}
+
+#[tokio::test]
+async fn read_typed_names_the_key_and_both_types_on_a_mismatch() {
+    let kv = MemoryKv::<Value>::default();
+    kv.write("lin-kv", "counter".into(), json!("seven"))
+        .await
+        .unwrap();
+    let err = kv
+        .read_typed::<i64>("lin-kv", "counter".into())
+        .await
+        .unwrap_err();
+    let text = format!("{:#}", err);
+    for part in ["lin-kv", "\"counter\"", "string", "\"seven\"", "i64"] {
+        assert!(text.contains(part), "{:?} not in {}", part, text);
+    }
+    kv.write("lin-kv", "counter".into(), json!(7))
+        .await
+        .unwrap();
+    let value: i64 = kv.read_typed("lin-kv", "counter".into()).await.unwrap();
+    assert_eq!(value, 7);
+}
+
+#[tokio::test]
+async fn watch_yields_the_value_written_after_it_started() {
+    let kv = Arc::new(MemoryKv::<i64>::default());
+    let mut watch = kv.watch("lin-kv", "k".into(), Duration::from_millis(5));
+    let writer = {
+        let kv = kv.clone();
+        tokio::spawn(async move {
+            kv.write("lin-kv", "k".into(), 1).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            // Rewriting the same value isn't a change
+            kv.write("lin-kv", "k".into(), 1).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            kv.write("lin-kv", "k".into(), 2).await.unwrap();
+        })
+    };
+    assert_eq!(watch.next().await.unwrap(), 1);
+    assert_eq!(watch.next().await.unwrap(), 2);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn a_failed_cas_falls_back_to_reading_the_current_value() {
+    let kv = MemoryKv::<i64>::default();
+    kv.write("lin-kv", "k".into(), 5).await.unwrap();
+    let failed = kv.cas_or_current("lin-kv", "k".into(), 1, 2, false).await;
+    assert_eq!(failed, Err((KvError::PreconditionFailed, Some(5))));
+    assert_eq!(kv.reads.load(Ordering::Relaxed), 1);
+    // Only a precondition failure has a current value to read
+    let failed = kv
+        .cas_or_current("lin-kv", "missing".into(), 1, 2, false)
+        .await;
+    assert_eq!(failed, Err((KvError::KeyDoesNotExist, None)));
+    assert_eq!(kv.reads.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn incr_retries_from_the_value_a_failed_cas_reports_without_reading() {
+    let kv = ReportingKv::default();
+    kv.write("lin-kv", "k".into(), 5).await.unwrap();
+    assert_eq!(kv.incr("lin-kv", "k".into(), 2).await.unwrap(), 7);
+    assert_eq!(kv.0.reads.load(Ordering::Relaxed), 0);
+    // The fallback needs a read for the same retry
+    let kv = MemoryKv::<i64>::default();
+    kv.write("lin-kv", "k".into(), 5).await.unwrap();
+    assert_eq!(kv.incr("lin-kv", "k".into(), 2).await.unwrap(), 7);
+    assert_eq!(kv.reads.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+
+use gossip_glomers::metrics::Throughput;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn the_rate_reflects_a_burst_until_it_leaves_the_window() {
+    let start = Instant::now();
+    let mut throughput = Throughput::new(Duration::from_secs(1));
+    assert_eq!(throughput.rate(start), 0.0);
+    for i in 0..50 {
+        throughput.record(start + ms(i));
+    }
+    assert_eq!(throughput.rate(start + ms(500)), 50.0);
+    // A second, smaller burst half a second later
+    for i in 0..10 {
+        throughput.record(start + ms(600 + i));
+    }
+    assert_eq!(throughput.rate(start + ms(900)), 60.0);
+    // The first burst has left the window, the second hasn't
+    assert_eq!(throughput.rate(start + ms(1300)), 10.0);
+    assert_eq!(throughput.rate(start + ms(2000)), 0.0);
+}
+
+#[test]
+fn a_short_window_scales_the_rate_to_per_second() {
+    let start = Instant::now();
+    let mut throughput = Throughput::new(ms(100));
+    for i in 0..5 {
+        throughput.record(start + ms(i));
+    }
+    assert_eq!(throughput.rate(start + ms(50)), 50.0);
+}
@@ -0,0 +1,180 @@
+use gossip_glomers::{Body, Message, Output};
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+fn message(dest: &str, in_reply_to: Option<usize>, payload: Value) -> Message<Value> {
+    Message {
+        src: "n1".to_string(),
+        dest: dest.to_string(),
+        body: Body {
+            id: None,
+            in_reply_to,
+            payload,
+        },
+    }
+}
+
+#[tokio::test]
+async fn replies_are_written_before_earlier_queued_gossip() {
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    let out = Output::spawn(writer);
+    let gossip = message("n2", None, json!({ "type": "gossip" }));
+    let reply = message("c1", Some(1), json!({ "type": "read_ok" }));
+
+    // Both are queued before the writer task gets to run
+    let (gossip_sent, reply_sent) = tokio::join!(gossip.send(&out), reply.send(&out));
+    gossip_sent.unwrap();
+    reply_sent.unwrap();
+    drop(out);
+
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    let types: Vec<Value> = raw
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["body"]["type"].clone())
+        .collect();
+    assert_eq!(types, [json!("read_ok"), json!("gossip")]);
+}
+
+/// A writer whose first `fail` writes fail with `kind`, capturing the rest.
+struct FlakyWriter {
+    fail: usize,
+    kind: std::io::ErrorKind,
+    written: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl tokio::io::AsyncWrite for FlakyWriter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if self.fail > 0 {
+            self.fail -= 1;
+            return std::task::Poll::Ready(Err(self.kind.into()));
+        }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+fn flaky(
+    fail: usize,
+    kind: std::io::ErrorKind,
+) -> (Output, std::sync::Arc<std::sync::Mutex<Vec<u8>>>) {
+    let written = std::sync::Arc::default();
+    let writer = FlakyWriter {
+        fail,
+        kind,
+        written: std::sync::Arc::clone(&written),
+    };
+    (Output::spawn(writer), written)
+}
+
+#[tokio::test]
+async fn a_send_is_retried_after_a_transient_write_error() {
+    let (out, written) = flaky(1, std::io::ErrorKind::WouldBlock);
+    message("c1", Some(1), json!({ "type": "read_ok" }))
+        .send(&out)
+        .await
+        .unwrap();
+    let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+    assert_eq!(written.lines().count(), 1, "written: {}", written);
+    assert!(written.contains("read_ok"), "written: {}", written);
+}
+
+#[tokio::test]
+async fn a_broken_pipe_fails_the_send_without_retrying() {
+    let (out, written) = flaky(1, std::io::ErrorKind::BrokenPipe);
+    let err = message("c1", Some(1), json!({ "type": "read_ok" }))
+        .send(&out)
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("write message"), "{:#}", err);
+    assert!(written.lock().unwrap().is_empty());
+}
+
+#[cfg(debug_assertions)]
+#[tokio::test]
+#[should_panic(expected = "message id 7 sent twice")]
+async fn sending_two_messages_with_the_same_id_panics_in_debug_builds() {
+    let (writer, _output) = tokio::io::duplex(1 << 16);
+    let out = Output::spawn(writer);
+    let mut msg = message("n2", None, json!({ "type": "gossip" }));
+    msg.body.id = Some(7);
+    msg.send(&out).await.unwrap();
+    let mut reply = message("c1", Some(1), json!({ "type": "read_ok" }));
+    reply.body.id = Some(7);
+    let _ = reply.send(&out).await;
+}
+
+#[cfg(debug_assertions)]
+#[tokio::test]
+#[should_panic(expected = "message id 3 sent twice")]
+async fn an_id_reused_long_after_it_was_sent_still_panics() {
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    let out = Output::spawn(writer);
+    // Drain the output, so the writer never waits on it
+    tokio::spawn(async move { while output.read(&mut [0; 4096]).await.unwrap_or(0) > 0 {} });
+    for id in (0..10_000).chain([3]) {
+        let mut msg = message("n2", None, json!({ "type": "gossip" }));
+        msg.body.id = Some(id);
+        msg.send(&out).await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_spawned_task_and_a_handler_write_whole_lines_through_clones() {
+    // A pipe much smaller than a message, so every write goes out in pieces
+    let (writer, mut output) = tokio::io::duplex(64);
+    let reader = tokio::spawn(async move {
+        let mut raw = String::new();
+        output.read_to_string(&mut raw).await.unwrap();
+        raw
+    });
+    let out = Output::spawn(writer);
+    let send_all = |out: Output, dest: &'static str| async move {
+        for i in 0..100 {
+            let padding = "x".repeat(200);
+            message(
+                dest,
+                None,
+                json!({ "type": "gossip", "i": i, "padding": padding }),
+            )
+            .send(&out)
+            .await
+            .unwrap();
+        }
+    };
+    let background = tokio::spawn(send_all(out.clone(), "n2"));
+    send_all(out, "n3").await;
+    background.await.unwrap();
+
+    let raw = reader.await.unwrap();
+    let mut next = std::collections::HashMap::from([("n2", 0), ("n3", 0)]);
+    for line in raw.lines() {
+        let msg: Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+        // Each sender's messages also keep their order
+        let expected = next.get_mut(msg["dest"].as_str().unwrap()).unwrap();
+        assert_eq!(msg["body"]["i"], *expected);
+        *expected += 1;
+    }
+    assert_eq!(
+        next,
+        std::collections::HashMap::from([("n2", 100), ("n3", 100)])
+    );
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use gossip_glomers::{owner, Init};
+
+fn nodes(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+}
+
+#[test]
+fn every_node_agrees_on_the_owner_whatever_its_view_of_the_order() {
+    let keys: Vec<String> = (0..100).map(|i| format!("key-{}", i)).collect();
+    let n1 = Init {
+        node_id: "n1".to_string(),
+        node_ids: nodes(&["n1", "n2", "n3"]),
+    };
+    let n3 = Init {
+        node_id: "n3".to_string(),
+        node_ids: nodes(&["n3", "n1", "n2"]),
+    };
+    for key in &keys {
+        assert_eq!(n1.owner(key), n3.owner(key), "key {}", key);
+    }
+    // Every node owns some of the keys
+    let mut owned: HashMap<&str, usize> = HashMap::new();
+    for key in &keys {
+        *owned.entry(n1.owner(key)).or_default() += 1;
+    }
+    assert_eq!(owned.len(), 3, "owned: {:?}", owned);
+}
+
+#[test]
+fn owners_are_the_same_in_every_process() {
+    // Pinned values: a hasher seeded per process would fail this in some run
+    let cluster = nodes(&["n1", "n2", "n3", "n4", "n5"]);
+    let owners: Vec<&str> = ["a", "b", "k1", "k2", "topic"]
+        .iter()
+        .map(|key| owner(key, &cluster).unwrap())
+        .collect();
+    assert_eq!(owners, PINNED);
+}
+
+const PINNED: [&str; 5] = ["n5", "n4", "n2", "n3", "n1"];
+
+#[test]
+fn removing_a_node_only_moves_its_own_keys() {
+    let before = nodes(&["n1", "n2", "n3", "n4"]);
+    let after = nodes(&["n1", "n2", "n4"]);
+    for key in (0..100).map(|i| i.to_string()) {
+        let owner_before = owner(&key, &before).unwrap();
+        if owner_before != "n3" {
+            assert_eq!(owner(&key, &after), Some(owner_before), "key {}", key);
+        }
+    }
+    assert_eq!(owner("k", &[]), None);
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use gossip_glomers::retry::{Backoff, RateLimiter};
+use gossip_glomers::Rng;
+
+#[tokio::test]
+async fn rate_limiter_paces_acquisitions_beyond_the_burst() {
+    let limiter = RateLimiter::new(50.0, 2);
+    let start = Instant::now();
+    for _ in 0..2 {
+        limiter.acquire().await;
+    }
+    assert!(
+        start.elapsed() < Duration::from_millis(50),
+        "the burst was paced"
+    );
+    for _ in 0..5 {
+        limiter.acquire().await;
+    }
+    // Five more tokens at 50 per second take 100ms to refill
+    assert!(start.elapsed() >= Duration::from_millis(95));
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn backoff_delays_double_up_to_the_cap() {
+    let mut backoff = Backoff::new(ms(10), ms(50), 0.0, Rng::with_seed(1));
+    let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+    assert_eq!(delays, [ms(10), ms(20), ms(40), ms(50), ms(50), ms(50)]);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), ms(10));
+}
+
+#[test]
+fn backoff_jitter_stays_within_bounds() {
+    let mut backoff = Backoff::new(ms(100), ms(100), 0.5, Rng::with_seed(7));
+    let delays: Vec<Duration> = (0..100).map(|_| backoff.next_delay()).collect();
+    assert!(
+        delays
+            .iter()
+            .all(|delay| (ms(50)..=ms(100)).contains(delay)),
+        "delays: {:?}",
+        delays
+    );
+    assert!(
+        delays.iter().any(|delay| *delay != delays[0]),
+        "no jitter: {:?}",
+        delays
+    );
+}
+
+#[test]
+fn backoff_does_not_overflow_after_many_attempts() {
+    let mut backoff = Backoff::new(ms(1), Duration::MAX, 0.0, Rng::with_seed(1));
+    for _ in 0..100 {
+        backoff.next_delay();
+    }
+}
@@ -0,0 +1,39 @@
+use gossip_glomers::{Init, Rng};
+
+fn draws(mut rng: Rng) -> Vec<u64> {
+    (0..16).map(|_| rng.next_u64()).collect()
+}
+
+#[test]
+fn generators_with_the_same_seed_draw_the_same_sequence() {
+    assert_eq!(draws(Rng::with_seed(42)), draws(Rng::with_seed(42)));
+    assert_ne!(draws(Rng::with_seed(42)), draws(Rng::with_seed(43)));
+    // Forks are seeded from their parent, so they repeat too
+    let (mut a, mut b) = (Rng::with_seed(7), Rng::with_seed(7));
+    assert_eq!(draws(a.fork()), draws(b.fork()));
+    assert_eq!(draws(a), draws(b));
+}
+
+#[test]
+fn nodes_are_seeded_from_their_id() {
+    let init = |node_id: &str| Init {
+        node_id: node_id.to_string(),
+        node_ids: vec!["n1".to_string(), "n2".to_string()],
+    };
+    assert_eq!(draws(init("n1").rng()), draws(Rng::for_node("n1")));
+    assert_eq!(draws(init("n1").rng()), draws(init("n1").rng()));
+    assert_ne!(draws(init("n1").rng()), draws(init("n2").rng()));
+}
+
+#[test]
+fn shuffles_with_the_same_seed_agree() {
+    let shuffled = |seed| {
+        let mut items: Vec<u32> = (0..20).collect();
+        Rng::with_seed(seed).shuffle(&mut items);
+        items
+    };
+    assert_eq!(shuffled(1), shuffled(1));
+    let mut sorted = shuffled(1);
+    sorted.sort_unstable();
+    assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+}
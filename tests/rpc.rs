@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use gossip_glomers::{
+    rpc::{self, PendingRpc},
+    Body, Message, Output,
+};
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+
+fn reply(in_reply_to: usize) -> Message<String> {
+    Message {
+        src: "n2".to_string(),
+        dest: "n1".to_string(),
+        body: Body {
+            id: None,
+            in_reply_to: Some(in_reply_to),
+            payload: "ok".to_string(),
+        },
+    }
+}
+
+/// RPC bookkeeping for a node whose output is discarded.
+fn pending() -> PendingRpc<String> {
+    PendingRpc::new(&Output::spawn(tokio::io::sink()))
+}
+
+#[tokio::test]
+async fn a_resolved_rpc_receives_its_reply() {
+    let pending = pending();
+    let (first, _) = pending.register();
+    let (id, rx) = pending.register();
+    assert_ne!(first, id);
+    assert!(pending.resolve(id, reply(id)));
+    assert_eq!(rx.await.unwrap().body.in_reply_to, Some(id));
+    // The reply is handed over once
+    assert!(!pending.resolve(id, reply(id)));
+    assert_eq!(pending.len(), 1);
+}
+
+#[tokio::test]
+async fn resolving_an_unknown_id_is_a_no_op() {
+    let pending = pending();
+    let (id, _rx) = pending.register();
+    assert!(!pending.resolve(id + 100, reply(id + 100)));
+    pending.cancel(id);
+    assert!(!pending.resolve(id, reply(id)));
+    assert!(pending.is_empty());
+}
+
+#[tokio::test]
+async fn cancel_all_drops_every_receiver() {
+    let pending = pending();
+    let receivers: Vec<_> = (0..3).map(|_| pending.register().1).collect();
+    pending.cancel_all();
+    assert!(pending.is_empty());
+    for rx in receivers {
+        assert!(rx.await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn sweep_drops_rpcs_older_than_the_ttl() {
+    let pending = pending();
+    let (_, old) = pending.register();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (fresh, _rx) = pending.register();
+    assert_eq!(pending.sweep(Duration::from_millis(25)), 1);
+    assert!(old.await.is_err());
+    assert!(pending.resolve(fresh, reply(fresh)));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn ids_drawn_concurrently_are_unique() {
+    let pending = std::sync::Arc::new(pending());
+    let drawers: Vec<_> = (0..8)
+        .map(|_| {
+            let pending = pending.clone();
+            tokio::spawn(async move { (0..1000).map(|_| pending.next_id()).collect::<Vec<_>>() })
+        })
+        .collect();
+    let mut ids = std::collections::HashSet::new();
+    for drawer in drawers {
+        for id in drawer.await.unwrap() {
+            assert!(ids.insert(id), "id {} drawn twice", id);
+        }
+    }
+    assert_eq!(ids.len(), 8000);
+}
+
+#[tokio::test]
+async fn an_rpc_awaited_inline_in_the_event_loop_warns_of_a_deadlock() {
+    let pending = pending().warn_deadlock_after(Some(Duration::from_millis(50)));
+    let (input, mut events) = tokio::sync::mpsc::unbounded_channel();
+    // An event loop that handles each event inline: the handler's reply
+    // arrives while the loop is still waiting on the handler
+    input.send(None).unwrap();
+    while let Some(event) = events.recv().await {
+        let Some(reply) = event else {
+            let (id, rx) = pending.register();
+            input.send(Some(reply(id))).unwrap();
+            let res = tokio::time::timeout(Duration::from_millis(200), rx).await;
+            assert!(res.is_err(), "the reply can't be routed");
+            pending.cancel(id);
+            break;
+        };
+        pending.resolve(reply.body.in_reply_to.unwrap(), reply);
+    }
+    assert_eq!(pending.suspected_deadlocks(), 1);
+    // An RPC answered in time doesn't warn
+    let (id, rx) = pending.register();
+    assert!(pending.resolve(id, reply(id)));
+    rx.await.unwrap();
+    assert_eq!(pending.suspected_deadlocks(), 1);
+}
+
+#[tokio::test]
+async fn resolve_reply_routes_by_in_reply_to() {
+    let pending = pending();
+    let (id, rx) = pending.register();
+    pending.resolve_reply(reply(id)).unwrap();
+    assert_eq!(rx.await.unwrap().body.in_reply_to, Some(id));
+    // Late replies are dropped, replies without `in_reply_to` are errors
+    pending.resolve_reply(reply(id)).unwrap();
+    let mut request = reply(id);
+    request.body.in_reply_to = None;
+    assert!(pending.resolve_reply(request).is_err());
+}
+
+#[tokio::test]
+async fn oneway_messages_are_sent_without_a_message_id() {
+    let (writer, mut output) = tokio::io::duplex(1 << 16);
+    let out = Output::spawn(writer);
+    rpc::send_oneway(&out, "n1", "n2", json!({ "type": "gossip" }))
+        .await
+        .unwrap();
+    drop(out);
+
+    let mut raw = String::new();
+    output.read_to_string(&mut raw).await.unwrap();
+    let sent: Value = serde_json::from_str(raw.trim()).unwrap();
+    assert_eq!(
+        sent,
+        json!({ "src": "n1", "dest": "n2", "body": { "type": "gossip" } })
+    );
+}
+
+#[tokio::test]
+async fn query_peers_folds_the_replies_of_the_peers_that_answered() {
+    let peers = ["n2", "n3", "n4"].map(String::from);
+    let answer = |peer: &str, payload: String| {
+        let peer = peer.to_string();
+        async move {
+            let reply = async {
+                // n3 is partitioned away and never answers
+                if peer == "n3" {
+                    std::future::pending::<()>().await;
+                }
+                Message {
+                    src: peer.clone(),
+                    dest: "n1".to_string(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        payload: format!("{} from {}", payload, peer),
+                    },
+                }
+            };
+            tokio::time::timeout(Duration::from_millis(50), reply)
+                .await
+                .map_err(anyhow::Error::from)
+        }
+    };
+    let mut folded = rpc::query_peers(&peers, "pull_ok".to_string(), answer, |replies| {
+        replies
+            .into_iter()
+            .map(|reply| reply.body.payload)
+            .collect::<Vec<_>>()
+    })
+    .await;
+    folded.sort();
+    assert_eq!(folded, ["pull_ok from n2", "pull_ok from n4"]);
+}
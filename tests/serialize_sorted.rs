@@ -0,0 +1,71 @@
+use std::collections::{HashMap, HashSet};
+
+use gossip_glomers::{serialize_sorted, serialize_sorted_map};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize)]
+struct Gossip {
+    #[serde(serialize_with = "serialize_sorted")]
+    seen: HashSet<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PollOk {
+    #[serde(serialize_with = "serialize_sorted_map")]
+    msgs: HashMap<String, Vec<Vec<i64>>>,
+}
+
+#[test]
+fn sets_serialize_the_same_every_time() {
+    // Each set has its own random hash order
+    let first = Gossip {
+        seen: (0..100).collect(),
+    };
+    let second = Gossip {
+        seen: (0..100).rev().collect(),
+    };
+    let first = serde_json::to_string(&first).unwrap();
+    assert_eq!(first, serde_json::to_string(&second).unwrap());
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&first).unwrap(),
+        json!({ "seen": (0..100).collect::<Vec<_>>() })
+    );
+}
+
+#[test]
+fn sorted_sets_deserialize_from_any_order() {
+    let gossip: Gossip = serde_json::from_value(json!({ "seen": [3, 1, 2] })).unwrap();
+    assert_eq!(gossip.seen, HashSet::from([1, 2, 3]));
+}
+
+#[test]
+fn maps_serialize_the_same_every_time() {
+    let keys: Vec<String> = (0..20).map(|i| format!("k{}", i)).collect();
+    // Each map has its own random hash order
+    let first = PollOk {
+        msgs: keys
+            .iter()
+            .map(|key| (key.clone(), vec![vec![0, 1]]))
+            .collect(),
+    };
+    let second = PollOk {
+        msgs: keys
+            .iter()
+            .rev()
+            .map(|key| (key.clone(), vec![vec![0, 1]]))
+            .collect(),
+    };
+    let first = serde_json::to_string(&first).unwrap();
+    assert_eq!(first, serde_json::to_string(&second).unwrap());
+    let mut sorted = keys.clone();
+    sorted.sort_unstable();
+    let at = |key: &String| first.find(&format!("\"{}\"", key)).unwrap();
+    assert!(
+        sorted.windows(2).all(|pair| at(&pair[0]) < at(&pair[1])),
+        "{}",
+        first
+    );
+    let poll: PollOk = serde_json::from_str(&first).unwrap();
+    assert_eq!(poll.msgs.len(), keys.len());
+}
@@ -0,0 +1,26 @@
+use gossip_glomers::{StoredValue, TypeMismatch};
+use serde_json::json;
+
+#[test]
+fn stored_values_deserialize_by_kind() {
+    let int: StoredValue = serde_json::from_value(json!(7)).unwrap();
+    let string: StoredValue = serde_json::from_value(json!("seven")).unwrap();
+    let map: StoredValue = serde_json::from_value(json!({ "n": 7 })).unwrap();
+    assert_eq!(int, StoredValue::Int(7));
+    assert_eq!(string, StoredValue::Str("seven".to_string()));
+    assert_eq!(map, StoredValue::Json(json!({ "n": 7 })));
+}
+
+#[test]
+fn reading_an_int_as_a_string_is_a_type_mismatch() {
+    let stored: StoredValue =
+        serde_json::from_str(&serde_json::to_string(&StoredValue::Int(7)).unwrap()).unwrap();
+    assert_eq!(
+        stored.clone().into_str(),
+        Err(TypeMismatch {
+            expected: "string",
+            found: "int",
+        })
+    );
+    assert_eq!(stored.into_int(), Ok(7));
+}
@@ -0,0 +1,63 @@
+use gossip_glomers::txn::{Conflict, Operation, TxnStore};
+
+fn r(key: u32) -> Operation {
+    ("r".to_string(), key, None)
+}
+
+fn w(key: u32, value: u32) -> Operation {
+    ("w".to_string(), key, Some(value))
+}
+
+#[test]
+fn a_txn_reads_its_own_writes() {
+    let mut store = TxnStore::default();
+    let prepared = store.prepare(vec![r(1), w(1, 5), r(1)]);
+    let ops = store.commit(prepared).unwrap();
+    assert_eq!(ops, [r(1), w(1, 5), ("r".to_string(), 1, Some(5))]);
+    assert_eq!(store.get(1), Some(5));
+}
+
+#[test]
+fn a_conflicting_concurrent_txn_is_aborted_without_effect() {
+    let mut store = TxnStore::default();
+    let first = store.prepare(vec![w(1, 10)]);
+    let second = store.prepare(vec![w(2, 20), w(1, 11)]);
+    store.commit(first).unwrap();
+    assert_eq!(store.commit(second).unwrap_err(), Conflict { key: 1 });
+    assert_eq!(store.get(1), Some(10));
+    // Not even the write to the key nobody else wrote is applied
+    assert_eq!(store.get(2), None);
+}
+
+#[test]
+fn txns_writing_different_keys_both_commit() {
+    let mut store = TxnStore::default();
+    let first = store.prepare(vec![w(1, 10)]);
+    let second = store.prepare(vec![r(3), w(2, 20)]);
+    store.commit(first).unwrap();
+    store.commit(second).unwrap();
+    assert_eq!((store.get(1), store.get(2)), (Some(10), Some(20)));
+}
+
+#[test]
+fn a_txn_whose_read_was_overwritten_is_aborted() {
+    let mut store = TxnStore::default();
+    // Write skew: each reads the key the other writes
+    let first = store.prepare(vec![r(1), w(2, 20)]);
+    let second = store.prepare(vec![r(2), w(1, 10)]);
+    store.commit(first).unwrap();
+    assert_eq!(store.commit(second).unwrap_err(), Conflict { key: 2 });
+    assert_eq!((store.get(1), store.get(2)), (None, Some(20)));
+}
+
+#[test]
+fn a_newer_write_merged_in_from_a_peer_aborts_a_prepared_txn() {
+    let mut store = TxnStore::default();
+    let prepared = store.prepare(vec![r(1), w(2, 20)]);
+    assert_eq!(prepared.keys().count(), 2);
+    assert!(store.merge(1, 5, 1));
+    // An older or repeated write doesn't replace it
+    assert!(!store.merge(1, 4, 1));
+    assert_eq!(store.commit(prepared).unwrap_err(), Conflict { key: 1 });
+    assert_eq!(store.entries([1, 2]), [(1, 5, 1)]);
+}